@@ -1,9 +1,50 @@
+use core::cmp;
 use core::convert::From;
 use core::ops::Shl;
 
 #[derive(Debug, Clone, Copy)]
 pub struct CAddr(pub [u8; 8], pub usize);
 
+impl CAddr {
+    /// The capability address `n` slots after this one, in the same
+    /// capability pool. Returns `None` if that would go past the last
+    /// slot of the pool.
+    pub fn offset(&self, n: usize) -> Option<CAddr> {
+        if self.1 == 0 {
+            return None;
+        }
+
+        let index = self.0[self.1 - 1] as usize + n;
+        if index > 255 {
+            None
+        } else {
+            let mut caddr = *self;
+            caddr.0[self.1 - 1] = index as u8;
+            Some(caddr)
+        }
+    }
+
+    /// Pack the capability address into two words, the address
+    /// bytes and the depth, so that it can be passed in registers.
+    pub fn to_raw(&self) -> (u64, u64) {
+        let mut bytes = 0;
+        for i in 0..8 {
+            bytes |= (self.0[i] as u64) << (i * 8);
+        }
+        (bytes, self.1 as u64)
+    }
+
+    /// Unpack a capability address packed by `to_raw`. A depth larger
+    /// than 8 is truncated to 8.
+    pub fn from_raw(bytes: u64, depth: u64) -> CAddr {
+        let mut caddr = CAddr([0; 8], cmp::min(depth, 8) as usize);
+        for i in 0..8 {
+            caddr.0[i] = (bytes >> (i * 8)) as u8;
+        }
+        caddr
+    }
+}
+
 impl Shl<usize> for CAddr {
     type Output = CAddr;
     fn shl(self, rhs: usize) -> CAddr {
@@ -3,8 +3,22 @@
 #![no_std]
 
 mod caddr;
+mod raw;
 
 pub use caddr::CAddr;
+pub use raw::{RawSystemCall, DecodeError, EncodeError, ABI_VERSION, RAW_SYSTEM_CALL_ARGS,
+              STATUS_OK, STATUS_BAD_VERSION, STATUS_BAD_OPCODE, STATUS_MALFORMED};
+
+// System calls made with `syscall` pass their number in `rdi`, and
+// their arguments in `rsi`, `rdx`, `r10` and `r8`. The result is
+// returned in `rax`: 0 on success, and 1 on failure.
+
+/// Number of a system call made with `syscall` whose call is in
+/// the task buffer.
+pub const REGISTER_CALL_BUFFER: u64 = 0;
+/// Put a raw value into a channel. Arguments: the channel address
+/// packed with `CAddr::to_raw`, and the value.
+pub const REGISTER_CALL_CHANNEL_PUT_RAW: u64 = 1;
 
 /// A trait that allows setting a struct back to its default value.
 pub trait SetDefault {
@@ -43,11 +57,37 @@ pub enum SystemCall {
         toplevel_table: CAddr,
         request: (usize, CAddr),
     },
+    PageMap {
+        untyped: CAddr,
+        toplevel_table: CAddr,
+        request: (usize, CAddr, PageRights, CachePolicy),
+        response: Option<usize>,
+    },
+    PageUnmap {
+        request: CAddr,
+    },
+    PageRemap {
+        request: (CAddr, PageRights, CachePolicy),
+    },
+    VSpaceLookup {
+        request: (CAddr, usize),
+        response: Option<VSpaceMapping>,
+    },
+    VSpaceClone {
+        request: (CAddr, CAddr, CAddr, CloneMode),
+        response: Option<usize>,
+    },
+    PageDerive {
+        request: (CAddr, CAddr, CAddr),
+    },
+    PageRevoke {
+        request: CAddr,
+    },
     RetypeCPool {
         request: (CAddr, CAddr),
     },
     ChannelTake {
-        request: CAddr,
+        request: (CAddr, ChannelReceive),
         response: Option<ChannelMessage>,
     },
     ChannelPut {
@@ -56,6 +96,38 @@ pub enum SystemCall {
     RetypeTask {
         request: (CAddr, CAddr),
     },
+    RetypePDPT {
+        request: (CAddr, CAddr),
+    },
+    RetypePD {
+        request: (CAddr, CAddr),
+    },
+    RetypePT {
+        request: (CAddr, CAddr),
+    },
+    MapPDPT {
+        request: (CAddr, usize, CAddr),
+    },
+    MapPD {
+        request: (CAddr, usize, CAddr),
+    },
+    MapPT {
+        request: (CAddr, usize, CAddr),
+    },
+    UntypedSplit {
+        request: (CAddr, usize, usize, CAddr),
+    },
+    RetypeN {
+        request: (CAddr, CapType, usize, CAddr),
+    },
+    UntypedInfo {
+        request: CAddr,
+        response: Option<UntypedInfo>,
+    },
+    CapIdentify {
+        request: CAddr,
+        response: Option<CapInfo>,
+    },
     TaskSetInstructionPointer {
         request: (CAddr, u64),
     },
@@ -77,24 +149,211 @@ pub enum SystemCall {
     TaskSetInactive {
         request: CAddr
     },
+    /// Set the CPUs a task may run on, as a bit mask of CPU indexes
+    /// with the bootstrap processor as bit 0. A task with an empty
+    /// mask is not run.
+    TaskSetAffinity {
+        request: (CAddr, u64),
+    },
+    /// Set the FS and GS base of a task, used for thread-local
+    /// storage. Applies to the calling task if no task is given.
+    TaskSetTLSBase {
+        request: (Option<CAddr>, u64, u64),
+    },
+    /// Stop the calling task with an exit code and a message of at
+    /// most 32 bytes of UTF-8. The task is marked dead and is not run
+    /// again, unless it is set active. Its instruction and stack
+    /// pointers must be set before that, as it would otherwise resume
+    /// right after the call.
+    TaskExit {
+        request: (u64, [u8; 32], usize),
+    },
+}
+
+/// Type of a capability.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CapType {
+    Untyped,
+    CPool,
+    Task,
+    RawPage,
+    TaskBufferPage,
+    Channel,
+    PML4,
+    PDPT,
+    PD,
+    PT,
+    LargePage,
+    HugePage,
+}
+
+/// Access rights of a mapped page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageRights {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+/// Cache policy of a mapped page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CachePolicy {
+    WriteBack,
+    WriteThrough,
+    Uncached,
+}
+
+/// How user pages are cloned by `VSpaceClone`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloneMode {
+    /// Copy every page into a new frame.
+    Copy,
+    /// Map the same frames in the clone.
+    Share,
+    /// Share the frames read-only, and copy a page on its first
+    /// write.
+    CopyOnWrite,
+}
+
+/// A virtual address translation, returned by `VSpaceLookup`.
+#[derive(Debug, Clone, Copy)]
+pub struct VSpaceMapping {
+    /// Physical address the virtual address translates to.
+    pub paddr: usize,
+    /// Flags of the page table entry mapping the address, without
+    /// the address bits.
+    pub flags: u64,
+    /// Level of the page table entry mapping the address: 1 for a
+    /// PT entry (4 KiB page), 2 for a PD entry (2 MiB page) and 3
+    /// for a PDPT entry (1 GiB page).
+    pub level: usize,
+}
+
+/// Information about an untyped capability.
+#[derive(Debug, Clone, Copy)]
+pub struct UntypedInfo {
+    /// Physical start address of the untyped region.
+    pub start_paddr: usize,
+    /// Length of the untyped region in bytes.
+    pub length: usize,
+    /// Physical address of the watermark. Memory below it has been
+    /// allocated.
+    pub watermark: usize,
+    /// Number of bytes left above the watermark.
+    pub remaining: usize,
+    /// Number of capabilities derived from the untyped region.
+    pub child_count: usize,
+}
+
+/// Information about a capability, returned by `CapIdentify`.
+#[derive(Debug, Clone, Copy)]
+pub struct CapInfo {
+    /// Type of the capability.
+    pub cap_type: CapType,
+    /// Physical address of the memory managed by the capability. For
+    /// untyped capabilities, pages and page tables this is the
+    /// memory they represent. For other capabilities it is the kernel
+    /// object itself.
+    pub paddr: usize,
+    /// Length of the memory in bytes.
+    pub length: usize,
+    /// Type-specific information.
+    pub detail: CapDetail,
+}
+
+/// Type-specific information about a capability.
+#[derive(Debug, Clone, Copy)]
+pub enum CapDetail {
+    None,
+    Untyped(UntypedInfo),
+    CPool {
+        /// Number of free slots in the capability pool.
+        free: usize,
+    },
+    Task(TaskState),
+    Channel {
+        /// Whether a value is waiting in the channel.
+        pending: bool,
+    },
+}
+
+/// Status of a task, as reported to user-space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    Active,
+    ChannelWait,
+    Inactive,
+    /// The task was stopped by the kernel, because of a malformed
+    /// system call or a page fault it could not resolve.
+    Faulted,
+    /// The task stopped itself with `TaskExit`, with the given exit
+    /// code. A supervisor can restart it by setting its instruction
+    /// and stack pointers, and then setting it active again.
+    Dead(u64),
 }
 
-/// Represents a task buffer used for system calls.
+/// Represents a task buffer used for system calls. The call is kept
+/// in its binary form, see `RawSystemCall`, so that the layout of
+/// the buffer does not depend on the compiler.
+#[repr(C)]
 pub struct TaskBuffer {
-    pub call: Option<SystemCall>,
+    pub call: RawSystemCall,
     pub payload_length: usize,
-    pub payload_data: [u8; 1024],
+    pub payload_data: [u8; TASK_BUFFER_PAYLOAD_LENGTH],
+}
+
+/// Size in bytes of the payload area of a task buffer.
+pub const TASK_BUFFER_PAYLOAD_LENGTH: usize = 1024;
+
+impl TaskBuffer {
+    /// The payload in the task buffer. Returns `None` if
+    /// `payload_length` is larger than the payload area.
+    pub fn payload(&self) -> Option<&[u8]> {
+        if self.payload_length > TASK_BUFFER_PAYLOAD_LENGTH {
+            None
+        } else {
+            Some(&self.payload_data[0..self.payload_length])
+        }
+    }
 }
 
 impl SetDefault for TaskBuffer {
     fn set_default(&mut self) {
-        self.call = None;
+        self.call = RawSystemCall::empty();
     }
 }
 
+/// Maximum number of capabilities in a `ChannelMessage::Compound`.
+pub const CHANNEL_MESSAGE_CAPS: usize = 4;
+/// Maximum number of pages in a `ChannelMessage::Bulk`.
+pub const CHANNEL_MESSAGE_PAGES: usize = 4;
+
 #[derive(Debug, Clone)]
 pub enum ChannelMessage {
     Raw(u64),
     Cap(Option<CAddr>),
     Payload,
+    /// Capabilities, together with the payload in the task
+    /// buffer. The receiver gets the slots the capabilities are put
+    /// in. Either all of the capabilities are transferred, or, if
+    /// one of the slots is not free, none of them.
+    Compound([Option<CAddr>; CHANNEL_MESSAGE_CAPS]),
+    /// Copy the given number of bytes from the sender's raw pages
+    /// into the raw pages supplied in `ChannelReceive`. The receiver
+    /// gets its own pages and the number of bytes copied.
+    Bulk([Option<CAddr>; CHANNEL_MESSAGE_PAGES], usize),
+}
+
+/// Supplied by the receiver with `ChannelTake`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChannelReceive {
+    /// Slots the capabilities of a `ChannelMessage::Compound`, or
+    /// the capability of a `ChannelMessage::Cap` in the first entry,
+    /// are put in. They may point into nested capability pools. A
+    /// capability without a slot is put in a free slot of the
+    /// top-level pool. If a slot is not free, none of the
+    /// capabilities are transferred, and all of them are dropped.
+    pub slots: [Option<CAddr>; CHANNEL_MESSAGE_CAPS],
+    /// Raw pages the data of a `ChannelMessage::Bulk` is copied into.
+    pub pages: [Option<CAddr>; CHANNEL_MESSAGE_PAGES],
 }
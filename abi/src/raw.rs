@@ -0,0 +1,826 @@
+use super::{SystemCall, CAddr, CapType, PageRights, CachePolicy, CloneMode, VSpaceMapping,
+            UntypedInfo, CapInfo, CapDetail, TaskState, ChannelMessage, ChannelReceive};
+
+/// Version of the binary system call ABI. It must be changed whenever
+/// an opcode, or the encoding of its arguments, changes.
+pub const ABI_VERSION: u32 = 1;
+
+/// Number of argument words in a `RawSystemCall`.
+pub const RAW_SYSTEM_CALL_ARGS: usize = 64;
+
+/// The call was decoded and handled.
+pub const STATUS_OK: u32 = 0;
+/// The call was made with a different ABI version. `version` of the
+/// reply holds the version of the kernel.
+pub const STATUS_BAD_VERSION: u32 = 1;
+/// The opcode is not known to the kernel.
+pub const STATUS_BAD_OPCODE: u32 = 2;
+/// The arguments could not be decoded.
+pub const STATUS_MALFORMED: u32 = 3;
+
+/// Binary representation of a system call, with a layout that does
+/// not depend on the compiler. A call is an opcode together with its
+/// request and response fields, encoded in order as 64-bit words in
+/// `args`:
+///
+/// * integers and addresses take one word;
+/// * `bool` is 0 or 1;
+/// * `CAddr` takes two words, as packed by `CAddr::to_raw`;
+/// * `Option<T>` is a word that is 0 for `None` and 1 for `Some`,
+///   followed by `T` if it is `Some`;
+/// * enums are a word holding the variant index, in declaration
+///   order, followed by the fields of the variant;
+/// * `PageRights` is a word with bit 0 for read, bit 1 for write and
+///   bit 2 for execute.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct RawSystemCall {
+    /// ABI version the call was encoded with.
+    pub version: u32,
+    /// Opcode of the call, see `SystemCall::opcode`. 0 means there is
+    /// no call.
+    pub opcode: u32,
+    /// One of the `STATUS_*` values, set by the kernel.
+    pub status: u32,
+    _reserved: u32,
+    /// Encoded request and response fields.
+    pub args: [u64; RAW_SYSTEM_CALL_ARGS],
+}
+
+impl RawSystemCall {
+    /// An empty raw system call, with no opcode.
+    pub fn empty() -> RawSystemCall {
+        RawSystemCall {
+            version: ABI_VERSION,
+            opcode: 0,
+            status: STATUS_OK,
+            _reserved: 0,
+            args: [0; RAW_SYSTEM_CALL_ARGS],
+        }
+    }
+}
+
+/// Error decoding a `RawSystemCall`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The call was encoded with another ABI version.
+    Version(u32),
+    /// The opcode is not known.
+    Opcode(u32),
+    /// An argument is out of range, or there are too many of them.
+    Malformed,
+}
+
+impl DecodeError {
+    /// The `STATUS_*` value reporting this error.
+    pub fn status(&self) -> u32 {
+        match *self {
+            DecodeError::Version(_) => STATUS_BAD_VERSION,
+            DecodeError::Opcode(_) => STATUS_BAD_OPCODE,
+            DecodeError::Malformed => STATUS_MALFORMED,
+        }
+    }
+}
+
+/// Error encoding a `SystemCall`: its fields do not fit in the
+/// argument words of a `RawSystemCall`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncodeError;
+
+struct Writer<'a> {
+    args: &'a mut [u64; RAW_SYSTEM_CALL_ARGS],
+    index: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn word(&mut self, value: u64) -> Result<(), EncodeError> {
+        if self.index >= RAW_SYSTEM_CALL_ARGS {
+            return Err(EncodeError);
+        }
+        self.args[self.index] = value;
+        self.index += 1;
+        Ok(())
+    }
+
+    fn put<T: Encode>(&mut self, value: &T) -> Result<(), EncodeError> {
+        value.encode(self)
+    }
+}
+
+struct Reader<'a> {
+    args: &'a [u64; RAW_SYSTEM_CALL_ARGS],
+    index: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn word(&mut self) -> Result<u64, DecodeError> {
+        if self.index >= RAW_SYSTEM_CALL_ARGS {
+            return Err(DecodeError::Malformed);
+        }
+        self.index += 1;
+        Ok(self.args[self.index - 1])
+    }
+
+    fn get<T: Decode>(&mut self) -> Result<T, DecodeError> {
+        T::decode(self)
+    }
+}
+
+trait Encode {
+    fn encode(&self, writer: &mut Writer) -> Result<(), EncodeError>;
+}
+
+trait Decode: Sized {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>;
+}
+
+impl Encode for u64 {
+    fn encode(&self, writer: &mut Writer) -> Result<(), EncodeError> {
+        writer.word(*self)
+    }
+}
+
+impl Decode for u64 {
+    fn decode(reader: &mut Reader) -> Result<u64, DecodeError> {
+        reader.word()
+    }
+}
+
+impl Encode for usize {
+    fn encode(&self, writer: &mut Writer) -> Result<(), EncodeError> {
+        writer.word(*self as u64)
+    }
+}
+
+impl Decode for usize {
+    fn decode(reader: &mut Reader) -> Result<usize, DecodeError> {
+        Ok(reader.word()? as usize)
+    }
+}
+
+impl Encode for bool {
+    fn encode(&self, writer: &mut Writer) -> Result<(), EncodeError> {
+        writer.word(if *self { 1 } else { 0 })
+    }
+}
+
+impl Decode for bool {
+    fn decode(reader: &mut Reader) -> Result<bool, DecodeError> {
+        match reader.word()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(DecodeError::Malformed),
+        }
+    }
+}
+
+impl Encode for CAddr {
+    fn encode(&self, writer: &mut Writer) -> Result<(), EncodeError> {
+        let (bytes, depth) = self.to_raw();
+        writer.word(bytes)?;
+        writer.word(depth)
+    }
+}
+
+impl Decode for CAddr {
+    fn decode(reader: &mut Reader) -> Result<CAddr, DecodeError> {
+        let bytes = reader.word()?;
+        let depth = reader.word()?;
+        if depth > 8 {
+            return Err(DecodeError::Malformed);
+        }
+        Ok(CAddr::from_raw(bytes, depth))
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode(&self, writer: &mut Writer) -> Result<(), EncodeError> {
+        match *self {
+            None => writer.word(0),
+            Some(ref value) => {
+                writer.word(1)?;
+                writer.put(value)
+            },
+        }
+    }
+}
+
+impl<T: Decode> Decode for Option<T> {
+    fn decode(reader: &mut Reader) -> Result<Option<T>, DecodeError> {
+        match reader.word()? {
+            0 => Ok(None),
+            1 => Ok(Some(reader.get()?)),
+            _ => Err(DecodeError::Malformed),
+        }
+    }
+}
+
+// Capability and page lists in channel messages. Both
+// `CHANNEL_MESSAGE_CAPS` and `CHANNEL_MESSAGE_PAGES` are 4.
+impl<T: Encode> Encode for [T; 4] {
+    fn encode(&self, writer: &mut Writer) -> Result<(), EncodeError> {
+        for value in self.iter() {
+            writer.put(value)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Decode> Decode for [T; 4] {
+    fn decode(reader: &mut Reader) -> Result<[T; 4], DecodeError> {
+        Ok([reader.get()?, reader.get()?, reader.get()?, reader.get()?])
+    }
+}
+
+impl Encode for [u8; 32] {
+    fn encode(&self, writer: &mut Writer) -> Result<(), EncodeError> {
+        for chunk in self.chunks(8) {
+            let mut word = 0;
+            for (i, byte) in chunk.iter().enumerate() {
+                word |= (*byte as u64) << (i * 8);
+            }
+            writer.word(word)?;
+        }
+        Ok(())
+    }
+}
+
+impl Decode for [u8; 32] {
+    fn decode(reader: &mut Reader) -> Result<[u8; 32], DecodeError> {
+        let mut bytes = [0; 32];
+        for chunk in bytes.chunks_mut(8) {
+            let word = reader.word()?;
+            for (i, byte) in chunk.iter_mut().enumerate() {
+                *byte = (word >> (i * 8)) as u8;
+            }
+        }
+        Ok(bytes)
+    }
+}
+
+macro_rules! tuple_codec {
+    ( $( $name:ident $value:ident ),* ) => (
+        impl<$( $name: Encode ),*> Encode for ($( $name, )*) {
+            fn encode(&self, writer: &mut Writer) -> Result<(), EncodeError> {
+                let ($( ref $value, )*) = *self;
+                $( writer.put($value)?; )*
+                Ok(())
+            }
+        }
+
+        impl<$( $name: Decode ),*> Decode for ($( $name, )*) {
+            fn decode(reader: &mut Reader) -> Result<($( $name, )*), DecodeError> {
+                Ok(($( reader.get::<$name>()?, )*))
+            }
+        }
+    )
+}
+
+tuple_codec!(A a, B b);
+tuple_codec!(A a, B b, C c);
+tuple_codec!(A a, B b, C c, D d);
+
+macro_rules! unit_enum_codec {
+    ( $t:ident, $( $variant:ident = $index:tt ),* ) => (
+        impl Encode for $t {
+            fn encode(&self, writer: &mut Writer) -> Result<(), EncodeError> {
+                writer.word(match *self {
+                    $( $t::$variant => $index, )*
+                })
+            }
+        }
+
+        impl Decode for $t {
+            fn decode(reader: &mut Reader) -> Result<$t, DecodeError> {
+                match reader.word()? {
+                    $( $index => Ok($t::$variant), )*
+                    _ => Err(DecodeError::Malformed),
+                }
+            }
+        }
+    )
+}
+
+unit_enum_codec!(CapType, Untyped = 0, CPool = 1, Task = 2, RawPage = 3, TaskBufferPage = 4,
+                 Channel = 5, PML4 = 6, PDPT = 7, PD = 8, PT = 9, LargePage = 10, HugePage = 11);
+unit_enum_codec!(CachePolicy, WriteBack = 0, WriteThrough = 1, Uncached = 2);
+unit_enum_codec!(CloneMode, Copy = 0, Share = 1, CopyOnWrite = 2);
+
+impl Encode for TaskState {
+    fn encode(&self, writer: &mut Writer) -> Result<(), EncodeError> {
+        match *self {
+            TaskState::Active => writer.word(0),
+            TaskState::ChannelWait => writer.word(1),
+            TaskState::Inactive => writer.word(2),
+            TaskState::Faulted => writer.word(3),
+            TaskState::Dead(code) => { writer.word(4)?; writer.put(&code) },
+        }
+    }
+}
+
+impl Decode for TaskState {
+    fn decode(reader: &mut Reader) -> Result<TaskState, DecodeError> {
+        match reader.word()? {
+            0 => Ok(TaskState::Active),
+            1 => Ok(TaskState::ChannelWait),
+            2 => Ok(TaskState::Inactive),
+            3 => Ok(TaskState::Faulted),
+            4 => Ok(TaskState::Dead(reader.get()?)),
+            _ => Err(DecodeError::Malformed),
+        }
+    }
+}
+
+impl Encode for PageRights {
+    fn encode(&self, writer: &mut Writer) -> Result<(), EncodeError> {
+        let mut bits = 0;
+        if self.read { bits |= 0b001; }
+        if self.write { bits |= 0b010; }
+        if self.execute { bits |= 0b100; }
+        writer.word(bits)
+    }
+}
+
+impl Decode for PageRights {
+    fn decode(reader: &mut Reader) -> Result<PageRights, DecodeError> {
+        let bits = reader.word()?;
+        if bits & !0b111 != 0 {
+            return Err(DecodeError::Malformed);
+        }
+        Ok(PageRights {
+            read: bits & 0b001 != 0,
+            write: bits & 0b010 != 0,
+            execute: bits & 0b100 != 0,
+        })
+    }
+}
+
+impl Encode for VSpaceMapping {
+    fn encode(&self, writer: &mut Writer) -> Result<(), EncodeError> {
+        writer.put(&(self.paddr, self.flags, self.level))
+    }
+}
+
+impl Decode for VSpaceMapping {
+    fn decode(reader: &mut Reader) -> Result<VSpaceMapping, DecodeError> {
+        Ok(VSpaceMapping {
+            paddr: reader.get()?,
+            flags: reader.get()?,
+            level: reader.get()?,
+        })
+    }
+}
+
+impl Encode for UntypedInfo {
+    fn encode(&self, writer: &mut Writer) -> Result<(), EncodeError> {
+        writer.put(&(self.start_paddr, self.length, self.watermark, self.remaining))?;
+        writer.put(&self.child_count)
+    }
+}
+
+impl Decode for UntypedInfo {
+    fn decode(reader: &mut Reader) -> Result<UntypedInfo, DecodeError> {
+        Ok(UntypedInfo {
+            start_paddr: reader.get()?,
+            length: reader.get()?,
+            watermark: reader.get()?,
+            remaining: reader.get()?,
+            child_count: reader.get()?,
+        })
+    }
+}
+
+impl Encode for CapDetail {
+    fn encode(&self, writer: &mut Writer) -> Result<(), EncodeError> {
+        match *self {
+            CapDetail::None => writer.word(0),
+            CapDetail::Untyped(ref info) => { writer.word(1)?; writer.put(info) },
+            CapDetail::CPool { free } => { writer.word(2)?; writer.put(&free) },
+            CapDetail::Task(ref state) => { writer.word(3)?; writer.put(state) },
+            CapDetail::Channel { pending } => { writer.word(4)?; writer.put(&pending) },
+        }
+    }
+}
+
+impl Decode for CapDetail {
+    fn decode(reader: &mut Reader) -> Result<CapDetail, DecodeError> {
+        match reader.word()? {
+            0 => Ok(CapDetail::None),
+            1 => Ok(CapDetail::Untyped(reader.get()?)),
+            2 => Ok(CapDetail::CPool { free: reader.get()? }),
+            3 => Ok(CapDetail::Task(reader.get()?)),
+            4 => Ok(CapDetail::Channel { pending: reader.get()? }),
+            _ => Err(DecodeError::Malformed),
+        }
+    }
+}
+
+impl Encode for CapInfo {
+    fn encode(&self, writer: &mut Writer) -> Result<(), EncodeError> {
+        writer.put(&(self.cap_type, self.paddr, self.length, self.detail))
+    }
+}
+
+impl Decode for CapInfo {
+    fn decode(reader: &mut Reader) -> Result<CapInfo, DecodeError> {
+        Ok(CapInfo {
+            cap_type: reader.get()?,
+            paddr: reader.get()?,
+            length: reader.get()?,
+            detail: reader.get()?,
+        })
+    }
+}
+
+impl Encode for ChannelMessage {
+    fn encode(&self, writer: &mut Writer) -> Result<(), EncodeError> {
+        match *self {
+            ChannelMessage::Raw(value) => { writer.word(0)?; writer.put(&value) },
+            ChannelMessage::Cap(ref caddr) => { writer.word(1)?; writer.put(caddr) },
+            ChannelMessage::Payload => writer.word(2),
+            ChannelMessage::Compound(ref caps) => { writer.word(3)?; writer.put(caps) },
+            ChannelMessage::Bulk(ref pages, length) => {
+                writer.word(4)?;
+                writer.put(pages)?;
+                writer.put(&length)
+            },
+        }
+    }
+}
+
+impl Decode for ChannelMessage {
+    fn decode(reader: &mut Reader) -> Result<ChannelMessage, DecodeError> {
+        match reader.word()? {
+            0 => Ok(ChannelMessage::Raw(reader.get()?)),
+            1 => Ok(ChannelMessage::Cap(reader.get()?)),
+            2 => Ok(ChannelMessage::Payload),
+            3 => Ok(ChannelMessage::Compound(reader.get()?)),
+            4 => Ok(ChannelMessage::Bulk(reader.get()?, reader.get()?)),
+            _ => Err(DecodeError::Malformed),
+        }
+    }
+}
+
+impl Encode for ChannelReceive {
+    fn encode(&self, writer: &mut Writer) -> Result<(), EncodeError> {
+        writer.put(&self.slots)?;
+        writer.put(&self.pages)
+    }
+}
+
+impl Decode for ChannelReceive {
+    fn decode(reader: &mut Reader) -> Result<ChannelReceive, DecodeError> {
+        Ok(ChannelReceive {
+            slots: reader.get()?,
+            pages: reader.get()?,
+        })
+    }
+}
+
+// Implements `opcode`, `encode` and `decode` for `SystemCall`, given
+// the opcode and the fields of every variant.
+macro_rules! system_call_codec {
+    ( $( $opcode:tt => $variant:ident { $( $field:ident ),* } ),* ) => (
+        impl SystemCall {
+            /// Opcode of the call in the binary ABI.
+            pub fn opcode(&self) -> u32 {
+                match *self {
+                    #[cfg(feature="kernel_debug")]
+                    SystemCall::DebugCPoolList => 0x100,
+                    #[cfg(feature="kernel_debug")]
+                    SystemCall::DebugTestSucceed => 0x101,
+                    #[cfg(feature="kernel_debug")]
+                    SystemCall::DebugTestFail => 0x102,
+                    $( SystemCall::$variant { .. } => $opcode, )*
+                }
+            }
+
+            /// Encode the call, with its response if it is set, into
+            /// the binary ABI. Fails if the fields do not fit.
+            pub fn encode(&self) -> Result<RawSystemCall, EncodeError> {
+                let mut raw = RawSystemCall::empty();
+                raw.opcode = self.opcode();
+                {
+                    let mut writer = Writer { args: &mut raw.args, index: 0 };
+                    match *self {
+                        #[cfg(feature="kernel_debug")]
+                        SystemCall::DebugCPoolList => (),
+                        #[cfg(feature="kernel_debug")]
+                        SystemCall::DebugTestSucceed => (),
+                        #[cfg(feature="kernel_debug")]
+                        SystemCall::DebugTestFail => (),
+                        $( SystemCall::$variant { $( ref $field ),* } => {
+                            $( writer.put($field)?; )*
+                        }, )*
+                    }
+                }
+                Ok(raw)
+            }
+
+            /// Decode a call from the binary ABI. Fails if it was
+            /// encoded with a different ABI version, if the opcode is
+            /// not known, for example a debug call when the
+            /// `kernel_debug` feature is not enabled, or if an
+            /// argument is out of range.
+            pub fn decode(raw: &RawSystemCall) -> Result<SystemCall, DecodeError> {
+                if raw.version != ABI_VERSION {
+                    return Err(DecodeError::Version(raw.version));
+                }
+
+                let mut reader = Reader { args: &raw.args, index: 0 };
+                match raw.opcode {
+                    #[cfg(feature="kernel_debug")]
+                    0x100 => Ok(SystemCall::DebugCPoolList),
+                    #[cfg(feature="kernel_debug")]
+                    0x101 => Ok(SystemCall::DebugTestSucceed),
+                    #[cfg(feature="kernel_debug")]
+                    0x102 => Ok(SystemCall::DebugTestFail),
+                    $( $opcode => Ok(SystemCall::$variant {
+                        $( $field: reader.get()? ),*
+                    }), )*
+                    opcode => Err(DecodeError::Opcode(opcode)),
+                }
+            }
+        }
+    )
+}
+
+system_call_codec! {
+    1 => Print { request },
+    2 => RetypeRawPageFree { request, response },
+    3 => MapRawPageFree { untyped, toplevel_table, request },
+    4 => PageMap { untyped, toplevel_table, request, response },
+    5 => PageUnmap { request },
+    6 => PageRemap { request },
+    7 => VSpaceLookup { request, response },
+    8 => VSpaceClone { request, response },
+    9 => PageDerive { request },
+    10 => PageRevoke { request },
+    11 => RetypeCPool { request },
+    12 => ChannelTake { request, response },
+    13 => ChannelPut { request },
+    14 => RetypeTask { request },
+    15 => RetypePDPT { request },
+    16 => RetypePD { request },
+    17 => RetypePT { request },
+    18 => MapPDPT { request },
+    19 => MapPD { request },
+    20 => MapPT { request },
+    21 => UntypedSplit { request },
+    22 => RetypeN { request },
+    23 => UntypedInfo { request, response },
+    24 => CapIdentify { request, response },
+    25 => TaskSetInstructionPointer { request },
+    26 => TaskSetStackPointer { request },
+    27 => TaskSetCPool { request },
+    28 => TaskSetTopPageTable { request },
+    29 => TaskSetBuffer { request },
+    30 => TaskSetActive { request },
+    31 => TaskSetInactive { request },
+    32 => TaskSetAffinity { request },
+    33 => TaskSetTLSBase { request },
+    34 => TaskExit { request }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{ChannelMessage, ChannelReceive};
+
+    const RW: PageRights = PageRights { read: true, write: true, execute: false };
+
+    fn caddr(index: u8) -> CAddr {
+        CAddr::from(index)
+    }
+
+    fn nested_caddr() -> CAddr {
+        CAddr::from([1, 2, 3])
+    }
+
+    fn message() -> [u8; 32] {
+        let mut bytes = [0; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = i as u8 + 1;
+        }
+        bytes
+    }
+
+    /// Encode `call`, check its opcode, decode it and encode it again.
+    /// The arguments of both encodings must be the same.
+    fn round_trip(opcode: u32, call: SystemCall) {
+        let raw = call.encode().unwrap();
+        assert_eq!(raw.version, ABI_VERSION);
+        assert_eq!(raw.opcode, opcode);
+
+        let decoded = SystemCall::decode(&raw).unwrap();
+        assert_eq!(decoded.opcode(), opcode);
+        let again = decoded.encode().unwrap();
+        assert_eq!(&raw.args[..], &again.args[..]);
+    }
+
+    #[test]
+    fn round_trip_pages() {
+        round_trip(1, SystemCall::Print { request: (message(), 32) });
+        round_trip(2, SystemCall::RetypeRawPageFree { request: caddr(2), response: None });
+        round_trip(2, SystemCall::RetypeRawPageFree { request: caddr(2), response: Some(nested_caddr()) });
+        round_trip(3, SystemCall::MapRawPageFree {
+            untyped: caddr(2), toplevel_table: caddr(3), request: (0x1000, caddr(4)),
+        });
+        round_trip(4, SystemCall::PageMap {
+            untyped: caddr(2), toplevel_table: caddr(3),
+            request: (0x2000, caddr(4), RW, CachePolicy::WriteThrough),
+            response: Some(0x200000),
+        });
+        round_trip(5, SystemCall::PageUnmap { request: caddr(4) });
+        round_trip(6, SystemCall::PageRemap { request: (caddr(4), RW, CachePolicy::Uncached) });
+        round_trip(7, SystemCall::VSpaceLookup {
+            request: (caddr(3), 0x3000),
+            response: Some(VSpaceMapping { paddr: 0x5000, flags: 0x8000000000000007, level: 1 }),
+        });
+        round_trip(8, SystemCall::VSpaceClone {
+            request: (caddr(2), caddr(3), caddr(5), CloneMode::CopyOnWrite),
+            response: Some(0x100000),
+        });
+        round_trip(9, SystemCall::PageDerive { request: (caddr(2), caddr(4), caddr(6)) });
+        round_trip(10, SystemCall::PageRevoke { request: caddr(4) });
+    }
+
+    #[test]
+    fn round_trip_channels() {
+        let mut receive = ChannelReceive::default();
+        receive.slots[1] = Some(nested_caddr());
+        receive.pages[3] = Some(caddr(9));
+        round_trip(12, SystemCall::ChannelTake { request: (caddr(7), receive), response: None });
+
+        let messages = [
+            ChannelMessage::Raw(42),
+            ChannelMessage::Cap(Some(caddr(8))),
+            ChannelMessage::Payload,
+            ChannelMessage::Compound([Some(caddr(8)), None, Some(nested_caddr()), None]),
+            ChannelMessage::Bulk([Some(caddr(9)), None, None, None], 4096),
+        ];
+        for message in messages.iter() {
+            round_trip(12, SystemCall::ChannelTake {
+                request: (caddr(7), receive), response: Some(message.clone()),
+            });
+            round_trip(13, SystemCall::ChannelPut { request: (caddr(7), message.clone()) });
+        }
+    }
+
+    #[test]
+    fn round_trip_retypes() {
+        round_trip(11, SystemCall::RetypeCPool { request: (caddr(2), caddr(10)) });
+        round_trip(14, SystemCall::RetypeTask { request: (caddr(2), caddr(11)) });
+        round_trip(15, SystemCall::RetypePDPT { request: (caddr(2), caddr(12)) });
+        round_trip(16, SystemCall::RetypePD { request: (caddr(2), caddr(13)) });
+        round_trip(17, SystemCall::RetypePT { request: (caddr(2), caddr(14)) });
+        round_trip(18, SystemCall::MapPDPT { request: (caddr(3), 0x8000000000, caddr(12)) });
+        round_trip(19, SystemCall::MapPD { request: (caddr(12), 0x40000000, caddr(13)) });
+        round_trip(20, SystemCall::MapPT { request: (caddr(13), 0x200000, caddr(14)) });
+        round_trip(21, SystemCall::UntypedSplit { request: (caddr(2), 12, 4, caddr(20)) });
+        round_trip(22, SystemCall::RetypeN { request: (caddr(2), CapType::HugePage, 2, caddr(30)) });
+    }
+
+    #[test]
+    fn round_trip_identify() {
+        let info = UntypedInfo {
+            start_paddr: 0x100000, length: 0x400000, watermark: 0x180000,
+            remaining: 0x380000, child_count: 7,
+        };
+        round_trip(23, SystemCall::UntypedInfo { request: caddr(2), response: None });
+        round_trip(23, SystemCall::UntypedInfo { request: caddr(2), response: Some(info) });
+
+        let details = [
+            CapDetail::None,
+            CapDetail::Untyped(info),
+            CapDetail::CPool { free: 200 },
+            CapDetail::Task(TaskState::Faulted),
+            CapDetail::Task(TaskState::Dead(3)),
+            CapDetail::Channel { pending: true },
+        ];
+        for detail in details.iter() {
+            round_trip(24, SystemCall::CapIdentify {
+                request: caddr(2),
+                response: Some(CapInfo {
+                    cap_type: CapType::Untyped, paddr: 0x100000, length: 0x400000, detail: *detail,
+                }),
+            });
+        }
+    }
+
+    #[test]
+    fn round_trip_tasks() {
+        round_trip(25, SystemCall::TaskSetInstructionPointer { request: (caddr(11), 0x400000) });
+        round_trip(26, SystemCall::TaskSetStackPointer { request: (caddr(11), 0x7ff000) });
+        round_trip(27, SystemCall::TaskSetCPool { request: (caddr(11), caddr(0)) });
+        round_trip(28, SystemCall::TaskSetTopPageTable { request: (caddr(11), caddr(3)) });
+        round_trip(29, SystemCall::TaskSetBuffer { request: (caddr(11), caddr(15)) });
+        round_trip(30, SystemCall::TaskSetActive { request: caddr(11) });
+        round_trip(31, SystemCall::TaskSetInactive { request: caddr(11) });
+        round_trip(32, SystemCall::TaskSetAffinity { request: (caddr(11), 0b101) });
+        round_trip(33, SystemCall::TaskSetTLSBase { request: (None, 0x1000, 0x2000) });
+        round_trip(33, SystemCall::TaskSetTLSBase { request: (Some(caddr(11)), 0x1000, 0x2000) });
+        round_trip(34, SystemCall::TaskExit { request: (1, message(), 5) });
+    }
+
+    #[cfg(feature="kernel_debug")]
+    #[test]
+    fn round_trip_debug() {
+        round_trip(0x100, SystemCall::DebugCPoolList);
+        round_trip(0x101, SystemCall::DebugTestSucceed);
+        round_trip(0x102, SystemCall::DebugTestFail);
+    }
+
+    #[test]
+    fn round_trip_values() {
+        let raw = SystemCall::Print { request: (message(), 17) }.encode().unwrap();
+        match SystemCall::decode(&raw).unwrap() {
+            SystemCall::Print { request: (bytes, length) } => {
+                assert_eq!(bytes, message());
+                assert_eq!(length, 17);
+            },
+            _ => panic!(),
+        }
+
+        let raw = SystemCall::PageRemap {
+            request: (nested_caddr(), PageRights { read: true, write: false, execute: true },
+                      CachePolicy::Uncached),
+        }.encode().unwrap();
+        match SystemCall::decode(&raw).unwrap() {
+            SystemCall::PageRemap { request: (page, rights, cache) } => {
+                assert_eq!(page.0, nested_caddr().0);
+                assert_eq!(page.1, 3);
+                assert_eq!(rights, PageRights { read: true, write: false, execute: true });
+                assert_eq!(cache, CachePolicy::Uncached);
+            },
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn unknown_opcode() {
+        let mut raw = RawSystemCall::empty();
+        assert_eq!(SystemCall::decode(&raw).unwrap_err(), DecodeError::Opcode(0));
+        raw.opcode = 35;
+        assert_eq!(SystemCall::decode(&raw).unwrap_err(), DecodeError::Opcode(35));
+        assert_eq!(DecodeError::Opcode(35).status(), STATUS_BAD_OPCODE);
+    }
+
+    #[cfg(not(feature="kernel_debug"))]
+    #[test]
+    fn debug_opcode_without_feature() {
+        let mut raw = RawSystemCall::empty();
+        raw.opcode = 0x101;
+        assert_eq!(SystemCall::decode(&raw).unwrap_err(), DecodeError::Opcode(0x101));
+    }
+
+    #[test]
+    fn bad_version() {
+        let mut raw = SystemCall::PageUnmap { request: caddr(4) }.encode().unwrap();
+        raw.version = ABI_VERSION + 1;
+        assert_eq!(SystemCall::decode(&raw).unwrap_err(), DecodeError::Version(ABI_VERSION + 1));
+        assert_eq!(DecodeError::Version(0).status(), STATUS_BAD_VERSION);
+    }
+
+    #[test]
+    fn truncated_arguments() {
+        let mut args = [0; RAW_SYSTEM_CALL_ARGS];
+        {
+            let mut writer = Writer { args: &mut args, index: RAW_SYSTEM_CALL_ARGS - 1 };
+            assert_eq!(writer.put(&caddr(1)), Err(EncodeError));
+            let mut writer = Writer { args: &mut args, index: RAW_SYSTEM_CALL_ARGS };
+            assert_eq!(writer.put(&0u64), Err(EncodeError));
+        }
+
+        let args = [0; RAW_SYSTEM_CALL_ARGS];
+        let mut reader = Reader { args: &args, index: RAW_SYSTEM_CALL_ARGS - 1 };
+        assert_eq!(reader.get::<CAddr>().map(|_| ()), Err(DecodeError::Malformed));
+        let mut reader = Reader { args: &args, index: RAW_SYSTEM_CALL_ARGS - 4 };
+        assert_eq!(reader.get::<VSpaceMapping>().map(|_| ()), Ok(()));
+        assert_eq!(reader.get::<u64>(), Ok(0));
+        assert_eq!(reader.get::<u64>(), Err(DecodeError::Malformed));
+    }
+
+    #[test]
+    fn malformed_arguments() {
+        // RetypeRawPageFree is a `CAddr` request followed by an
+        // `Option<CAddr>` response.
+        let raw = SystemCall::RetypeRawPageFree { request: caddr(2), response: None }.encode().unwrap();
+
+        let mut bad_option = raw;
+        bad_option.args[2] = 2;
+        assert_eq!(SystemCall::decode(&bad_option).unwrap_err(), DecodeError::Malformed);
+
+        let mut bad_depth = raw;
+        bad_depth.args[1] = 9;
+        assert_eq!(SystemCall::decode(&bad_depth).unwrap_err(), DecodeError::Malformed);
+
+        let mut raw = SystemCall::PageRemap { request: (caddr(4), RW, CachePolicy::WriteBack) }.encode().unwrap();
+        raw.args[2] = 0b1000;
+        assert_eq!(SystemCall::decode(&raw).unwrap_err(), DecodeError::Malformed);
+        raw.args[2] = 0b011;
+        raw.args[3] = 3;
+        assert_eq!(SystemCall::decode(&raw).unwrap_err(), DecodeError::Malformed);
+        assert_eq!(DecodeError::Malformed.status(), STATUS_MALFORMED);
+    }
+}
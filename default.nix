@@ -49,7 +49,19 @@ x86_64-target-spec = stdenv.mkDerivation {
   '';
 };
 
-libcore = stdenv.mkDerivation {
+# Userspace is built with SSE enabled. The spec keeps the `x86_64`
+# file name, so that cargo puts userspace builds in `target/x86_64`.
+x86_64-userspace-target-spec = stdenv.mkDerivation {
+  name = "userspace-target-spec";
+  src = ./x86_64-userspace.json;
+  phases = [ "buildPhase" ];
+  buildPhase = ''
+    mkdir -p $out
+    cp $src $out/x86_64.json
+  '';
+};
+
+buildLibcore = target-spec: stdenv.mkDerivation {
   name = "libcore";
   buildInputs = [
     rust.rustc
@@ -57,11 +69,11 @@ libcore = stdenv.mkDerivation {
   phases = [ "buildPhase" ];
   buildPhase = ''
     mkdir -p $out
-    rustc --target=${x86_64-target-spec}/x86_64.json --out-dir=$out --crate-name=core --crate-type=lib ${rust.rust-src}/lib/rustlib/src/rust/src/libcore/lib.rs
+    rustc --target=${target-spec}/x86_64.json --out-dir=$out --crate-name=core --crate-type=lib ${rust.rust-src}/lib/rustlib/src/rust/src/libcore/lib.rs
   '';
 };
 
-libcompiler_builtins = stdenv.mkDerivation {
+buildLibcompilerBuiltins = target-spec: libcore: stdenv.mkDerivation {
   name = "libcompiler_builtins";
   buildInputs = [
     rust.rustc
@@ -69,11 +81,11 @@ libcompiler_builtins = stdenv.mkDerivation {
   phases = [ "buildPhase" ];
   buildPhase = ''
     mkdir -p $out
-    rustc -L ${libcore} --cfg 'feature="compiler-builtins"' --target=${x86_64-target-spec}/x86_64.json --out-dir=$out --crate-name=compiler_builtins --crate-type=lib ${rust.rust-src}/lib/rustlib/src/rust/src/libcompiler_builtins/src/lib.rs
+    rustc -L ${libcore} --cfg 'feature="compiler-builtins"' --target=${target-spec}/x86_64.json --out-dir=$out --crate-name=compiler_builtins --crate-type=lib ${rust.rust-src}/lib/rustlib/src/rust/src/libcompiler_builtins/src/lib.rs
   '';
 };
 
-liballoc = stdenv.mkDerivation {
+buildLiballoc = target-spec: libcore: libcompiler_builtins: stdenv.mkDerivation {
   name = "liballoc";
   buildInputs = [
     rust.rustc
@@ -81,10 +93,18 @@ liballoc = stdenv.mkDerivation {
   phases = [ "buildPhase" ];
   buildPhase = ''
     mkdir -p $out
-    rustc -L ${libcore} -L ${libcompiler_builtins} --target=${x86_64-target-spec}/x86_64.json --out-dir=$out --crate-name=alloc --crate-type=lib ${rust.rust-src}/lib/rustlib/src/rust/src/liballoc/lib.rs
+    rustc -L ${libcore} -L ${libcompiler_builtins} --target=${target-spec}/x86_64.json --out-dir=$out --crate-name=alloc --crate-type=lib ${rust.rust-src}/lib/rustlib/src/rust/src/liballoc/lib.rs
   '';
 };
 
+libcore = buildLibcore x86_64-target-spec;
+libcompiler_builtins = buildLibcompilerBuiltins x86_64-target-spec libcore;
+liballoc = buildLiballoc x86_64-target-spec libcore libcompiler_builtins;
+
+userspace-libcore = buildLibcore x86_64-userspace-target-spec;
+userspace-libcompiler_builtins = buildLibcompilerBuiltins x86_64-userspace-target-spec userspace-libcore;
+userspace-liballoc = buildLiballoc x86_64-userspace-target-spec userspace-libcore userspace-libcompiler_builtins;
+
 triple = "x86_64-none-elf";
 
 userspace-linker = stdenv.mkDerivation {
@@ -116,11 +136,16 @@ in stdenv.mkDerivation {
   RUST_SRC = "${rust.rust-src}";
   TARGET_SPEC = "${x86_64-target-spec}/x86_64.json";
   USERSPACE_LINKER = "${userspace-linker}/linker.ld";
+  USERSPACE_TARGET_SPEC = "${x86_64-userspace-target-spec}/x86_64.json";
 
   LIBCORE = "${libcore}";
   LIBCOMPILER_BUILTINS = "${libcompiler_builtins}";
   LIBALLOC = "${liballoc}";
 
+  USERSPACE_LIBCORE = "${userspace-libcore}";
+  USERSPACE_LIBCOMPILER_BUILTINS = "${userspace-libcompiler_builtins}";
+  USERSPACE_LIBALLOC = "${userspace-liballoc}";
+
   LD = "${triple}-ld";
   AS = "${triple}-as";
   OBJDUMP = "${triple}-objdump";
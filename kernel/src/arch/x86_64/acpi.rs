@@ -0,0 +1,337 @@
+use core::mem::size_of;
+use core::ptr;
+use core::slice;
+use common::PAddr;
+use util::{Mutex, MemoryObject};
+
+/// Maximum number of CPUs recorded from the MADT.
+pub const MAX_ACPI_CPUS: usize = 32;
+/// Maximum number of I/O APICs recorded from the MADT.
+pub const MAX_IO_APICS: usize = 8;
+/// Maximum number of interrupt source overrides recorded from the
+/// MADT.
+pub const MAX_INTERRUPT_OVERRIDES: usize = 16;
+
+/// Root System Description Pointer, as defined in ACPI 2.0. Version
+/// 1 tables end before `length`.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    _reserved: [u8; 3],
+}
+
+/// Length of an ACPI 1.0 RSDP.
+const RSDP_V1_LENGTH: usize = 20;
+
+/// Header common to all system description tables.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct SdtHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+/// A processor with its local APIC, from the MADT.
+#[derive(Debug, Clone, Copy)]
+pub struct AcpiCpu {
+    pub processor_id: u8,
+    pub apic_id: u8,
+}
+
+/// An I/O APIC, from the MADT.
+#[derive(Debug, Clone, Copy)]
+pub struct AcpiIoApic {
+    pub id: u8,
+    pub address: PAddr,
+    /// First global system interrupt handled by this I/O APIC.
+    pub gsi_base: u32,
+}
+
+/// An interrupt source override, from the MADT. Maps an ISA IRQ to a
+/// global system interrupt, with its polarity and trigger mode.
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    /// MPS INTI flags: polarity in bits 0-1, trigger mode in bits
+    /// 2-3.
+    pub flags: u16,
+}
+
+/// Routing of an interrupt to an I/O APIC input.
+#[derive(Debug, Clone, Copy)]
+pub struct IrqRoute {
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+/// Information discovered from the ACPI tables.
+#[derive(Debug)]
+pub struct AcpiInfo {
+    cpus: [Option<AcpiCpu>; MAX_ACPI_CPUS],
+    io_apics: [Option<AcpiIoApic>; MAX_IO_APICS],
+    overrides: [Option<InterruptOverride>; MAX_INTERRUPT_OVERRIDES],
+}
+
+impl AcpiInfo {
+    const fn empty() -> AcpiInfo {
+        AcpiInfo {
+            cpus: [None; MAX_ACPI_CPUS],
+            io_apics: [None; MAX_IO_APICS],
+            overrides: [None; MAX_INTERRUPT_OVERRIDES],
+        }
+    }
+
+    /// Enabled CPUs. Empty if no MADT was found.
+    pub fn cpus<'a>(&'a self) -> impl Iterator<Item=AcpiCpu> + 'a {
+        self.cpus.iter().filter_map(|cpu| *cpu)
+    }
+
+    /// I/O APICs. Empty if no MADT was found.
+    fn io_apics<'a>(&'a self) -> impl Iterator<Item=AcpiIoApic> + 'a {
+        self.io_apics.iter().filter_map(|io_apic| *io_apic)
+    }
+
+    /// I/O APIC handling global system interrupt 0, which the legacy
+    /// ISA IRQs are routed to.
+    pub fn primary_io_apic(&self) -> Option<AcpiIoApic> {
+        self.io_apics().find(|io_apic| io_apic.gsi_base == 0)
+            .or_else(|| self.io_apics().next())
+    }
+
+    /// Route of the ISA IRQ `irq`. Without an interrupt source
+    /// override, the IRQ is identity-mapped, active high and edge
+    /// triggered.
+    pub fn isa_irq_route(&self, irq: u8) -> IrqRoute {
+        let over = self.overrides.iter().filter_map(|over| *over).find(|over| over.irq == irq);
+        match over {
+            Some(over) => IrqRoute {
+                gsi: over.gsi,
+                active_low: over.flags & 0b11 == 0b11,
+                level_triggered: (over.flags >> 2) & 0b11 == 0b11,
+            },
+            None => IrqRoute {
+                gsi: irq as u32,
+                active_low: false,
+                level_triggered: false,
+            },
+        }
+    }
+}
+
+/// The ACPI information static, filled by `init`.
+pub static ACPI: Mutex<AcpiInfo> = Mutex::new(AcpiInfo::empty());
+
+/// Run `f` on `length` bytes of physical memory at `paddr`.
+fn with_physical<T, F: FnOnce(&[u8]) -> T>(paddr: PAddr, length: usize, f: F) -> T {
+    let object = unsafe { MemoryObject::<u8>::slice(paddr, length) };
+    f(unsafe { slice::from_raw_parts(object.as_ptr(), length) })
+}
+
+/// Read a `T` at `offset` of `bytes`. Returns `None` if it does not
+/// fit.
+fn read<T: Copy>(bytes: &[u8], offset: usize) -> Option<T> {
+    if offset + size_of::<T>() > bytes.len() {
+        return None;
+    }
+    Some(unsafe { ptr::read_unaligned(bytes.as_ptr().offset(offset as isize) as *const T) })
+}
+
+/// Whether the bytes sum up to zero, as required for ACPI tables.
+fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/// Search for the RSDP on 16-byte boundaries in `length` bytes of
+/// physical memory at `start`.
+fn scan_rsdp(start: PAddr, length: usize) -> Option<(PAddr, Rsdp)> {
+    with_physical(start, length, |bytes| {
+        let mut offset = 0;
+        while offset + RSDP_V1_LENGTH <= length {
+            if &bytes[offset..(offset + 8)] != b"RSD PTR " ||
+                !checksum(&bytes[offset..(offset + RSDP_V1_LENGTH)])
+            {
+                offset += 16;
+                continue;
+            }
+            let mut rsdp: Rsdp = unsafe { ::core::mem::zeroed() };
+            let copy_length = if offset + size_of::<Rsdp>() > length {
+                RSDP_V1_LENGTH
+            } else {
+                size_of::<Rsdp>()
+            };
+            unsafe {
+                ptr::copy_nonoverlapping(bytes.as_ptr().offset(offset as isize),
+                                         &mut rsdp as *mut Rsdp as *mut u8, copy_length);
+            }
+            return Some((start + offset, rsdp));
+        }
+        None
+    })
+}
+
+/// Find the RSDP with the BIOS scan: the first KiB of the extended
+/// BIOS data area, then the BIOS area below 1 MiB. Multiboot 1 does
+/// not pass the RSDP, so this is the only way to find it.
+fn find_rsdp() -> Option<(PAddr, Rsdp)> {
+    let ebda_segment: u16 = with_physical(PAddr::from(0x40E: usize), 2, |bytes| read(bytes, 0))?;
+    let ebda = (ebda_segment as usize) << 4;
+
+    if ebda >= 0x80000 && ebda < 0xA0000 {
+        if let Some(found) = scan_rsdp(PAddr::from(ebda), 0x400) {
+            return Some(found);
+        }
+    }
+    scan_rsdp(PAddr::from(0xE0000: usize), 0x20000)
+}
+
+/// Run `f` on the system description table at `paddr`, including its
+/// header. Returns `None` if the checksum does not match.
+fn with_table<T, F: FnOnce(SdtHeader, &[u8]) -> T>(paddr: PAddr, f: F) -> Option<T> {
+    let header: SdtHeader = with_physical(paddr, size_of::<SdtHeader>(), |bytes| read(bytes, 0))?;
+    let length = header.length as usize;
+    if length < size_of::<SdtHeader>() {
+        return None;
+    }
+
+    with_physical(paddr, length, |bytes| {
+        if !checksum(bytes) {
+            log!("ACPI table {:?} at 0x{:x} has a bad checksum.",
+                 ::core::str::from_utf8(&header.signature), paddr);
+            return None;
+        }
+        Some(f(header, bytes))
+    })
+}
+
+/// Parse the MADT, recording the enabled CPUs, the I/O APICs and the
+/// interrupt source overrides. The local APIC is found through the
+/// `IA32_APIC_BASE` MSR instead.
+fn parse_madt(info: &mut AcpiInfo, bytes: &[u8]) {
+    let (mut cpus, mut io_apics, mut overrides) = (0, 0, 0);
+    let mut offset = 44;
+    while let (Some(entry_type), Some(entry_length)) = (read::<u8>(bytes, offset), read::<u8>(bytes, offset + 1)) {
+        let entry_length = entry_length as usize;
+        if entry_length < 2 || offset + entry_length > bytes.len() {
+            break;
+        }
+        let entry = &bytes[offset..(offset + entry_length)];
+
+        match entry_type {
+            // Processor local APIC. Flags bit 0 is enabled. Online
+            // capable CPUs (bit 1) are not started.
+            0 if entry_length >= 8 => {
+                let flags: u32 = read(entry, 4).unwrap();
+                if flags & 1 != 0 {
+                    if cpus < MAX_ACPI_CPUS {
+                        info.cpus[cpus] = Some(AcpiCpu {
+                            processor_id: entry[2],
+                            apic_id: entry[3],
+                        });
+                        cpus += 1;
+                    } else {
+                        log!("Too many CPUs, ignoring local APIC 0x{:x}.", entry[3]);
+                    }
+                }
+            },
+            // I/O APIC.
+            1 if entry_length >= 12 => {
+                if io_apics < MAX_IO_APICS {
+                    info.io_apics[io_apics] = Some(AcpiIoApic {
+                        id: entry[2],
+                        address: PAddr::from(read::<u32>(entry, 4).unwrap() as u64),
+                        gsi_base: read(entry, 8).unwrap(),
+                    });
+                    io_apics += 1;
+                } else {
+                    log!("Too many I/O APICs, ignoring I/O APIC 0x{:x}.", entry[2]);
+                }
+            },
+            // Interrupt source override. Only the ISA bus (0) is
+            // defined.
+            2 if entry_length >= 10 => {
+                if overrides < MAX_INTERRUPT_OVERRIDES {
+                    info.overrides[overrides] = Some(InterruptOverride {
+                        irq: entry[3],
+                        gsi: read(entry, 4).unwrap(),
+                        flags: read(entry, 8).unwrap(),
+                    });
+                    overrides += 1;
+                } else {
+                    log!("Too many interrupt source overrides, ignoring IRQ {}.", entry[3]);
+                }
+            },
+            _ => (),
+        }
+
+        offset += entry_length;
+    }
+}
+
+/// Find and parse the ACPI tables, filling `ACPI`. Leaves it empty if
+/// there is no RSDP. Must be called after paging is initialized.
+pub fn init() {
+    let (rsdp_paddr, rsdp) = match find_rsdp() {
+        Some(found) => found,
+        None => {
+            log!("ACPI RSDP not found.");
+            return;
+        },
+    };
+    log!("ACPI RSDP at 0x{:x}, revision {}", rsdp_paddr, rsdp.revision);
+
+    // Use the XSDT if there is one, with 64-bit entries.
+    let (root_paddr, entry_length) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (PAddr::from(rsdp.xsdt_address), 8)
+    } else {
+        (PAddr::from(rsdp.rsdt_address as u64), 4)
+    };
+
+    let mut tables = [None; 32];
+    let count = with_table(root_paddr, |_, bytes| {
+        let entries = (bytes.len() - size_of::<SdtHeader>()) / entry_length;
+        let mut count = 0;
+        for i in 0..entries {
+            if count == tables.len() {
+                log!("Too many ACPI tables, ignoring the rest.");
+                break;
+            }
+            let offset = size_of::<SdtHeader>() + i * entry_length;
+            tables[count] = if entry_length == 8 {
+                read::<u64>(bytes, offset).map(PAddr::from)
+            } else {
+                read::<u32>(bytes, offset).map(|paddr| PAddr::from(paddr as u64))
+            };
+            count += 1;
+        }
+        count
+    }).unwrap_or(0);
+
+    let mut info = ACPI.lock();
+    for table in tables[..count].iter().filter_map(|table| *table) {
+        with_table(table, |header, bytes| {
+            match &header.signature {
+                b"APIC" => parse_madt(&mut info, bytes),
+                _ => (),
+            }
+        });
+    }
+
+    log!("ACPI: {:?}", *info);
+}
@@ -0,0 +1,190 @@
+use core::mem::size_of;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use elf::{ElfBinary, FileHeader, SectionHeader, SectionType, SectionFlag, StrOffset,
+          ELF_MAGIC, SHT_NULL, SHT_SYMTAB, SHT_STRTAB, STT_FUNC};
+use arch::{cpu, outportb};
+
+/// Length of the buffer the kernel symbols are copied to.
+const SYMBOLS_LENGTH: usize = 1024 * 1024;
+/// Most frames printed in a backtrace, in case the frame pointers
+/// form a loop.
+const MAX_FRAMES: usize = 64;
+/// Kernel code, data and stacks all lie in the higher half.
+const KERNEL_SPACE_START: u64 = 0xFFFF800000000000;
+
+/// Port of the QEMU `isa-debug-exit` device, and the value written
+/// to it when a test fails.
+const DEBUG_EXIT_PORT: u16 = 0x501;
+const DEBUG_EXIT_FAILURE: u8 = 0x30;
+
+/// Section name string table of the symbol image. `.symtab` is at
+/// offset 1, `.strtab` at 9 and `.shstrtab` at 17.
+const SECTION_NAMES: &'static [u8] = b"\0.symtab\0.strtab\0.shstrtab\0";
+
+/// Kernel symbols, as a minimal ELF image holding only the symbol
+/// table and its string table, so that it can be read with
+/// `ElfBinary`.
+static mut SYMBOLS: [u8; SYMBOLS_LENGTH] = [0; SYMBOLS_LENGTH];
+/// Length of the image in `SYMBOLS`, or 0 if there is none.
+static SYMBOLS_USED: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether a panic exits QEMU with a test failure.
+static EXIT_ON_PANIC: AtomicBool = AtomicBool::new(false);
+/// Set by the first panic, so that a panic while reporting a panic
+/// does not report again.
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Copy the kernel symbol table `symtab` and its string table
+/// `strtab` into the symbol image. Returns `None` if they do not
+/// fit.
+///
+/// # Safety
+///
+/// Must be called during initialization, before any backtrace is
+/// printed.
+pub unsafe fn load_symbols(symtab: &[u8], strtab: &[u8]) -> Option<()> {
+    let headers_offset = size_of::<FileHeader>();
+    let names_offset = headers_offset + 4 * size_of::<SectionHeader>();
+    let symtab_offset = names_offset + SECTION_NAMES.len();
+    let strtab_offset = symtab_offset + symtab.len();
+    let length = strtab_offset + strtab.len();
+    if length > SYMBOLS_LENGTH {
+        return None;
+    }
+
+    ptr::copy_nonoverlapping(ELF_MAGIC.as_ptr(), SYMBOLS.as_mut_ptr(), ELF_MAGIC.len());
+    {
+        let header = &mut *(SYMBOLS.as_mut_ptr() as *mut FileHeader);
+        header.shoff = headers_offset as u64;
+        header.shentsize = size_of::<SectionHeader>() as u16;
+        header.shnum = 4;
+        header.shstrndx = 3;
+    }
+
+    let section = |name: u32, shtype: SectionType, offset: usize, size: usize, link: u32| SectionHeader {
+        name: StrOffset(name),
+        shtype: shtype,
+        flags: SectionFlag(0),
+        addr: 0,
+        offset: offset as u64,
+        size: size as u64,
+        link: link,
+        info: 0,
+        addralign: 1,
+        entsize: 0,
+    };
+    let headers = SYMBOLS.as_mut_ptr().offset(headers_offset as isize) as *mut SectionHeader;
+    ptr::write(headers, section(0, SHT_NULL, 0, 0, 0));
+    ptr::write(headers.offset(1), section(1, SHT_SYMTAB, symtab_offset, symtab.len(), 2));
+    ptr::write(headers.offset(2), section(9, SHT_STRTAB, strtab_offset, strtab.len(), 0));
+    ptr::write(headers.offset(3), section(17, SHT_STRTAB, names_offset, SECTION_NAMES.len(), 0));
+
+    SYMBOLS[names_offset..symtab_offset].copy_from_slice(SECTION_NAMES);
+    SYMBOLS[symtab_offset..strtab_offset].copy_from_slice(symtab);
+    SYMBOLS[strtab_offset..length].copy_from_slice(strtab);
+
+    SYMBOLS_USED.store(length, Ordering::SeqCst);
+    Some(())
+}
+
+/// Make a panic exit QEMU with a test failure, for running tests.
+pub fn set_exit_on_panic(exit: bool) {
+    EXIT_ON_PANIC.store(exit, Ordering::SeqCst);
+}
+
+/// The kernel symbol image, if the symbols were loaded.
+fn kernel_symbols() -> Option<ElfBinary<'static>> {
+    let length = SYMBOLS_USED.load(Ordering::SeqCst);
+    if length == 0 {
+        return None;
+    }
+    ElfBinary::new("kernel", unsafe { &SYMBOLS[..length] })
+}
+
+/// Name of the function containing `addr`, and the offset of `addr`
+/// in it.
+fn symbolize(symbols: &ElfBinary<'static>, addr: u64) -> Option<(&'static str, u64)> {
+    let mut found = None;
+    symbols.for_each_symbol(|symbol| {
+        let (value, size) = (symbol.value, symbol.size);
+        if symbol.sym_type() == STT_FUNC && value <= addr && addr < value + size {
+            found = Some(symbol);
+        }
+    });
+    found.map(|symbol| (symbols.symbol_name(symbol), addr - symbol.value))
+}
+
+/// Print the return addresses of the frames on the current stack,
+/// following the saved frame pointers from `rbp`. The kernel keeps
+/// frame pointers, as `eliminate-frame-pointer` is off in its target
+/// specification.
+pub fn print_backtrace() {
+    let mut rbp: u64;
+    unsafe { asm!("mov %rbp, $0" : "=r"(rbp) ::: "volatile"); }
+    let symbols = kernel_symbols();
+
+    error!("Backtrace:");
+    for _ in 0..MAX_FRAMES {
+        if rbp < KERNEL_SPACE_START || rbp % 8 != 0 {
+            break;
+        }
+        let return_address = unsafe { *((rbp + 8) as *const u64) };
+        if return_address == 0 {
+            break;
+        }
+        match symbols.as_ref().and_then(|symbols| symbolize(symbols, return_address)) {
+            Some((name, offset)) => error!("  0x{:016x} {}+0x{:x}", return_address, name, offset),
+            None => error!("  0x{:016x}", return_address),
+        }
+        rbp = unsafe { *(rbp as *const u64) };
+    }
+}
+
+/// Print the task the current CPU last switched to.
+pub fn print_current_task() {
+    match unsafe { cpu::current().current_task } {
+        Some(task) => error!("Current task: 0x{:x}", task),
+        None => error!("Current task: idle"),
+    }
+}
+
+/// Print the registers the current task last entered the kernel
+/// with.
+fn print_task_registers() {
+    let cpu = unsafe { cpu::current() };
+    let registers = cpu.registers;
+    error!("rax = 0x{:016x}, rbx = 0x{:016x}, rcx = 0x{:016x}, rdx = 0x{:016x}",
+           registers.rax, registers.rbx, registers.rcx, registers.rdx);
+    error!("rsi = 0x{:016x}, rdi = 0x{:016x}, rbp = 0x{:016x}, r8  = 0x{:016x}",
+           registers.rsi, registers.rdi, registers.rbp, registers.r8);
+    error!("r9  = 0x{:016x}, r10 = 0x{:016x}, r11 = 0x{:016x}, r12 = 0x{:016x}",
+           registers.r9, registers.r10, registers.r11, registers.r12);
+    error!("r13 = 0x{:016x}, r14 = 0x{:016x}, r15 = 0x{:016x}",
+           registers.r13, registers.r14, registers.r15);
+    if let Some(frame) = cpu.exception_stack_frame {
+        error!("rip = 0x{:016x}, rsp = 0x{:016x}, rflags = 0x{:x}, cs = 0x{:x}, ss = 0x{:x}",
+               frame.instruction_pointer, frame.stack_pointer, frame.cpu_flags,
+               frame.code_segment, frame.stack_segment);
+    }
+}
+
+/// Report a kernel panic after its message has been printed: the
+/// current task and its registers, and a backtrace of the kernel.
+/// Exits QEMU if running a test.
+pub fn report_panic() {
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        error!("Panic while another panic is being reported.");
+    } else {
+        // The per-CPU data is only reachable once it is initialized.
+        if cpu::count() > 0 {
+            print_current_task();
+            print_task_registers();
+        }
+        print_backtrace();
+    }
+
+    if EXIT_ON_PANIC.load(Ordering::SeqCst) {
+        unsafe { outportb(DEBUG_EXIT_PORT, DEBUG_EXIT_FAILURE); }
+    }
+}
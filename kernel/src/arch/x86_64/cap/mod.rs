@@ -8,6 +8,10 @@ macro_rules! doto_arch_any {
             $f ($any.into(): ::arch::cap::PDCap, $($param),*)
         } else if $any.is::<::arch::cap::PTCap>() {
             $f ($any.into(): ::arch::cap::PTCap, $($param),*)
+        } else if $any.is::<::arch::cap::LargePageCap>() {
+            $f ($any.into(): ::arch::cap::LargePageCap, $($param),*)
+        } else if $any.is::<::arch::cap::HugePageCap>() {
+            $f ($any.into(): ::arch::cap::HugePageCap, $($param),*)
         } else {
             panic!();
         }
@@ -22,6 +26,8 @@ pub use self::paging::{PML4Descriptor, PML4Cap,
                        PDDescriptor, PDCap,
                        PTDescriptor, PTCap,
                        PageDescriptor, PageCap,
+                       LargePageDescriptor, LargePageCap,
+                       HugePageDescriptor, HugePageCap,
                        PAGE_LENGTH};
 
 /// The top-level page table capability. In `x86_64`, this is PML4.
@@ -30,6 +36,12 @@ pub type TopPageTableCap = PML4Cap;
 use common::*;
 use core::any::{TypeId};
 use util::managed_arc::{ManagedArc, ManagedArcAny};
+use cap::Mappable;
+
+impl Mappable for PML4Cap { }
+impl Mappable for PDPTCap { }
+impl Mappable for PDCap { }
+impl Mappable for PTCap { }
 
 /// Create a managed Arc (capability) from an address of an
 /// architecture-specific kernel object. The `type_id` should be a
@@ -50,6 +62,10 @@ pub unsafe fn upgrade_arch_any(ptr: PAddr, type_id: TypeId) -> Option<ManagedArc
         Some({ ManagedArc::from_ptr(ptr): PDCap }.into())
     } else if type_id == TypeId::of::<PTCap>() {
         Some({ ManagedArc::from_ptr(ptr): PTCap }.into())
+    } else if type_id == TypeId::of::<LargePageCap>() {
+        Some({ ManagedArc::from_ptr(ptr): LargePageCap }.into())
+    } else if type_id == TypeId::of::<HugePageCap>() {
+        Some({ ManagedArc::from_ptr(ptr): HugePageCap }.into())
     } else {
         None
     }
@@ -67,6 +83,10 @@ pub fn drop_any(any: ManagedArcAny) {
         any.into(): PDCap;
     } else if any.is::<PTCap>() {
         any.into(): PTCap;
+    } else if any.is::<LargePageCap>() {
+        any.into(): LargePageCap;
+    } else if any.is::<HugePageCap>() {
+        any.into(): HugePageCap;
     } else {
         panic!();
     }
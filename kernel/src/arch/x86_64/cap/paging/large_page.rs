@@ -0,0 +1,215 @@
+use common::*;
+use arch::paging::{BASE_PAGE_LENGTH, LARGE_PAGE_LENGTH, HUGE_PAGE_LENGTH,
+                   PDEntry, PDPTEntry, pd_index, pdpt_index, flush};
+use util::{MemoryObject, UniqueWriteGuard, RwLock};
+use util::managed_arc::{ManagedWeakPool1Arc};
+use super::{LargePageDescriptor, LargePageCap, HugePageDescriptor, HugePageCap, PML4Cap,
+            large_page_flags, huge_page_flags, CHUNK_LENGTH};
+use cap::{UntypedDescriptor, SetDefault, Identify, Mappable, RawPage};
+use abi::{CapType, CapInfo, CapDetail, PageRights, CachePolicy};
+
+macro_rules! large_page_cap {
+    ( $cap:ty, $desc:tt, $length:expr, $cap_type:expr ) => (
+        impl $cap {
+            /// Create the page capability from an untyped
+            /// capability. The page is aligned to its length. Only
+            /// its first chunk is zeroed here, see `zero_chunk` for
+            /// the rest.
+            pub fn retype_from(untyped: &mut UntypedDescriptor) -> Option<Self> {
+                untyped.transaction(|untyped| {
+                    let mut arc: Option<Self> = None;
+
+                    let start_paddr = unsafe { untyped.allocate($length, $length)? };
+
+                    let mapped_weak_pool = unsafe { ManagedWeakPool1Arc::create(
+                        untyped.allocate(ManagedWeakPool1Arc::inner_length(),
+                                         ManagedWeakPool1Arc::inner_alignment())?) };
+
+                    unsafe {
+                        untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
+                            arc = Some(
+                                Self::new(paddr, RwLock::new($desc {
+                                    mapped_weak_pool: mapped_weak_pool,
+                                    mapped_vaddr: None,
+                                    start_paddr: start_paddr,
+                                    zeroed_length: 0,
+                                    next: next_child,
+                                }))
+                            );
+
+                            arc.clone().unwrap().into()
+                        })?;
+                    }
+
+                    if let Some(ref arc) = arc {
+                        arc.write().zero_chunk();
+                    }
+                    arc
+                })
+            }
+        }
+
+        impl $desc {
+            pub fn start_paddr(&self) -> PAddr {
+                self.start_paddr
+            }
+
+            pub fn length(&self) -> usize {
+                $length
+            }
+
+            /// Virtual address the page is mapped at, if it is mapped.
+            pub fn mapped_vaddr(&self) -> Option<VAddr> {
+                self.mapped_vaddr
+            }
+
+            /// Whether all of the page has been zeroed.
+            pub fn is_zeroed(&self) -> bool {
+                self.zeroed_length == $length
+            }
+
+            /// Length of the page that is not zeroed yet.
+            pub fn remaining_zero_length(&self) -> usize {
+                $length - self.zeroed_length
+            }
+
+            /// Zero the next `CHUNK_LENGTH` of the page, one base
+            /// page at a time, unless it is already zeroed. Returns
+            /// whether all of the page is zeroed afterwards.
+            pub fn zero_chunk(&mut self) -> bool {
+                if !self.is_zeroed() {
+                    for i in 0..(CHUNK_LENGTH / BASE_PAGE_LENGTH) {
+                        let mut page = unsafe {
+                            UniqueWriteGuard::<RawPage>::new(MemoryObject::new(
+                                self.start_paddr + self.zeroed_length + i * BASE_PAGE_LENGTH))
+                        };
+                        page.set_default();
+                    }
+                    self.zeroed_length += CHUNK_LENGTH;
+                }
+                self.is_zeroed()
+            }
+        }
+
+        impl Identify for $cap {
+            fn identify(&self) -> CapInfo {
+                let desc = self.read();
+                CapInfo {
+                    cap_type: $cap_type,
+                    paddr: desc.start_paddr().into(),
+                    length: desc.length(),
+                    detail: CapDetail::None,
+                }
+            }
+        }
+    )
+}
+
+large_page_cap!(LargePageCap, LargePageDescriptor, LARGE_PAGE_LENGTH, CapType::LargePage);
+large_page_cap!(HugePageCap, HugePageDescriptor, HUGE_PAGE_LENGTH, CapType::HugePage);
+
+impl LargePageCap {
+    /// Unmap the page from the address space it is mapped in, and
+    /// invalidate the TLB entry. Returns `None` if the page is not
+    /// mapped.
+    pub fn unmap(&self) -> Option<()> {
+        let mut desc = self.write();
+        let vaddr = desc.mapped_vaddr?;
+        let pml4_cap: PML4Cap = desc.mapped_weak_pool.read().upgrade(0)?;
+        let mut pd = unsafe { UniqueWriteGuard::new(pml4_cap.read().pd_object(vaddr)?) };
+
+        pd[pd_index(vaddr)] = PDEntry::empty();
+        desc.mapped_weak_pool.read().remove(0);
+        desc.mapped_vaddr = None;
+        unsafe { flush(vaddr) };
+        pml4_cap.read().shootdown();
+        Some(())
+    }
+
+    /// Change the rights and cache policy of the mapped page, and
+    /// invalidate the TLB entry. Returns `None` if the page is not
+    /// mapped.
+    pub fn remap(&self, rights: PageRights, cache: CachePolicy) -> Option<()> {
+        let desc = self.read();
+        let vaddr = desc.mapped_vaddr?;
+        let pml4_cap: PML4Cap = desc.mapped_weak_pool.read().upgrade(0)?;
+        let mut pd = unsafe { UniqueWriteGuard::new(pml4_cap.read().pd_object(vaddr)?) };
+
+        pd[pd_index(vaddr)] = PDEntry::new(desc.start_paddr(), large_page_flags(rights, cache));
+        unsafe { flush(vaddr) };
+        pml4_cap.read().shootdown();
+        Some(())
+    }
+}
+
+impl Mappable for LargePageCap {
+    fn map_page(&self, pml4: &mut PML4Cap, vaddr: VAddr, rights: PageRights,
+                cache: CachePolicy, untyped: &mut UntypedDescriptor) -> Option<usize> {
+        if !self.write().zero_chunk() {
+            return Some(self.read().remaining_zero_length());
+        }
+        pml4.map_large(vaddr, self, rights, cache, untyped)?;
+        Some(0)
+    }
+
+    fn unmap_page(&self) -> Option<()> {
+        self.unmap()
+    }
+
+    fn remap_page(&self, rights: PageRights, cache: CachePolicy) -> Option<()> {
+        self.remap(rights, cache)
+    }
+}
+
+impl HugePageCap {
+    /// Unmap the page from the address space it is mapped in, and
+    /// invalidate the TLB entry. Returns `None` if the page is not
+    /// mapped.
+    pub fn unmap(&self) -> Option<()> {
+        let mut desc = self.write();
+        let vaddr = desc.mapped_vaddr?;
+        let pml4_cap: PML4Cap = desc.mapped_weak_pool.read().upgrade(0)?;
+        let mut pdpt = unsafe { UniqueWriteGuard::new(pml4_cap.read().pdpt_object(vaddr)?) };
+
+        pdpt[pdpt_index(vaddr)] = PDPTEntry::empty();
+        desc.mapped_weak_pool.read().remove(0);
+        desc.mapped_vaddr = None;
+        unsafe { flush(vaddr) };
+        pml4_cap.read().shootdown();
+        Some(())
+    }
+
+    /// Change the rights and cache policy of the mapped page, and
+    /// invalidate the TLB entry. Returns `None` if the page is not
+    /// mapped.
+    pub fn remap(&self, rights: PageRights, cache: CachePolicy) -> Option<()> {
+        let desc = self.read();
+        let vaddr = desc.mapped_vaddr?;
+        let pml4_cap: PML4Cap = desc.mapped_weak_pool.read().upgrade(0)?;
+        let mut pdpt = unsafe { UniqueWriteGuard::new(pml4_cap.read().pdpt_object(vaddr)?) };
+
+        pdpt[pdpt_index(vaddr)] = PDPTEntry::new(desc.start_paddr(), huge_page_flags(rights, cache));
+        unsafe { flush(vaddr) };
+        pml4_cap.read().shootdown();
+        Some(())
+    }
+}
+
+impl Mappable for HugePageCap {
+    fn map_page(&self, pml4: &mut PML4Cap, vaddr: VAddr, rights: PageRights,
+                cache: CachePolicy, untyped: &mut UntypedDescriptor) -> Option<usize> {
+        if !self.write().zero_chunk() {
+            return Some(self.read().remaining_zero_length());
+        }
+        pml4.map_huge(vaddr, self, rights, cache, untyped)?;
+        Some(0)
+    }
+
+    fn unmap_page(&self) -> Option<()> {
+        self.unmap()
+    }
+
+    fn remap_page(&self, rights: PageRights, cache: CachePolicy) -> Option<()> {
+        self.remap(rights, cache)
+    }
+}
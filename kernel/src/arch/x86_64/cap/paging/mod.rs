@@ -1,22 +1,73 @@
 mod page;
+mod large_page;
 mod pml4;
 
 use common::*;
-use arch::paging::{BASE_PAGE_LENGTH,
-                   PT, PTEntry, PT_P, PT_RW, PT_US,
-                   PD, PDEntry, PD_P, PD_RW, PD_US,
-                   PDPT, PDPTEntry, PDPT_P, PDPT_RW, PDPT_US};
+use arch::paging::{BASE_PAGE_LENGTH, LARGE_PAGE_LENGTH,
+                   PT, PTEntry, PT_P, PT_RW, PT_US, PT_PWT, PT_PCD, PT_XD,
+                   PD, PDEntry, PD_P, PD_RW, PD_US, PD_PS,
+                   PDPT, PDPTEntry, PDPT_P, PDPT_RW, PDPT_US, PDPT_PS};
 use util::{MemoryObject, UniqueReadGuard, UniqueWriteGuard, RwLock};
 use util::managed_arc::{ManagedArc, ManagedArcAny, ManagedWeakPool1Arc};
 use core::marker::{PhantomData};
 use core::any::{Any};
-use cap::{UntypedDescriptor, SetDefault};
+use cap::{UntypedDescriptor, SetDefault, Identify};
+use abi::{CapType, CapInfo, CapDetail, PageRights, CachePolicy};
 
 /// Page length used in current kernel. This is `BASE_PAGE_LENGTH` in x86_64.
 pub const PAGE_LENGTH: usize = BASE_PAGE_LENGTH;
 
+/// Length zeroed or copied in one go when preparing a large or huge
+/// page. Doing a whole huge page at once would keep the kernel busy
+/// for too long.
+const CHUNK_LENGTH: usize = LARGE_PAGE_LENGTH;
+
+/// Page table entry flags of a user-accessible page with the given
+/// rights and cache policy. Pages are always readable on x86_64.
+fn page_flags(rights: PageRights, cache: CachePolicy) -> PTEntry {
+    let mut flags = PT_P | PT_US;
+    if rights.write {
+        flags = flags | PT_RW;
+    }
+    if !rights.execute {
+        flags = flags | PT_XD;
+    }
+    match cache {
+        CachePolicy::WriteBack => flags,
+        CachePolicy::WriteThrough => flags | PT_PWT,
+        CachePolicy::Uncached => flags | PT_PCD | PT_PWT,
+    }
+}
+
+/// PD entry flags of a large page. The access and cache bits are at
+/// the same positions as in a PT entry.
+fn large_page_flags(rights: PageRights, cache: CachePolicy) -> PDEntry {
+    PDEntry::from_bits_truncate(page_flags(rights, cache).bits()) | PD_PS
+}
+
+/// PDPT entry flags of a huge page.
+fn huge_page_flags(rights: PageRights, cache: CachePolicy) -> PDPTEntry {
+    PDPTEntry::from_bits_truncate(page_flags(rights, cache).bits()) | PDPT_PS
+}
+
+/// A huge page being copied into an address space by
+/// `clone_vspace`, `CHUNK_LENGTH` per call.
+struct PendingCopy {
+    page: HugePageCap,
+    source_paddr: PAddr,
+    vaddr: VAddr,
+    copied_length: usize,
+}
+
 /// PML4 page table descriptor.
+///
+/// `untyped_weak_pool` holds the untyped capability new frames are
+/// retyped from when a copy-on-write page in this address space is
+/// written to. `pending_copy` is the huge page an unfinished clone
+/// into this address space is copying.
 pub struct PML4Descriptor {
+    untyped_weak_pool: ManagedWeakPool1Arc,
+    pending_copy: Option<PendingCopy>,
     start_paddr: PAddr,
     #[allow(dead_code)]
     next: Option<ManagedArcAny>,
@@ -62,9 +113,21 @@ pub struct PTDescriptor {
 pub type PTCap = ManagedArc<RwLock<PTDescriptor>>;
 
 /// Page descriptor.
+///
+/// Copies of a page share its physical memory but have their own
+/// mapping. The original page and all its copies form a derivation
+/// list in `next_copy`, where each copy directly follows the page it
+/// is derived from, with a larger `copy_depth`.
+///
+/// `device` marks memory that is not RAM, such as the VGA buffer.
 pub struct PageDescriptor<T: SetDefault + Any> {
     mapped_weak_pool: ManagedWeakPool1Arc,
+    mapped_vaddr: Option<VAddr>,
     start_paddr: PAddr,
+    copy_depth: usize,
+    next_copy: Option<PageCap<T>>,
+    revoked: bool,
+    device: bool,
     #[allow(dead_code)]
     next: Option<ManagedArcAny>,
     _marker: PhantomData<T>
@@ -73,49 +136,87 @@ pub struct PageDescriptor<T: SetDefault + Any> {
 /// Page capability.
 pub type PageCap<T> = ManagedArc<RwLock<PageDescriptor<T>>>;
 
-macro_rules! paging_cap {
-    ( $cap:ty, $desc:tt, $paging:ty, $entry:tt, $map_fn:ident, $sub_cap:ty, $access:expr ) => (
-        impl $cap {
-            pub fn retype_from(untyped: &mut UntypedDescriptor) -> Self {
-                let mut arc: Option<Self> = None;
-
-                let start_paddr = unsafe { untyped.allocate(BASE_PAGE_LENGTH, BASE_PAGE_LENGTH) };
-
-                let mapped_weak_pool = unsafe { ManagedWeakPool1Arc::create(
-                    untyped.allocate(ManagedWeakPool1Arc::inner_length(),
-                                     ManagedWeakPool1Arc::inner_alignment())) };
-
-                unsafe {
-                    untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
-                        let mut desc = $desc {
-                            mapped_weak_pool: mapped_weak_pool,
-                            start_paddr: start_paddr,
-                            next: next_child,
-                        };
+/// Large page (2 MiB) descriptor.
+///
+/// `zeroed_length` is the length at the start of the page that has
+/// been zeroed. The page can only be mapped once all of it is.
+pub struct LargePageDescriptor {
+    mapped_weak_pool: ManagedWeakPool1Arc,
+    mapped_vaddr: Option<VAddr>,
+    start_paddr: PAddr,
+    zeroed_length: usize,
+    #[allow(dead_code)]
+    next: Option<ManagedArcAny>,
+}
 
-                        for item in desc.write().iter_mut() {
-                            *item = $entry::empty();
-                        }
+/// Large page (2 MiB) capability. Mapped directly in a PD.
+pub type LargePageCap = ManagedArc<RwLock<LargePageDescriptor>>;
 
-                        arc = Some(
-                            Self::new(paddr, RwLock::new(desc))
-                        );
+/// Huge page (1 GiB) descriptor. See `LargePageDescriptor` for
+/// `zeroed_length`.
+pub struct HugePageDescriptor {
+    mapped_weak_pool: ManagedWeakPool1Arc,
+    mapped_vaddr: Option<VAddr>,
+    start_paddr: PAddr,
+    zeroed_length: usize,
+    #[allow(dead_code)]
+    next: Option<ManagedArcAny>,
+}
 
-                        arc.clone().unwrap().into()
-                    });
-                }
+/// Huge page (1 GiB) capability. Mapped directly in a PDPT.
+pub type HugePageCap = ManagedArc<RwLock<HugePageDescriptor>>;
 
-                arc.unwrap()
+macro_rules! paging_cap {
+    ( $cap:ty, $desc:tt, $paging:ty, $entry:tt, $map_fn:ident, $sub_cap:ty, $access:expr, $cap_type:expr ) => (
+        impl $cap {
+            pub fn retype_from(untyped: &mut UntypedDescriptor) -> Option<Self> {
+                untyped.transaction(|untyped| {
+                    let mut arc: Option<Self> = None;
+
+                    let start_paddr = unsafe { untyped.allocate(BASE_PAGE_LENGTH, BASE_PAGE_LENGTH)? };
+
+                    let mapped_weak_pool = unsafe { ManagedWeakPool1Arc::create(
+                        untyped.allocate(ManagedWeakPool1Arc::inner_length(),
+                                         ManagedWeakPool1Arc::inner_alignment())?) };
+
+                    unsafe {
+                        untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
+                            let mut desc = $desc {
+                                mapped_weak_pool: mapped_weak_pool,
+                                start_paddr: start_paddr,
+                                next: next_child,
+                            };
+
+                            for item in desc.write().iter_mut() {
+                                *item = $entry::empty();
+                            }
+
+                            arc = Some(
+                                Self::new(paddr, RwLock::new(desc))
+                            );
+
+                            arc.clone().unwrap().into()
+                        })?;
+                    }
+
+                    arc
+                })
             }
 
-            pub fn $map_fn(&mut self, index: usize, sub: &$sub_cap) {
+            /// Map a lower-level table at `index`. Returns `None` if
+            /// the entry is already present or the table is already
+            /// mapped.
+            pub fn $map_fn(&mut self, index: usize, sub: &$sub_cap) -> Option<()> {
                 let mut current_desc = self.write();
                 let mut current = current_desc.write();
                 let sub_desc = sub.read();
-                assert!(!current[index].is_present());
+                if current[index].is_present() || !sub_desc.mapped_weak_pool.read().is_free(0) {
+                    return None;
+                }
 
                 sub_desc.mapped_weak_pool.read().downgrade_at(self, 0);
                 current[index] = $entry::new(sub_desc.start_paddr(), $access);
+                Some(())
             }
         }
 
@@ -140,53 +241,57 @@ macro_rules! paging_cap {
                 unsafe { UniqueWriteGuard::new(self.page_object()) }
             }
         }
-    )
-}
-
-paging_cap!(PDPTCap, PDPTDescriptor, PDPT, PDPTEntry, map_pd, PDCap, PDPT_P | PDPT_RW | PDPT_US);
-paging_cap!(PDCap, PDDescriptor, PD, PDEntry, map_pt, PTCap, PD_P | PD_RW | PD_US);
-
-impl PTCap {
-    pub fn retype_from(untyped: &mut UntypedDescriptor) -> Self {
-        let mut arc: Option<Self> = None;
-
-        let start_paddr = unsafe { untyped.allocate(BASE_PAGE_LENGTH, BASE_PAGE_LENGTH) };
-
-        let mapped_weak_pool = unsafe { ManagedWeakPool1Arc::create(
-            untyped.allocate(ManagedWeakPool1Arc::inner_length(),
-                             ManagedWeakPool1Arc::inner_alignment())) };
-
-        unsafe {
-            untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
-                let mut desc = PTDescriptor {
-                    mapped_weak_pool: mapped_weak_pool,
-                    start_paddr: start_paddr,
-                    next: next_child,
-                };
 
-                for item in desc.write().iter_mut() {
-                    *item = PTEntry::empty();
+        impl Identify for $cap {
+            fn identify(&self) -> CapInfo {
+                let desc = self.read();
+                CapInfo {
+                    cap_type: $cap_type,
+                    paddr: desc.start_paddr().into(),
+                    length: desc.length(),
+                    detail: CapDetail::None,
                 }
-
-                arc = Some(
-                    Self::new(paddr, RwLock::new(desc))
-                );
-
-                arc.clone().unwrap().into()
-            });
+            }
         }
+    )
+}
 
-        arc.unwrap()
-    }
+paging_cap!(PDPTCap, PDPTDescriptor, PDPT, PDPTEntry, map_pd, PDCap, PDPT_P | PDPT_RW | PDPT_US, CapType::PDPT);
+paging_cap!(PDCap, PDDescriptor, PD, PDEntry, map_pt, PTCap, PD_P | PD_RW | PD_US, CapType::PD);
 
-    pub fn map_page<T: SetDefault + Any>(&mut self, index: usize, sub: &PageCap<T>) {
-        let mut current_desc = self.write();
-        let mut current = current_desc.write();
-        let sub_desc = sub.read();
-        assert!(!current[index].is_present());
+impl PTCap {
+    pub fn retype_from(untyped: &mut UntypedDescriptor) -> Option<Self> {
+        untyped.transaction(|untyped| {
+            let mut arc: Option<Self> = None;
+
+            let start_paddr = unsafe { untyped.allocate(BASE_PAGE_LENGTH, BASE_PAGE_LENGTH)? };
+
+            let mapped_weak_pool = unsafe { ManagedWeakPool1Arc::create(
+                untyped.allocate(ManagedWeakPool1Arc::inner_length(),
+                                 ManagedWeakPool1Arc::inner_alignment())?) };
+
+            unsafe {
+                untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
+                    let mut desc = PTDescriptor {
+                        mapped_weak_pool: mapped_weak_pool,
+                        start_paddr: start_paddr,
+                        next: next_child,
+                    };
+
+                    for item in desc.write().iter_mut() {
+                        *item = PTEntry::empty();
+                    }
+
+                    arc = Some(
+                        Self::new(paddr, RwLock::new(desc))
+                    );
+
+                    arc.clone().unwrap().into()
+                })?;
+            }
 
-        sub_desc.mapped_weak_pool.read().downgrade_at(self, 0);
-        current[index] = PTEntry::new(sub_desc.start_paddr(), PT_P | PT_RW | PT_US);
+            arc
+        })
     }
 }
 
@@ -211,3 +316,15 @@ impl PTDescriptor {
         unsafe { UniqueWriteGuard::new(self.page_object()) }
     }
 }
+
+impl Identify for PTCap {
+    fn identify(&self) -> CapInfo {
+        let desc = self.read();
+        CapInfo {
+            cap_type: CapType::PT,
+            paddr: desc.start_paddr().into(),
+            length: desc.length(),
+            detail: CapDetail::None,
+        }
+    }
+}
@@ -1,50 +1,221 @@
 use common::*;
-use arch::paging::{BASE_PAGE_LENGTH};
+use arch::paging::{BASE_PAGE_LENGTH, PTEntry, PT_RW, PT_COW, PT_SHARED, ADDRESS_MASK, pt_index, flush};
 use util::{MemoryObject, UniqueReadGuard, UniqueWriteGuard, RwLock};
-use util::managed_arc::{ManagedWeakPool1Arc};
+use util::managed_arc::{ManagedWeakPool1Arc, ManagedArcAny};
 use core::marker::{PhantomData};
-use core::any::{Any};
+use core::any::{Any, TypeId};
 use core::mem;
-use super::{PageDescriptor, PageCap, PAGE_LENGTH};
-use cap::{UntypedDescriptor, SetDefault};
+use super::{PageDescriptor, PageCap, PML4Cap, PAGE_LENGTH, page_flags};
+use cap::{UntypedDescriptor, SetDefault, Mappable, RawPage};
+use abi::{PageRights, CachePolicy};
 
 impl<T: SetDefault + Any> PageCap<T> {
-    pub fn retype_from(untyped: &mut UntypedDescriptor) -> Self {
-        unsafe { Self::bootstrap(untyped.allocate(BASE_PAGE_LENGTH, BASE_PAGE_LENGTH), untyped) }
+    pub fn retype_from(untyped: &mut UntypedDescriptor) -> Option<Self> {
+        untyped.transaction(|untyped| unsafe {
+            let start_paddr = untyped.allocate(BASE_PAGE_LENGTH, BASE_PAGE_LENGTH)?;
+            Self::bootstrap(start_paddr, untyped)
+        })
     }
 
-    pub unsafe fn bootstrap(start_paddr: PAddr, untyped: &mut UntypedDescriptor) -> Self {
+    pub unsafe fn bootstrap(start_paddr: PAddr, untyped: &mut UntypedDescriptor) -> Option<Self> {
         assert!(mem::size_of::<T>() <= PAGE_LENGTH);
 
-        let mut arc: Option<Self> = None;
+        untyped.transaction(|untyped| {
+            let mut arc: Option<Self> = None;
 
-        let mapped_weak_pool = ManagedWeakPool1Arc::create(
-            untyped.allocate(ManagedWeakPool1Arc::inner_length(),
-                             ManagedWeakPool1Arc::inner_alignment()));
+            let mapped_weak_pool = ManagedWeakPool1Arc::create(
+                untyped.allocate(ManagedWeakPool1Arc::inner_length(),
+                                 ManagedWeakPool1Arc::inner_alignment())?);
 
-        untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
-            let mut desc = PageDescriptor::<T> {
-                mapped_weak_pool: mapped_weak_pool,
-                start_paddr: start_paddr,
-                next: next_child,
-                _marker: PhantomData
-            };
+            untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
+                let mut desc = PageDescriptor::<T> {
+                    mapped_weak_pool: mapped_weak_pool,
+                    mapped_vaddr: None,
+                    start_paddr: start_paddr,
+                    copy_depth: 0,
+                    next_copy: None,
+                    revoked: false,
+                    device: false,
+                    next: next_child,
+                    _marker: PhantomData
+                };
 
-            desc.write().set_default();
+                desc.write().set_default();
 
-            arc = Some(
-                Self::new(paddr, RwLock::new(desc))
-            );
+                arc = Some(
+                    Self::new(paddr, RwLock::new(desc))
+                );
 
-            arc.clone().unwrap().into()
-        });
+                arc.clone().unwrap().into()
+            })?;
 
-        arc.unwrap()
+            arc
+        })
+    }
+
+    /// Create a page capability for the device memory at
+    /// `start_paddr`, such as the VGA buffer. Its mappings are always
+    /// shared when an address space is cloned.
+    pub unsafe fn bootstrap_device(start_paddr: PAddr, untyped: &mut UntypedDescriptor) -> Option<Self> {
+        let page = Self::bootstrap(start_paddr, untyped)?;
+        page.write().device = true;
+        Some(page)
     }
 
     pub const fn length() -> usize {
         BASE_PAGE_LENGTH
     }
+
+    /// Create a copy of the page, sharing the same physical memory
+    /// but with its own mapping. The copy's descriptor is allocated
+    /// from `untyped`. Returns `None` if `untyped` does not have
+    /// enough space left, or the page is revoked.
+    pub fn derive(&self, untyped: &mut UntypedDescriptor) -> Option<Self> {
+        let (start_paddr, copy_depth) = {
+            let desc = self.read();
+            if desc.revoked {
+                return None;
+            }
+            (desc.start_paddr, desc.copy_depth + 1)
+        };
+
+        let copy = untyped.transaction(|untyped| {
+            let mut arc: Option<Self> = None;
+
+            let mapped_weak_pool = unsafe { ManagedWeakPool1Arc::create(
+                untyped.allocate(ManagedWeakPool1Arc::inner_length(),
+                                 ManagedWeakPool1Arc::inner_alignment())?) };
+
+            unsafe {
+                untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
+                    arc = Some(
+                        Self::new(paddr, RwLock::new(PageDescriptor::<T> {
+                            mapped_weak_pool: mapped_weak_pool,
+                            mapped_vaddr: None,
+                            start_paddr: start_paddr,
+                            copy_depth: copy_depth,
+                            next_copy: None,
+                            revoked: false,
+                            device: false,
+                            next: next_child,
+                            _marker: PhantomData
+                        }))
+                    );
+
+                    arc.clone().unwrap().into()
+                })?;
+            }
+
+            arc
+        })?;
+
+        let mut desc = self.write();
+        copy.write().next_copy = desc.next_copy.take();
+        desc.next_copy = Some(copy.clone());
+        let _ = desc.mark_mapping_shared();
+        Some(copy)
+    }
+
+    /// Revoke all copies derived from the page, directly or
+    /// indirectly. Revoked copies are unmapped, removed from the
+    /// derivation list, and can no longer be mapped or derived.
+    pub fn revoke(&self) {
+        let copy_depth = self.read().copy_depth;
+        let mut next_copy = self.read().next_copy.clone();
+
+        while let Some(copy) = next_copy {
+            if copy.read().copy_depth <= copy_depth {
+                next_copy = Some(copy);
+                break;
+            }
+
+            let _ = copy.unmap();
+            let mut copy_desc = copy.write();
+            copy_desc.revoked = true;
+            next_copy = copy_desc.next_copy.take();
+        }
+
+        self.write().next_copy = next_copy;
+    }
+
+    /// Unmap the page from the address space it is mapped in, and
+    /// invalidate the TLB entry. Returns `None` if the page is not
+    /// mapped. This includes a copy-on-write page whose entry was
+    /// replaced by a private copy after a write, which is left mapped
+    /// while the page itself is no longer recorded as mapped.
+    pub fn unmap(&self) -> Option<()> {
+        let mut desc = self.write();
+        let vaddr = desc.mapped_vaddr?;
+        let pml4_cap: PML4Cap = desc.mapped_weak_pool.read().upgrade(0)?;
+        let pml4_desc = pml4_cap.write();
+        let mut pt = unsafe { UniqueWriteGuard::new(pml4_desc.pt_object(vaddr)?) };
+        let index = pt_index(vaddr);
+
+        desc.mapped_weak_pool.read().remove(0);
+        desc.mapped_vaddr = None;
+        if !pt[index].is_present() || pt[index].get_address() != desc.start_paddr() {
+            return None;
+        }
+
+        pt[index] = PTEntry::empty();
+        unsafe { flush(vaddr) };
+        pml4_desc.shootdown();
+        Some(())
+    }
+
+    /// Change the rights and cache policy of the mapped page, and
+    /// invalidate the TLB entry. A copy-on-write page stays
+    /// read-only and copy-on-write if it is remapped writable, so
+    /// that the address spaces sharing it stay isolated. Returns
+    /// `None` if the page is not mapped, or its entry was replaced by
+    /// a private copy after a write.
+    pub fn remap(&self, rights: PageRights, cache: CachePolicy) -> Option<()> {
+        let desc = self.read();
+        let vaddr = desc.mapped_vaddr?;
+        let pml4_cap: PML4Cap = desc.mapped_weak_pool.read().upgrade(0)?;
+        let pml4_desc = pml4_cap.write();
+        let mut pt = unsafe { UniqueWriteGuard::new(pml4_desc.pt_object(vaddr)?) };
+        let index = pt_index(vaddr);
+        let entry = pt[index];
+        if !entry.is_present() || entry.get_address() != desc.start_paddr() {
+            return None;
+        }
+
+        let mut flags = desc.entry_flags(rights, cache);
+        let old_flags = PTEntry::from_bits_truncate(entry.bits() & !ADDRESS_MASK);
+        if old_flags.contains(PT_COW) && flags.contains(PT_RW) {
+            flags = (flags - PT_RW) | PT_COW;
+        }
+        pt[index] = PTEntry::new(desc.start_paddr(), flags);
+        unsafe { flush(vaddr) };
+        pml4_desc.shootdown();
+        Some(())
+    }
+}
+
+impl<T: SetDefault + Any> Mappable for PageCap<T> {
+    fn map_page(&self, pml4: &mut PML4Cap, vaddr: VAddr, rights: PageRights,
+                cache: CachePolicy, untyped: &mut UntypedDescriptor) -> Option<usize> {
+        pml4.map(vaddr, self, rights, cache, untyped)?;
+        Some(0)
+    }
+
+    fn unmap_page(&self) -> Option<()> {
+        self.unmap()
+    }
+
+    fn remap_page(&self, rights: PageRights, cache: CachePolicy) -> Option<()> {
+        self.remap(rights, cache)
+    }
+
+    fn derive_page(&self, untyped: &mut UntypedDescriptor) -> Option<ManagedArcAny> {
+        self.derive(untyped).map(|copy| copy.into())
+    }
+
+    fn revoke_page(&self) -> Option<()> {
+        self.revoke();
+        Some(())
+    }
 }
 
 impl<T: SetDefault + Any> PageDescriptor<T> {
@@ -56,6 +227,53 @@ impl<T: SetDefault + Any> PageDescriptor<T> {
         BASE_PAGE_LENGTH
     }
 
+    /// Virtual address the page is mapped at, if it is mapped.
+    pub fn mapped_vaddr(&self) -> Option<VAddr> {
+        self.mapped_vaddr
+    }
+
+    /// Whether the page is a revoked copy.
+    pub fn is_revoked(&self) -> bool {
+        self.revoked
+    }
+
+    /// Whether the memory of the page may be reachable other than
+    /// through this page: it is device memory, a task buffer, or
+    /// part of a derivation list.
+    pub fn is_shared(&self) -> bool {
+        self.device || TypeId::of::<T>() != TypeId::of::<RawPage>() ||
+            self.copy_depth > 0 || self.next_copy.is_some()
+    }
+
+    /// Page table entry flags of the page with the given rights and
+    /// cache policy, marked `PT_SHARED` if the page is shared.
+    pub fn entry_flags(&self, rights: PageRights, cache: CachePolicy) -> PTEntry {
+        let flags = page_flags(rights, cache);
+        if self.is_shared() {
+            flags | PT_SHARED
+        } else {
+            flags
+        }
+    }
+
+    /// Mark the entry mapping the page `PT_SHARED`, after a copy of
+    /// it has been derived. Returns `None` if the page is not mapped,
+    /// or its entry was replaced by a private copy after a write.
+    fn mark_mapping_shared(&self) -> Option<()> {
+        let vaddr = self.mapped_vaddr?;
+        let pml4_cap: PML4Cap = self.mapped_weak_pool.read().upgrade(0)?;
+        let pml4_desc = pml4_cap.write();
+        let mut pt = unsafe { UniqueWriteGuard::new(pml4_desc.pt_object(vaddr)?) };
+
+        let entry = pt[pt_index(vaddr)];
+        if !entry.is_present() || entry.get_address() != self.start_paddr {
+            return None;
+        }
+        let flags = PTEntry::from_bits_truncate(entry.bits() & !ADDRESS_MASK);
+        pt[pt_index(vaddr)] = PTEntry::new(entry.get_address(), flags | PT_SHARED);
+        Some(())
+    }
+
     fn page_object(&self) -> MemoryObject<T> {
         unsafe { MemoryObject::new(self.start_paddr) }
     }
@@ -1,150 +1,534 @@
 use common::*;
 use arch::{KERNEL_BASE};
 use arch::init::{KERNEL_PDPT};
-use arch::paging::{BASE_PAGE_LENGTH, PML4, PML4Entry, pml4_index};
+use arch::paging::{BASE_PAGE_LENGTH, LARGE_PAGE_LENGTH, HUGE_PAGE_LENGTH,
+                   PML4, PML4Entry, PDPT, PDPTEntry, PD, PDEntry, PT, PTEntry,
+                   PML4_P, PML4_RW, PML4_US, PDPT_P, PDPT_RW, PDPT_US, PD_P, PD_RW, PD_US,
+                   PT_RW, PT_COW, PT_SHARED,
+                   pml4_index, pdpt_index, pd_index, pt_index, ADDRESS_MASK, flush, flush_all,
+                   has_huge_pages};
+use arch::cpu;
 use util::{MemoryObject, UniqueReadGuard, UniqueWriteGuard, RwLock};
-use super::{PML4Descriptor, PML4Cap, PDPTCap, PDCap, PTCap, PageCap};
-use cap::{self, UntypedDescriptor, CPoolDescriptor, SetDefault};
+use util::managed_arc::{ManagedWeakPool1Arc};
+use super::{PML4Descriptor, PML4Cap, PDPTCap, PDCap, PTCap, PageCap, LargePageCap, HugePageCap,
+            PendingCopy, large_page_flags, huge_page_flags, CHUNK_LENGTH};
+use cap::{UntypedDescriptor, UntypedCap, SetDefault, Identify, RawPage, RawPageCap};
+use abi::{CapType, CapInfo, CapDetail, PageRights, CachePolicy, CloneMode};
 use core::any::Any;
+use core::ops::DerefMut;
 
 impl PML4Cap {
-    pub fn retype_from(untyped: &mut UntypedDescriptor) -> Self {
-        let mut arc: Option<Self> = None;
-
-        let start_paddr = unsafe { untyped.allocate(BASE_PAGE_LENGTH, BASE_PAGE_LENGTH) };
-
-        unsafe {
-            use arch::paging::{PML4_P, PML4_RW};
-
-            untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
-                let mut desc = PML4Descriptor {
-                    start_paddr: start_paddr,
-                    next: next_child,
-                };
-
-                for item in desc.write().iter_mut() {
-                    *item = PML4Entry::empty();
-                }
+    pub fn retype_from(untyped: &mut UntypedDescriptor) -> Option<Self> {
+        untyped.transaction(|untyped| {
+            let mut arc: Option<Self> = None;
+
+            let start_paddr = unsafe { untyped.allocate(BASE_PAGE_LENGTH, BASE_PAGE_LENGTH)? };
+
+            let untyped_weak_pool = unsafe { ManagedWeakPool1Arc::create(
+                untyped.allocate(ManagedWeakPool1Arc::inner_length(),
+                                 ManagedWeakPool1Arc::inner_alignment())?) };
+
+            unsafe {
+                untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
+                    let mut desc = PML4Descriptor {
+                        untyped_weak_pool: untyped_weak_pool,
+                        pending_copy: None,
+                        start_paddr: start_paddr,
+                        next: next_child,
+                    };
+
+                    for item in desc.write().iter_mut() {
+                        *item = PML4Entry::empty();
+                    }
 
-                desc.write()[pml4_index(VAddr::from(KERNEL_BASE))] =
-                    PML4Entry::new(KERNEL_PDPT.paddr(), PML4_P | PML4_RW);
+                    desc.write()[pml4_index(VAddr::from(KERNEL_BASE))] =
+                        PML4Entry::new(KERNEL_PDPT.paddr(), PML4_P | PML4_RW);
 
-                arc = Some(
-                    Self::new(paddr, RwLock::new(desc))
-                );
+                    arc = Some(
+                        Self::new(paddr, RwLock::new(desc))
+                    );
 
-                arc.clone().unwrap().into()
-            });
-        }
+                    arc.clone().unwrap().into()
+                })?;
+            }
 
-        arc.unwrap()
+            arc
+        })
     }
 
-    pub fn map_pdpt(&mut self, index: usize, sub: &PDPTCap) {
-        use arch::paging::{pml4_index, PML4_P, PML4_RW, PML4_US};
-
+    /// Map a PDPT at `index`. Returns `None` if the entry is already
+    /// present, `index` is the kernel entry, or the PDPT is already
+    /// mapped.
+    pub fn map_pdpt(&mut self, index: usize, sub: &PDPTCap) -> Option<()> {
         let mut current_desc = self.write();
         let mut current = current_desc.write();
         let sub_desc = sub.read();
-        assert!(!(pml4_index(VAddr::from(KERNEL_BASE)) == index));
-        assert!(!current[index].is_present());
+        if pml4_index(VAddr::from(KERNEL_BASE)) == index || current[index].is_present() ||
+            !sub_desc.mapped_weak_pool.read().is_free(0) {
+            return None;
+        }
 
         sub_desc.mapped_weak_pool.read().downgrade_at(self, 0);
         current[index] = PML4Entry::new(sub_desc.start_paddr(), PML4_P | PML4_RW | PML4_US);
+        Some(())
+    }
+
+    /// Physical address of the PDPT covering `vaddr`. If it is
+    /// missing, a new PDPT is retyped from `untyped`.
+    fn ensure_pdpt(&mut self, vaddr: VAddr, untyped: &mut UntypedDescriptor) -> Option<PAddr> {
+        let index = pml4_index(vaddr);
+        if index == pml4_index(VAddr::from(KERNEL_BASE)) {
+            return None;
+        }
+
+        if !{ self.read().read()[index] }.is_present() {
+            let pdpt_cap = PDPTCap::retype_from(untyped)?;
+            self.map_pdpt(index, &pdpt_cap)?;
+        }
+
+        Some({ self.read().read()[index] }.get_address())
     }
 
+    /// Map a page at `vaddr` with the given rights and cache
+    /// policy. The page records this PML4 and `vaddr` as its
+    /// mapping. Missing intermediate page tables are retyped from
+    /// `untyped`. They are not put into any capability pool, and are
+    /// only owned by `untyped`. As the parent of a PD or PT created
+    /// this way may have no capability either, every created table
+    /// records this PML4 as its mapping, like the created PDPT does.
+    /// Returns `None` if `untyped` does not
+    /// have enough space left for them, if the page or `vaddr` is
+    /// already mapped, or if the page is revoked.
     pub fn map<T: SetDefault + Any>(&mut self, vaddr: VAddr, page: &PageCap<T>,
-                                    untyped: &mut UntypedDescriptor, cpool: &mut CPoolDescriptor) {
-        use arch::paging::{pml4_index, pdpt_index, pd_index, pt_index};
+                                    rights: PageRights, cache: CachePolicy,
+                                    untyped: &mut UntypedDescriptor) -> Option<()> {
+        debug!("PML4 mapping: 0x{:x}", vaddr);
+
+        let pdpt_paddr = self.ensure_pdpt(vaddr, untyped)?;
+        let pd_paddr = ensure_pd(self, pdpt_paddr, vaddr, untyped)?;
+        let pt_paddr = ensure_pt(self, pd_paddr, vaddr, untyped)?;
+
+        let mut pt = unsafe { UniqueWriteGuard::<PT>::new(MemoryObject::new(pt_paddr)) };
+        let index = pt_index(vaddr);
+        let mut page_desc = page.write();
+        if pt[index].is_present() || page_desc.mapped_vaddr.is_some() || page_desc.revoked {
+            return None;
+        }
+
+        page_desc.mapped_weak_pool.read().downgrade_at(self, 0);
+        page_desc.mapped_vaddr = Some(vaddr);
+        pt[index] = PTEntry::new(page_desc.start_paddr(), page_desc.entry_flags(rights, cache));
+        Some(())
+    }
+
+    /// Map a large page at `vaddr`, which must be aligned to
+    /// `LARGE_PAGE_LENGTH`, directly in a PD. The page must be
+    /// zeroed. Otherwise the same as `map`.
+    pub fn map_large(&mut self, vaddr: VAddr, page: &LargePageCap,
+                     rights: PageRights, cache: CachePolicy,
+                     untyped: &mut UntypedDescriptor) -> Option<()> {
+        debug!("PML4 large mapping: 0x{:x}", vaddr);
+
+        if vaddr.into(): usize % LARGE_PAGE_LENGTH != 0 {
+            return None;
+        }
+
+        let pdpt_paddr = self.ensure_pdpt(vaddr, untyped)?;
+        let pd_paddr = ensure_pd(self, pdpt_paddr, vaddr, untyped)?;
+
+        let mut pd = unsafe { UniqueWriteGuard::<PD>::new(MemoryObject::new(pd_paddr)) };
+        let index = pd_index(vaddr);
+        let mut page_desc = page.write();
+        if pd[index].is_present() || page_desc.mapped_vaddr.is_some() || !page_desc.is_zeroed() {
+            return None;
+        }
+
+        page_desc.mapped_weak_pool.read().downgrade_at(self, 0);
+        page_desc.mapped_vaddr = Some(vaddr);
+        pd[index] = PDEntry::new(page_desc.start_paddr(), large_page_flags(rights, cache));
+        Some(())
+    }
+
+    /// Map a huge page at `vaddr`, which must be aligned to
+    /// `HUGE_PAGE_LENGTH`, directly in a PDPT. The page must be
+    /// zeroed. Returns `None` if the CPU does not support huge pages.
+    /// Otherwise the same as `map`.
+    pub fn map_huge(&mut self, vaddr: VAddr, page: &HugePageCap,
+                    rights: PageRights, cache: CachePolicy,
+                    untyped: &mut UntypedDescriptor) -> Option<()> {
+        debug!("PML4 huge mapping: 0x{:x}", vaddr);
+
+        if !has_huge_pages() || vaddr.into(): usize % HUGE_PAGE_LENGTH != 0 {
+            return None;
+        }
+
+        let pdpt_paddr = self.ensure_pdpt(vaddr, untyped)?;
 
-        log!("PML4 mapping: 0x{:x}", vaddr);
+        let mut pdpt = unsafe { UniqueWriteGuard::<PDPT>::new(MemoryObject::new(pdpt_paddr)) };
+        let index = pdpt_index(vaddr);
+        let mut page_desc = page.write();
+        if pdpt[index].is_present() || page_desc.mapped_vaddr.is_some() || !page_desc.is_zeroed() {
+            return None;
+        }
+
+        page_desc.mapped_weak_pool.read().downgrade_at(self, 0);
+        page_desc.mapped_vaddr = Some(vaddr);
+        pdpt[index] = PDPTEntry::new(page_desc.start_paddr(), huge_page_flags(rights, cache));
+        Some(())
+    }
 
-        let mut pdpt_cap: PDPTCap = {
-            let index = pml4_index(vaddr);
+    /// Clone the user part of this address space into `target`,
+    /// creating its page tables and frames from `untyped`. Depending
+    /// on `mode`, 4 KiB pages are copied into new frames, shared, or
+    /// shared read-only and marked copy-on-write in both address
+    /// spaces. Task buffers, device memory and pages with derived
+    /// copies are always shared. Large and huge pages are shared with
+    /// `CloneMode::Share`, and copied otherwise.
+    ///
+    /// Huge pages are copied `CHUNK_LENGTH` per call. Returns the
+    /// length left to copy of the huge page being copied, after which
+    /// the clone must be called again with the same arguments to
+    /// carry on. Entries already present in `target` are kept, so the
+    /// clone is complete once this returns 0.
+    ///
+    /// Like the page tables created by `map`, the new tables and
+    /// frames are only owned by `untyped`. They record `target`, and
+    /// frames also their virtual address, as their mapping, so that
+    /// copied frames can be unmapped. Shared frames are still owned
+    /// by the pages of this address space, which must be kept alive
+    /// while the clone uses them. Returns `None` if `target` is this
+    /// address space, or if `untyped` runs out of space, in which
+    /// case the clone may be incomplete.
+    ///
+    /// The untyped capability is locked first, then both PML4s for
+    /// writing, in the order of their addresses, as the entries of
+    /// this address space are marked copy-on-write during the walk.
+    pub fn clone_vspace(&self, target: &PML4Cap, untyped: &UntypedCap, mode: CloneMode) -> Option<usize> {
+        if self.paddr() == target.paddr() {
+            return None;
+        }
 
-            if !{ self.read().read()[index] }.is_present() {
-                let pdpt_cap = PDPTCap::retype_from(untyped);
-                self.map_pdpt(index, &pdpt_cap);
-                cpool.downgrade_free(&pdpt_cap);
+        if mode == CloneMode::CopyOnWrite {
+            for pml4 in [self, target].iter() {
+                let desc = pml4.read();
+                if desc.untyped_weak_pool.read().is_free(0) {
+                    desc.untyped_weak_pool.read().downgrade_at(untyped, 0);
+                }
             }
+        }
 
-            let position = (0..cpool.size()).position(|i| {
-                let any = cpool.upgrade_any(i);
-                if let Some(any) = any {
-                    if any.is::<PDPTCap>() {
-                        let cap: PDPTCap = any.into();
-                        let cap_desc = cap.read();
-                        cap_desc.start_paddr() == { self.read().read()[index] }.get_address()
-                    } else {
-                        cap::drop_any(any);
-                        false
+        let remaining = {
+            let mut untyped_desc = untyped.write();
+            let (source_desc, mut target_desc) = if self.paddr() < target.paddr() {
+                let source_desc = self.write();
+                (source_desc, target.write())
+            } else {
+                let target_desc = target.write();
+                (self.write(), target_desc)
+            };
+            let mut pending_copy = target_desc.pending_copy.take();
+            let remaining = {
+                let source_table = source_desc.read();
+                let mut target_table = target_desc.write();
+                let mut remaining = 0;
+
+                for index in 0..source_table.len() {
+                    if index == pml4_index(VAddr::from(KERNEL_BASE)) || !source_table[index].is_present() {
+                        continue;
+                    }
+
+                    if !target_table[index].is_present() {
+                        let pdpt_cap = PDPTCap::retype_from(untyped_desc.deref_mut())?;
+                        pdpt_cap.read().mapped_weak_pool.read().downgrade_at(target, 0);
+                        target_table[index] = PML4Entry::new(pdpt_cap.read().start_paddr(),
+                                                             PML4_P | PML4_RW | PML4_US);
+                    }
+                    remaining = clone_pdpt(target, VAddr::from(index << 39), source_table[index].get_address(),
+                                           target_table[index].get_address(), untyped_desc.deref_mut(), mode,
+                                           &mut pending_copy)?;
+                    if remaining != 0 {
+                        break;
                     }
-                } else {
-                    false
                 }
-            }).unwrap();
 
-            cpool.upgrade(position)
-        }.unwrap();
+                remaining
+            };
+
+            target_desc.pending_copy = pending_copy;
+            remaining
+        };
+
+        if mode == CloneMode::CopyOnWrite {
+            unsafe { flush_all() };
+            self.read().shootdown();
+        }
+
+        Some(remaining)
+    }
+
+    /// Resolve a write fault at `vaddr` on a copy-on-write page, by
+    /// copying the page into a new frame retyped from the untyped
+    /// capability recorded for this address space. The page stays
+    /// copy-on-write in the other address spaces sharing it, so each
+    /// of them gets its own copy on its first write. Returns `None`
+    /// if the page is not copy-on-write or the copy cannot be made.
+    /// A page that is already writable is considered resolved, as the
+    /// fault may have raced with the resolution on another CPU.
+    ///
+    /// Like `map` and `clone_vspace`, this locks the untyped
+    /// capability before the PML4. The entry is checked and replaced
+    /// under the PML4 write lock, so that concurrent faults on the
+    /// same page make a single copy.
+    pub fn resolve_copy_on_write(&self, vaddr: VAddr) -> Option<()> {
+        let untyped: UntypedCap = self.read().untyped_weak_pool.read().upgrade(0)?;
+        let mut untyped_desc = untyped.write();
+        let desc = self.write();
+        let mut pt = unsafe { UniqueWriteGuard::new(desc.pt_object(vaddr)?) };
+        let index = pt_index(vaddr);
+        let entry = pt[index];
+        let flags = PTEntry::from_bits_truncate(entry.bits() & !ADDRESS_MASK);
+        if entry.is_present() && flags.contains(PT_RW) {
+            unsafe { flush(vaddr) };
+            return Some(());
+        }
+        if !entry.is_present() || !flags.contains(PT_COW) {
+            return None;
+        }
+
+        let page_cap = RawPageCap::retype_from(untyped_desc.deref_mut())?;
+        let paddr = page_cap.read().start_paddr();
+        copy_frame(entry.get_address(), paddr, BASE_PAGE_LENGTH);
 
-        let mut pd_cap: PDCap = {
-            let index = pdpt_index(vaddr);
+        pt[index] = PTEntry::new(paddr, (flags - PT_COW) | PT_RW);
+        unsafe { flush(vaddr) };
+        desc.shootdown();
+        Some(())
+    }
+}
 
-            if !{ pdpt_cap.read().read()[index] }.is_present() {
-                let pd_cap = PDCap::retype_from(untyped);
-                pdpt_cap.map_pd(index, &pd_cap);
-                cpool.downgrade_free(&pd_cap);
+
+/// Copy `length` bytes of physical memory from `from` to `to`, one
+/// base page at a time.
+fn copy_frame(from: PAddr, to: PAddr, length: usize) {
+    for i in 0..(length / BASE_PAGE_LENGTH) {
+        let source = unsafe { UniqueReadGuard::<RawPage>::new(MemoryObject::new(from + i * BASE_PAGE_LENGTH)) };
+        let mut target = unsafe { UniqueWriteGuard::<RawPage>::new(MemoryObject::new(to + i * BASE_PAGE_LENGTH)) };
+        target.0.copy_from_slice(&source.0);
+    }
+}
+
+/// Clone the PDPT at `source`, which maps the user memory starting at
+/// `base`, into the PDPT at `target`, in the address space of `pml4`.
+/// Entries already present in `target` are kept. A huge page being
+/// copied is kept in `pending_copy` until the whole page is copied,
+/// and the length left to copy is returned, as by `clone_vspace`.
+fn clone_pdpt(pml4: &PML4Cap, base: VAddr, source: PAddr, target: PAddr,
+              untyped: &mut UntypedDescriptor, mode: CloneMode,
+              pending_copy: &mut Option<PendingCopy>) -> Option<usize> {
+    let source = unsafe { UniqueReadGuard::<PDPT>::new(MemoryObject::new(source)) };
+    let mut target = unsafe { UniqueWriteGuard::<PDPT>::new(MemoryObject::new(target)) };
+
+    for index in 0..source.len() {
+        let entry = source[index];
+        if !entry.is_present() || target[index].is_page() {
+            continue;
+        }
+
+        let vaddr = base + index * HUGE_PAGE_LENGTH;
+        if entry.is_page() {
+            if target[index].is_present() {
+                continue;
+            }
+            if mode == CloneMode::Share {
+                target[index] = entry;
+                continue;
             }
 
-            let position = (0..cpool.size()).position(|i| {
-                let any = cpool.upgrade_any(i);
-                if let Some(any) = any {
-                    if any.is::<PDCap>() {
-                        let cap: PDCap = any.into();
-                        let cap_desc = cap.read();
-                        cap_desc.start_paddr() == { pdpt_cap.read().read()[index] }.get_address()
-                    } else {
-                        cap::drop_any(any);
-                        false
-                    }
-                } else {
-                    false
-                }
-            }).unwrap();
+            let resumed = match *pending_copy {
+                Some(ref copy) => copy.vaddr == vaddr && copy.source_paddr == entry.get_address(),
+                None => false,
+            };
+            if !resumed {
+                *pending_copy = Some(PendingCopy {
+                    page: HugePageCap::retype_from(untyped)?,
+                    source_paddr: entry.get_address(),
+                    vaddr: vaddr,
+                    copied_length: 0,
+                });
+            }
+
+            let copied_length = match *pending_copy {
+                Some(ref mut copy) => {
+                    let paddr = copy.page.read().start_paddr();
+                    copy_frame(copy.source_paddr + copy.copied_length, paddr + copy.copied_length,
+                               CHUNK_LENGTH);
+                    copy.copied_length += CHUNK_LENGTH;
+                    copy.copied_length
+                },
+                None => unreachable!(),
+            };
+            if copied_length < HUGE_PAGE_LENGTH {
+                return Some(HUGE_PAGE_LENGTH - copied_length);
+            }
 
-            cpool.upgrade(position)
-        }.unwrap();
+            let copy = pending_copy.take()?;
+            let mut page_desc = copy.page.write();
+            page_desc.zeroed_length = HUGE_PAGE_LENGTH;
+            page_desc.mapped_weak_pool.read().downgrade_at(pml4, 0);
+            page_desc.mapped_vaddr = Some(vaddr);
+            let flags = PDPTEntry::from_bits_truncate(entry.bits() & !ADDRESS_MASK);
+            target[index] = PDPTEntry::new(page_desc.start_paddr(), flags);
+        } else {
+            if !target[index].is_present() {
+                let pd_cap = PDCap::retype_from(untyped)?;
+                pd_cap.read().mapped_weak_pool.read().downgrade_at(pml4, 0);
+                target[index] = PDPTEntry::new(pd_cap.read().start_paddr(), PDPT_P | PDPT_RW | PDPT_US);
+            }
+            clone_pd(pml4, vaddr, entry.get_address(), target[index].get_address(), untyped, mode)?;
+        }
+    }
 
-        let mut pt_cap: PTCap = {
-            let index = pd_index(vaddr);
+    Some(0)
+}
 
-            if !{ pd_cap.read().read()[index] }.is_present() {
-                let pt_cap = PTCap::retype_from(untyped);
-                pd_cap.map_pt(index, &pt_cap);
-                cpool.downgrade_free(&pt_cap);
+/// Clone the PD at `source`, which maps the user memory starting at
+/// `base`, into the PD at `target`, in the address space of `pml4`.
+/// Entries already present in `target` are kept.
+fn clone_pd(pml4: &PML4Cap, base: VAddr, source: PAddr, target: PAddr,
+            untyped: &mut UntypedDescriptor, mode: CloneMode) -> Option<()> {
+    let source = unsafe { UniqueReadGuard::<PD>::new(MemoryObject::new(source)) };
+    let mut target = unsafe { UniqueWriteGuard::<PD>::new(MemoryObject::new(target)) };
+
+    for index in 0..source.len() {
+        let entry = source[index];
+        if !entry.is_present() || target[index].is_page() {
+            continue;
+        }
+
+        let vaddr = base + index * LARGE_PAGE_LENGTH;
+        if entry.is_page() {
+            if target[index].is_present() {
+                continue;
+            }
+            let flags = PDEntry::from_bits_truncate(entry.bits() & !ADDRESS_MASK);
+            target[index] = if mode == CloneMode::Share {
+                entry
+            } else {
+                let page_cap = LargePageCap::retype_from(untyped)?;
+                let paddr = page_cap.read().start_paddr();
+                copy_frame(entry.get_address(), paddr, LARGE_PAGE_LENGTH);
+                {
+                    let mut page_desc = page_cap.write();
+                    page_desc.zeroed_length = LARGE_PAGE_LENGTH;
+                    page_desc.mapped_weak_pool.read().downgrade_at(pml4, 0);
+                    page_desc.mapped_vaddr = Some(vaddr);
+                }
+                PDEntry::new(paddr, flags)
+            };
+        } else {
+            if !target[index].is_present() {
+                let pt_cap = PTCap::retype_from(untyped)?;
+                pt_cap.read().mapped_weak_pool.read().downgrade_at(pml4, 0);
+                target[index] = PDEntry::new(pt_cap.read().start_paddr(), PD_P | PD_RW | PD_US);
             }
+            clone_pt(pml4, vaddr, entry.get_address(), target[index].get_address(), untyped, mode)?;
+        }
+    }
 
-            let position = (0..cpool.size()).position(|i| {
-                let any = cpool.upgrade_any(i);
-                if let Some(any) = any {
-                    if any.is::<PTCap>() {
-                        let cap: PTCap = any.into();
-                        let cap_desc = cap.read();
-                        cap_desc.start_paddr() == { pd_cap.read().read()[index] }.get_address()
-                    } else {
-                        cap::drop_any(any);
-                        false
-                    }
+    Some(())
+}
+
+/// Clone the PT at `source`, which maps the user memory starting at
+/// `base`, into the PT at `target`, in the address space of `pml4`.
+/// Entries already present in `target` are kept. With `CloneMode::CopyOnWrite`, writable entries in
+/// `source` are made read-only and marked copy-on-write as well.
+///
+/// Entries marked `PT_SHARED` map memory that is not owned by a
+/// single raw page, such as task buffers, device memory and pages
+/// with derived copies. They are always shared, as copying them
+/// would detach the clone from the other users of the memory.
+fn clone_pt(pml4: &PML4Cap, base: VAddr, source: PAddr, target: PAddr,
+            untyped: &mut UntypedDescriptor, mode: CloneMode) -> Option<()> {
+    let mut source = unsafe { UniqueWriteGuard::<PT>::new(MemoryObject::new(source)) };
+    let mut target = unsafe { UniqueWriteGuard::<PT>::new(MemoryObject::new(target)) };
+
+    for index in 0..source.len() {
+        let entry = source[index];
+        if !entry.is_present() || target[index].is_present() {
+            continue;
+        }
+
+        let flags = PTEntry::from_bits_truncate(entry.bits() & !ADDRESS_MASK);
+        if flags.contains(PT_SHARED) {
+            target[index] = entry;
+            continue;
+        }
+
+        target[index] = match mode {
+            CloneMode::Share => entry,
+            CloneMode::Copy => {
+                let page_cap = RawPageCap::retype_from(untyped)?;
+                let paddr = page_cap.read().start_paddr();
+                copy_frame(entry.get_address(), paddr, BASE_PAGE_LENGTH);
+                {
+                    let mut page_desc = page_cap.write();
+                    page_desc.mapped_weak_pool.read().downgrade_at(pml4, 0);
+                    page_desc.mapped_vaddr = Some(base + index * BASE_PAGE_LENGTH);
+                }
+                if flags.contains(PT_COW) {
+                    PTEntry::new(paddr, (flags - PT_COW) | PT_RW)
                 } else {
-                    false
+                    PTEntry::new(paddr, flags)
+                }
+            },
+            CloneMode::CopyOnWrite => {
+                if flags.contains(PT_RW) {
+                    source[index] = PTEntry::new(entry.get_address(), (flags - PT_RW) | PT_COW);
                 }
-            }).unwrap();
+                source[index]
+            },
+        };
+    }
 
-            cpool.upgrade(position)
-        }.unwrap();
+    Some(())
+}
 
-        pt_cap.map_page(pt_index(vaddr), page);
+/// Physical address of the PD covering `vaddr` in the PDPT at
+/// `pdpt_paddr`. If it is missing, a new PD is retyped from
+/// `untyped`, recording `pml4` as its mapping. Returns `None` if the
+/// entry maps a huge page.
+fn ensure_pd(pml4: &PML4Cap, pdpt_paddr: PAddr, vaddr: VAddr, untyped: &mut UntypedDescriptor) -> Option<PAddr> {
+    let index = pdpt_index(vaddr);
+    let mut pdpt = unsafe { UniqueWriteGuard::<PDPT>::new(MemoryObject::new(pdpt_paddr)) };
+
+    if !pdpt[index].is_present() {
+        let pd_cap = PDCap::retype_from(untyped)?;
+        pd_cap.read().mapped_weak_pool.read().downgrade_at(pml4, 0);
+        pdpt[index] = PDPTEntry::new(pd_cap.read().start_paddr(), PDPT_P | PDPT_RW | PDPT_US);
+    } else if pdpt[index].is_page() {
+        return None;
     }
+
+    Some(pdpt[index].get_address())
+}
+
+/// Physical address of the PT covering `vaddr` in the PD at
+/// `pd_paddr`. If it is missing, a new PT is retyped from
+/// `untyped`, recording `pml4` as its mapping. Returns `None` if the
+/// entry maps a large page.
+fn ensure_pt(pml4: &PML4Cap, pd_paddr: PAddr, vaddr: VAddr, untyped: &mut UntypedDescriptor) -> Option<PAddr> {
+    let index = pd_index(vaddr);
+    let mut pd = unsafe { UniqueWriteGuard::<PD>::new(MemoryObject::new(pd_paddr)) };
+
+    if !pd[index].is_present() {
+        let pt_cap = PTCap::retype_from(untyped)?;
+        pt_cap.read().mapped_weak_pool.read().downgrade_at(pml4, 0);
+        pd[index] = PDEntry::new(pt_cap.read().start_paddr(), PD_P | PD_RW | PD_US);
+    } else if pd[index].is_page() {
+        return None;
+    }
+
+    Some(pd[index].get_address())
 }
 
 impl PML4Descriptor {
@@ -168,9 +552,96 @@ impl PML4Descriptor {
         unsafe { UniqueWriteGuard::new(self.page_object()) }
     }
 
+    /// The PDPT covering `vaddr`, found by walking the page tables
+    /// through their physical addresses. Returns `None` if it is
+    /// missing.
+    pub fn pdpt_object(&self, vaddr: VAddr) -> Option<MemoryObject<PDPT>> {
+        let pml4_entry = self.read()[pml4_index(vaddr)];
+        if !pml4_entry.is_present() {
+            return None;
+        }
+
+        Some(unsafe { MemoryObject::new(pml4_entry.get_address()) })
+    }
+
+    /// The PD covering `vaddr`. Returns `None` if it is missing, or
+    /// `vaddr` is in a huge page.
+    pub fn pd_object(&self, vaddr: VAddr) -> Option<MemoryObject<PD>> {
+        let pdpt_entry = unsafe { UniqueReadGuard::new(self.pdpt_object(vaddr)?) }[pdpt_index(vaddr)];
+        if !pdpt_entry.is_present() || pdpt_entry.is_page() {
+            return None;
+        }
+
+        Some(unsafe { MemoryObject::new(pdpt_entry.get_address()) })
+    }
+
+    /// The PT covering `vaddr`. Returns `None` if it is missing, or
+    /// `vaddr` is in a large or huge page.
+    pub fn pt_object(&self, vaddr: VAddr) -> Option<MemoryObject<PT>> {
+        let pd_entry = unsafe { UniqueReadGuard::new(self.pd_object(vaddr)?) }[pd_index(vaddr)];
+        if !pd_entry.is_present() || pd_entry.is_page() {
+            return None;
+        }
+
+        Some(unsafe { MemoryObject::new(pd_entry.get_address()) })
+    }
+
+    /// Translate `vaddr` by walking the page tables. Returns the
+    /// physical address, the flags of the entry mapping it, and the
+    /// level of that entry (1 for PT, 2 for PD and 3 for PDPT), or
+    /// `None` if `vaddr` is not mapped.
+    pub fn translate(&self, vaddr: VAddr) -> Option<(PAddr, u64, usize)> {
+        let offset = |length: usize| vaddr.into(): usize & (length - 1);
+
+        let pdpt_entry = unsafe { UniqueReadGuard::new(self.pdpt_object(vaddr)?) }[pdpt_index(vaddr)];
+        if !pdpt_entry.is_present() {
+            return None;
+        }
+        if pdpt_entry.is_page() {
+            return Some((pdpt_entry.get_address() + offset(HUGE_PAGE_LENGTH),
+                         pdpt_entry.bits() & !ADDRESS_MASK, 3));
+        }
+
+        let pd_entry = unsafe { UniqueReadGuard::new(self.pd_object(vaddr)?) }[pd_index(vaddr)];
+        if !pd_entry.is_present() {
+            return None;
+        }
+        if pd_entry.is_page() {
+            return Some((pd_entry.get_address() + offset(LARGE_PAGE_LENGTH),
+                         pd_entry.bits() & !ADDRESS_MASK, 2));
+        }
+
+        let pt_entry = unsafe { UniqueReadGuard::new(self.pt_object(vaddr)?) }[pt_index(vaddr)];
+        if !pt_entry.is_present() {
+            return None;
+        }
+
+        Some((pt_entry.get_address() + offset(BASE_PAGE_LENGTH),
+              pt_entry.bits() & !ADDRESS_MASK, 1))
+    }
+
     pub fn switch_to(&mut self) {
         use arch::paging;
 
         unsafe { paging::switch_to(self.start_paddr); }
     }
+
+    /// Make other CPUs drop their TLB entries of this address space,
+    /// after its mappings changed. The current CPU must already have
+    /// flushed its own entries.
+    pub fn shootdown(&self) {
+        cpu::tlb_shootdown(self.start_paddr);
+    }
+}
+
+impl Identify for PML4Cap {
+    fn identify(&self) -> CapInfo {
+        let desc = self.read();
+        CapInfo {
+            cap_type: CapType::PML4,
+            paddr: desc.start_paddr().into(),
+            length: desc.length(),
+            detail: CapDetail::None,
+        }
+    }
 }
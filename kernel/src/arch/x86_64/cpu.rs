@@ -0,0 +1,383 @@
+use core::mem::size_of;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering, spin_loop_hint};
+use common::{PAddr, VAddr};
+use arch::segmentation::{SegmentDescriptor, SegmentSelector, TaskStateSegment};
+use arch::interrupt::{Registers, ExceptionStackFrame, InterruptVector, LOCAL_APIC,
+                      WAKEUP_INTERRUPT_CODE, TLB_SHOOTDOWN_INTERRUPT_CODE};
+use arch::paging::{cr3, flush_all, BASE_PAGE_LENGTH};
+use arch::wrmsr;
+
+/// Maximum number of CPUs the kernel runs on. Application processors
+/// beyond this number are left halted. Must match `AP_MAX_CPUS` in
+/// `start.S`.
+pub const MAX_CPUS: usize = 8;
+
+/// Length of the kernel stack of a task, used when it enters the
+/// kernel from user-mode.
+pub const KERNEL_STACK_LENGTH: usize = 4 * 4096;
+/// Alignment of the kernel stack of a task.
+pub const KERNEL_STACK_ALIGNMENT: usize = 4096;
+
+/// Length of the per-CPU idle and interrupt stacks.
+const CPU_STACK_LENGTH: usize = 4096;
+
+/// Interrupt stack table index of the stack non-maskable interrupts
+/// run on.
+pub const NMI_IST_INDEX: u16 = 2;
+/// Interrupt stack table index of the stack double faults run on.
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 3;
+
+/// `IA32_FS_BASE` MSR, holding the active `fs` base.
+const IA32_FS_BASE: u32 = 0xC0000100;
+/// `IA32_GS_BASE` MSR, holding the active `gs` base.
+const IA32_GS_BASE: u32 = 0xC0000101;
+/// `IA32_KERNEL_GS_BASE` MSR, holding the `gs` base swapped in by
+/// `swapgs`.
+const IA32_KERNEL_GS_BASE: u32 = 0xC0000102;
+
+// Offsets of `CpuLocal` fields, used by the context switching code to
+// address them through `gs`. Checked in `init`.
+
+/// Offset of `CpuLocal::rsp_after_saving_registers`.
+pub const CPU_RSP_AFTER_SAVING_REGISTERS: usize = 16;
+/// Offset of `CpuLocal::sysret_stack_pointer`.
+pub const CPU_SYSRET_STACK_POINTER: usize = 24;
+/// Offset of `CpuLocal::registers`. The registers follow in the
+/// order of `Registers`, each 8 bytes long.
+pub const CPU_REGISTERS: usize = 32;
+pub const CPU_RAX: usize = CPU_REGISTERS;
+pub const CPU_RBX: usize = CPU_REGISTERS + 8;
+pub const CPU_RCX: usize = CPU_REGISTERS + 16;
+pub const CPU_RDX: usize = CPU_REGISTERS + 24;
+pub const CPU_RSI: usize = CPU_REGISTERS + 32;
+pub const CPU_RDI: usize = CPU_REGISTERS + 40;
+pub const CPU_RBP: usize = CPU_REGISTERS + 48;
+pub const CPU_R8: usize = CPU_REGISTERS + 56;
+pub const CPU_R9: usize = CPU_REGISTERS + 64;
+pub const CPU_R10: usize = CPU_REGISTERS + 72;
+pub const CPU_R11: usize = CPU_REGISTERS + 80;
+pub const CPU_R12: usize = CPU_REGISTERS + 88;
+pub const CPU_R13: usize = CPU_REGISTERS + 96;
+pub const CPU_R14: usize = CPU_REGISTERS + 104;
+pub const CPU_R15: usize = CPU_REGISTERS + 112;
+
+/// Per-CPU data. The kernel `gs` base of a CPU points to its
+/// `CpuLocal`. In user-mode the base is swapped out with `swapgs`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct CpuLocal {
+    /// Address of this struct, so that a reference can be made from
+    /// `gs`.
+    this: u64,
+    /// Index of the CPU. The bootstrap processor is 0.
+    index: u64,
+    /// Kernel stack pointer saved by the switch functions, restored
+    /// when the task returns to the kernel.
+    pub rsp_after_saving_registers: u64,
+    /// User stack pointer to switch to right before `sysret`.
+    pub sysret_stack_pointer: u64,
+    /// Registers of the task running on the CPU.
+    pub registers: Registers,
+    pub exception_stack_frame: Option<ExceptionStackFrame>,
+    pub exception_error_code: Option<u64>,
+    pub exception_code: Option<u64>,
+    /// Local APIC id of the CPU, used as the destination of
+    /// inter-processor interrupts.
+    apic_id: u64,
+    /// Page table the CPU runs user code with. Only valid while the
+    /// CPU is in `USER_CPUS`.
+    user_page_table: u64,
+    /// Global descriptor table of the CPU. It is a copy of the boot
+    /// `GDT`, with the task state segment of the CPU in entries 7
+    /// and 8.
+    gdt: [u64; 9],
+    tss: TaskStateSegment,
+    /// FPU area of the task whose state is in the FPU registers of
+    /// the CPU, if any.
+    pub fpu_owner: Option<PAddr>,
+    /// Whether the CPU runs a task, as opposed to kernel code. Set
+    /// right before switching to a task, and cleared when the task
+    /// returns to the kernel.
+    pub in_task: bool,
+    /// Hardware interrupt that arrived while the kernel itself was
+    /// running. It is handled the next time a task is switched to.
+    pub pending_interrupt: Option<u64>,
+    /// Physical address of the task the CPU last switched to, or
+    /// `None` for the idle task. Reported on double faults.
+    pub current_task: Option<PAddr>,
+}
+
+/// Stacks of a CPU that do not belong to a task. Each stack has a
+/// guard page below it, left unmapped by the kernel page tables.
+#[repr(C, align(4096))]
+#[derive(Clone, Copy)]
+struct CpuStacks {
+    idle_guard: [u8; BASE_PAGE_LENGTH],
+    /// Stack of the idle task.
+    idle: [u8; CPU_STACK_LENGTH],
+    nmi_guard: [u8; BASE_PAGE_LENGTH],
+    /// Stack of non-maskable interrupts.
+    nmi: [u8; CPU_STACK_LENGTH],
+    double_fault_guard: [u8; BASE_PAGE_LENGTH],
+    /// Stack of double faults.
+    double_fault: [u8; CPU_STACK_LENGTH],
+}
+
+impl CpuLocal {
+    const fn empty() -> CpuLocal {
+        CpuLocal {
+            this: 0,
+            index: 0,
+            rsp_after_saving_registers: 0,
+            sysret_stack_pointer: 0,
+            registers: Registers {
+                rax: 0, rbx: 0, rcx: 0, rdx: 0, rsi: 0, rdi: 0,
+                r8: 0, r9: 0, r10: 0, r11: 0, r12: 0, r13: 0, r14: 0, r15: 0, rbp: 0
+            },
+            exception_stack_frame: None,
+            exception_error_code: None,
+            exception_code: None,
+            apic_id: 0,
+            user_page_table: 0,
+            gdt: [0; 9],
+            tss: TaskStateSegment::empty(),
+            fpu_owner: None,
+            in_task: false,
+            pending_interrupt: None,
+            current_task: None,
+        }
+    }
+
+    /// Index of the CPU. The bootstrap processor is 0.
+    pub fn index(&self) -> usize {
+        self.index as usize
+    }
+
+    /// Set the kernel stack used when entering the kernel from
+    /// user-space. Interrupts of kernel-mode code stay on the current
+    /// stack.
+    pub fn set_kernel_stack(&mut self, addr: u64) {
+        self.tss.sp0 = addr;
+    }
+}
+
+extern {
+    /// GDT memory address exposed by linker.
+    static GDT: [SegmentDescriptor; 9];
+}
+
+/// Per-CPU data of all CPUs, indexed by CPU index.
+static mut CPUS: [CpuLocal; MAX_CPUS] = [CpuLocal::empty(); MAX_CPUS];
+
+/// Idle and interrupt stacks of all CPUs, indexed by CPU index.
+static mut CPU_STACKS: [CpuStacks; MAX_CPUS] = [CpuStacks {
+    idle_guard: [0; BASE_PAGE_LENGTH],
+    idle: [0; CPU_STACK_LENGTH],
+    nmi_guard: [0; BASE_PAGE_LENGTH],
+    nmi: [0; CPU_STACK_LENGTH],
+    double_fault_guard: [0; BASE_PAGE_LENGTH],
+    double_fault: [0; CPU_STACK_LENGTH],
+}; MAX_CPUS];
+
+/// Number of CPUs that have been initialized.
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Bit mask of the CPUs running the idle task, by CPU index.
+static IDLE_CPUS: AtomicUsize = AtomicUsize::new(0);
+
+/// Bit mask of the CPUs running user code, by CPU index.
+static USER_CPUS: AtomicUsize = AtomicUsize::new(0);
+
+/// Bit mask of the CPUs that must flush their TLB before running
+/// user code again, by CPU index.
+static TLB_FLUSH_PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// Per-CPU data of the current CPU.
+///
+/// # Safety
+///
+/// `init` must have been called on the CPU, and there must be no
+/// other reference to the data.
+pub unsafe fn current() -> &'static mut CpuLocal {
+    let this: u64;
+    asm!("mov $0, gs:[0]" : "=r"(this) : : : "volatile", "intel");
+    &mut *(this as *mut CpuLocal)
+}
+
+/// Index of the current CPU.
+pub fn current_index() -> usize {
+    unsafe { current().index() }
+}
+
+/// Number of CPUs that have been initialized.
+pub fn count() -> usize {
+    CPU_COUNT.load(Ordering::SeqCst)
+}
+
+/// Top of the stack the idle task of the current CPU runs on.
+pub fn idle_stack_top() -> u64 {
+    unsafe {
+        let stacks = &CPU_STACKS[current_index()];
+        &stacks.idle as *const _ as u64 + CPU_STACK_LENGTH as u64
+    }
+}
+
+/// Whether `vaddr` is in the guard page of an idle or interrupt stack
+/// of a CPU.
+pub fn is_stack_guard_page(vaddr: VAddr) -> bool {
+    let vaddr = vaddr.into(): u64;
+    unsafe {
+        CPU_STACKS.iter().any(|stacks| {
+            [&stacks.idle_guard, &stacks.nmi_guard, &stacks.double_fault_guard].iter().any(|guard| {
+                let start = *guard as *const _ as u64;
+                start <= vaddr && vaddr < start + BASE_PAGE_LENGTH as u64
+            })
+        })
+    }
+}
+
+/// Initialize the per-CPU data of the CPU with the given index, and
+/// make it current. Loads the per-CPU GDT and task state segment,
+/// with `kernel_stack` as the initial kernel stack, and the
+/// non-maskable interrupt and double fault stacks of the CPU in the
+/// interrupt stack table.
+///
+/// # Safety
+///
+/// Must be called exactly once on each CPU, with distinct indexes,
+/// before any task is switched to.
+pub unsafe fn init(index: usize, kernel_stack: u64) {
+    use arch::segmentation::{DESC_P, DESC_DPL3, TYPE_SYS_TSS_AVAILABLE, load_tr};
+    use arch::interrupt::dtables::{DescriptorTablePointer, lgdt};
+
+    assert!(index < MAX_CPUS);
+    let cpu = &mut CPUS[index];
+    let this = cpu as *mut CpuLocal as u64;
+
+    assert!(&cpu.rsp_after_saving_registers as *const _ as u64 - this == CPU_RSP_AFTER_SAVING_REGISTERS as u64);
+    assert!(&cpu.sysret_stack_pointer as *const _ as u64 - this == CPU_SYSRET_STACK_POINTER as u64);
+    assert!(&cpu.registers.rax as *const _ as u64 - this == CPU_RAX as u64);
+    assert!(&cpu.registers.r15 as *const _ as u64 - this == CPU_R15 as u64);
+
+    cpu.this = this;
+    cpu.index = index as u64;
+    cpu.apic_id = (LOCAL_APIC.lock().id() >> 24) as u64;
+    cpu.set_kernel_stack(kernel_stack);
+    {
+        let stacks = &CPU_STACKS[index];
+        cpu.tss.ist2 = &stacks.nmi as *const _ as u64 + CPU_STACK_LENGTH as u64;
+        cpu.tss.ist3 = &stacks.double_fault as *const _ as u64 + CPU_STACK_LENGTH as u64;
+    }
+
+    let tss_vaddr = &cpu.tss as *const _ as u64;
+    let mut tss_desc = SegmentDescriptor::new((tss_vaddr & 0xFFFFFFFF) as u32,
+                                              size_of::<TaskStateSegment>() as u32);
+    tss_desc.insert(DESC_P | TYPE_SYS_TSS_AVAILABLE | DESC_DPL3);
+    for i in 0..7 {
+        cpu.gdt[i] = GDT[i].bits();
+    }
+    cpu.gdt[7] = tss_desc.bits();
+    cpu.gdt[8] = tss_vaddr >> 32;
+
+    lgdt(&DescriptorTablePointer {
+        limit: (size_of::<[u64; 9]>() - 1) as u16,
+        base: &cpu.gdt as *const _ as u64,
+    });
+    load_tr(SegmentSelector::new(7));
+
+    wrmsr(IA32_GS_BASE, this);
+    wrmsr(IA32_KERNEL_GS_BASE, 0);
+
+    CPU_COUNT.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Set the `fs` and `gs` base the current CPU runs user code with.
+/// The `gs` base is written to `IA32_KERNEL_GS_BASE`, and swapped in
+/// with `swapgs` when switching to user-mode.
+pub unsafe fn set_user_segment_bases(fs_base: u64, gs_base: u64) {
+    wrmsr(IA32_FS_BASE, fs_base);
+    wrmsr(IA32_KERNEL_GS_BASE, gs_base);
+}
+
+/// Send the interrupt `vector` to the CPU with the given index.
+fn send_interrupt(index: usize, vector: InterruptVector) {
+    let apic_id = unsafe { CPUS[index].apic_id };
+    LOCAL_APIC.lock().send(apic_id as u8, vector as u8);
+}
+
+/// Record the task the current CPU is about to switch to, or `None`
+/// for the idle task.
+pub fn set_current_task(task: Option<PAddr>) {
+    unsafe { current().current_task = task; }
+}
+
+/// Mark whether the current CPU is about to run the idle task, so
+/// that it can be woken up by `wake_up`.
+pub fn set_idle(idle: bool) {
+    let bit = 1 << current_index();
+    if idle {
+        IDLE_CPUS.fetch_or(bit, Ordering::SeqCst);
+    } else {
+        IDLE_CPUS.fetch_and(!bit, Ordering::SeqCst);
+    }
+}
+
+/// Wake up the idle CPUs in `mask`, other than the current one, so
+/// that they look for runnable tasks without waiting for the next
+/// timer interrupt.
+pub fn wake_up(mask: u64) {
+    let targets = IDLE_CPUS.load(Ordering::SeqCst) & (mask as usize) & !(1 << current_index());
+    for index in 0..MAX_CPUS {
+        if targets & (1 << index) != 0 {
+            send_interrupt(index, WAKEUP_INTERRUPT_CODE);
+        }
+    }
+}
+
+/// Mark the current CPU as running user code with the current page
+/// table, and flush the TLB if a shootdown is pending. Called right
+/// before switching to a user task.
+pub unsafe fn enter_user() {
+    let cpu = current();
+    let bit = 1 << cpu.index;
+
+    ptr::write_volatile(&mut cpu.user_page_table, cr3());
+    USER_CPUS.fetch_or(bit, Ordering::SeqCst);
+    if TLB_FLUSH_PENDING.fetch_and(!bit, Ordering::SeqCst) & bit != 0 {
+        flush_all();
+    }
+}
+
+/// Mark the current CPU as no longer running user code. Called right
+/// after a user task returns to the kernel.
+pub fn leave_user() {
+    USER_CPUS.fetch_and(!(1 << current_index()), Ordering::SeqCst);
+}
+
+/// Make other CPUs drop stale TLB entries after a change to the page
+/// table at `page_table`. The current CPU must already have flushed
+/// its own entries. Every other CPU flushes its TLB before it runs
+/// user code again, and the CPUs running user code with the page
+/// table are interrupted. Waits until they have returned to the
+/// kernel.
+pub fn tlb_shootdown(page_table: PAddr) {
+    let others = ((1 << MAX_CPUS) - 1) & !(1 << current_index());
+    TLB_FLUSH_PENDING.fetch_or(others, Ordering::SeqCst);
+
+    let user = USER_CPUS.load(Ordering::SeqCst) & others;
+    let mut waiting = 0;
+    for index in 0..MAX_CPUS {
+        if user & (1 << index) != 0 &&
+            unsafe { ptr::read_volatile(&CPUS[index].user_page_table) } == page_table.into(): u64
+        {
+            send_interrupt(index, TLB_SHOOTDOWN_INTERRUPT_CODE);
+            waiting |= 1 << index;
+        }
+    }
+
+    while waiting != 0 {
+        waiting &= TLB_FLUSH_PENDING.load(Ordering::SeqCst) & USER_CPUS.load(Ordering::SeqCst);
+        spin_loop_hint();
+    }
+}
@@ -1,3 +1,15 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// I/O port of the serial console.
+static SERIAL_PORT: AtomicUsize = AtomicUsize::new(0x3F8);
+
+/// Set the I/O port of the serial console, for example `0x2F8` for
+/// COM2.
+pub fn set_serial_port(port: u16)
+{
+	SERIAL_PORT.store(port as usize, Ordering::Relaxed);
+}
+
 /// Write a string to the output channel
 ///
 /// This method is unsafe because it does port accesses without synchronisation
@@ -14,13 +26,15 @@ pub unsafe fn puts(s: &str)
 /// This method is unsafe because it does port accesses without synchronisation
 pub unsafe fn putb(b: u8)
 {
+	let port = SERIAL_PORT.load(Ordering::Relaxed) as u16;
+
 	// Wait for the serial port's fifo to not be empty
-        while (::arch::inportb(0x3F8+5) & 0x20) == 0
+        while (::arch::inportb(port+5) & 0x20) == 0
 	{
 		// Do nothing
 	}
 	// Send the byte out the serial port
-        ::arch::outportb(0x3F8, b);
+        ::arch::outportb(port, b);
 	
 	// Also send to the bochs 0xe9 hack
         ::arch::outportb(0xe9, b);
@@ -0,0 +1,205 @@
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use common::PAddr;
+use arch::cpu;
+use arch::MemoryObject;
+
+/// Length of a task FPU area. Enough for the XSAVE area of every
+/// state component up to AVX-512.
+pub const FPU_AREA_LENGTH: usize = 4096;
+/// Alignment of a task FPU area. One page, so that the area can be
+/// mapped as a single page.
+pub const FPU_AREA_ALIGNMENT: usize = 4096;
+
+/// `CR0.MP`, monitor coprocessor.
+const CR0_MP: u64 = 1 << 1;
+/// `CR0.EM`, x87 emulation.
+const CR0_EM: u64 = 1 << 2;
+/// `CR0.TS`, task switched. FPU instructions raise `#NM` while set.
+const CR0_TS: u64 = 1 << 3;
+/// `CR4.OSFXSR`, enables `fxsave`, `fxrstor` and SSE.
+const CR4_OSFXSR: u64 = 1 << 9;
+/// `CR4.OSXMMEXCPT`, enables SIMD floating-point exceptions.
+const CR4_OSXMMEXCPT: u64 = 1 << 10;
+/// `CR4.OSXSAVE`, enables `xsave`, `xrstor` and `xgetbv`.
+const CR4_OSXSAVE: u64 = 1 << 18;
+
+/// `XCR0` bits of the x87, SSE and AVX state components.
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+
+/// Initial x87 control word: all exceptions masked, 64-bit precision.
+const INITIAL_FCW: u16 = 0x37F;
+/// Initial `MXCSR`: all exceptions masked.
+const INITIAL_MXCSR: u32 = 0x1F80;
+
+/// Whether the areas are saved with `xsave`. Otherwise `fxsave` is
+/// used.
+static USE_XSAVE: AtomicBool = AtomicBool::new(false);
+/// State components enabled in `XCR0`.
+static XSAVE_MASK: AtomicUsize = AtomicUsize::new(0);
+
+/// Execute `cpuid` with the given leaf and subleaf. Returns `eax`,
+/// `ebx`, `ecx` and `edx`.
+pub unsafe fn cpuid(leaf: u32, subleaf: u32) -> (u32, u32, u32, u32) {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    asm!("cpuid"
+         : "={eax}"(eax), "={ebx}"(ebx), "={ecx}"(ecx), "={edx}"(edx)
+         : "{eax}"(leaf), "{ecx}"(subleaf)
+         :: "volatile");
+    (eax, ebx, ecx, edx)
+}
+
+unsafe fn cr0() -> u64 {
+    let ret: u64;
+    asm!("mov %cr0, $0" : "=r" (ret) ::: "volatile");
+    ret
+}
+
+unsafe fn cr0_write(val: u64) {
+    asm!("mov $0, %cr0" :: "r" (val) : "memory" : "volatile");
+}
+
+unsafe fn cr4() -> u64 {
+    let ret: u64;
+    asm!("mov %cr4, $0" : "=r" (ret) ::: "volatile");
+    ret
+}
+
+unsafe fn cr4_write(val: u64) {
+    asm!("mov $0, %cr4" :: "r" (val) : "memory" : "volatile");
+}
+
+unsafe fn xsetbv(xcr: u32, value: u64) {
+    asm!("xsetbv" :: "{ecx}"(xcr), "{eax}"(value as u32), "{edx}"((value >> 32) as u32)
+         :: "volatile");
+}
+
+/// Set or clear `CR0.TS`.
+unsafe fn set_task_switched(task_switched: bool) {
+    let value = cr0();
+    if task_switched {
+        cr0_write(value | CR0_TS);
+    } else {
+        cr0_write(value & !CR0_TS);
+    }
+}
+
+/// Enable the FPU, SSE and, if supported, `xsave` with AVX on the
+/// current CPU. `CR0.TS` is left set, so the first FPU instruction
+/// of a task raises `#NM`.
+///
+/// # Safety
+///
+/// Must be called once on each CPU during initialization. The
+/// bootstrap processor must be initialized first.
+pub unsafe fn init(bootstrap: bool) {
+    let (_, _, features_ecx, _) = cpuid(1, 0);
+    let has_xsave = features_ecx & (1 << 26) != 0;
+    let has_avx = features_ecx & (1 << 28) != 0;
+
+    cr0_write((cr0() & !CR0_EM) | CR0_MP | CR0_TS);
+
+    let mut cr4_value = cr4() | CR4_OSFXSR | CR4_OSXMMEXCPT;
+    if has_xsave {
+        cr4_value |= CR4_OSXSAVE;
+    }
+    cr4_write(cr4_value);
+
+    if has_xsave {
+        let mask = XCR0_X87 | XCR0_SSE | if has_avx { XCR0_AVX } else { 0 };
+        xsetbv(0, mask);
+
+        if bootstrap {
+            // Size of the area for the components enabled in XCR0.
+            let (_, length, _, _) = cpuid(0xD, 0);
+            assert!(length as usize <= FPU_AREA_LENGTH);
+            XSAVE_MASK.store(mask as usize, Ordering::SeqCst);
+            USE_XSAVE.store(true, Ordering::SeqCst);
+            log!("xsave enabled with mask 0b{:b}, area length {}", mask, length);
+        }
+    }
+}
+
+/// Initialize the FPU area at `paddr`, so that restoring it gives the
+/// initial FPU state.
+///
+/// # Safety
+///
+/// `paddr` must point to `FPU_AREA_LENGTH` bytes of memory reserved
+/// for the area.
+pub unsafe fn init_area(paddr: PAddr) {
+    let mut area = MemoryObject::<[u8; FPU_AREA_LENGTH]>::new(paddr);
+    let bytes = area.as_mut();
+    for byte in bytes.iter_mut() {
+        *byte = 0;
+    }
+    // Legacy region: FCW at 0, MXCSR at 24. The XSAVE header is left
+    // empty, so the other components are in their initial state.
+    ptr::write_unaligned(bytes.as_mut_ptr() as *mut u16, INITIAL_FCW);
+    ptr::write_unaligned(bytes.as_mut_ptr().offset(24) as *mut u32, INITIAL_MXCSR);
+}
+
+/// Save the FPU state of the current CPU to the area at `paddr`.
+unsafe fn save(paddr: PAddr) {
+    let area = MemoryObject::<[u8; FPU_AREA_LENGTH]>::new(paddr);
+    let pointer = area.as_ptr() as *mut u8;
+    if USE_XSAVE.load(Ordering::Relaxed) {
+        let mask = XSAVE_MASK.load(Ordering::Relaxed) as u64;
+        asm!("xsave64 ($0)" :: "r"(pointer), "{eax}"(mask as u32), "{edx}"((mask >> 32) as u32)
+             : "memory" : "volatile");
+    } else {
+        asm!("fxsave64 ($0)" :: "r"(pointer) : "memory" : "volatile");
+    }
+}
+
+/// Restore the FPU state of the current CPU from the area at
+/// `paddr`.
+unsafe fn restore(paddr: PAddr) {
+    let area = MemoryObject::<[u8; FPU_AREA_LENGTH]>::new(paddr);
+    let pointer = area.as_ptr() as *const u8;
+    if USE_XSAVE.load(Ordering::Relaxed) {
+        let mask = XSAVE_MASK.load(Ordering::Relaxed) as u64;
+        asm!("xrstor64 ($0)" :: "r"(pointer), "{eax}"(mask as u32), "{edx}"((mask >> 32) as u32)
+             : "memory" : "volatile");
+    } else {
+        asm!("fxrstor64 ($0)" :: "r"(pointer) : "memory" : "volatile");
+    }
+}
+
+/// Prepare the FPU before switching to a task with the area at
+/// `paddr`. If the registers of the current CPU still hold the state
+/// of the task, the FPU is enabled right away. Otherwise `CR0.TS` is
+/// set, and the state is restored by `device_not_available` when the
+/// task first uses the FPU. `ran_here` tells whether the task last
+/// ran on the current CPU, as the registers are stale otherwise.
+pub unsafe fn enter(paddr: Option<PAddr>, ran_here: bool) {
+    let owner = cpu::current().fpu_owner;
+    match paddr {
+        Some(paddr) if ran_here && owner == Some(paddr) => set_task_switched(false),
+        _ => set_task_switched(true),
+    }
+}
+
+/// Handle `#NM` raised by a task with the area at `paddr`: restore
+/// its state and enable the FPU.
+pub unsafe fn device_not_available(paddr: PAddr) {
+    set_task_switched(false);
+    restore(paddr);
+    cpu::current().fpu_owner = Some(paddr);
+}
+
+/// Save the FPU state after a task with the area at `paddr` returned
+/// to the kernel, if the task had the FPU enabled. The registers are
+/// kept, so `enter` can skip restoring them if the task runs again on
+/// this CPU. The area is always up to date afterwards, so the task can
+/// run on any CPU.
+pub unsafe fn leave(paddr: Option<PAddr>) {
+    if let Some(paddr) = paddr {
+        if cr0() & CR0_TS == 0 && cpu::current().fpu_owner == Some(paddr) {
+            save(paddr);
+        }
+    }
+    set_task_switched(true);
+}
@@ -1,4 +1,13 @@
-use arch::interrupt::{self, IDT, IO_APIC, LOCAL_APIC, disable_pic};
+use arch::interrupt::{self, IDT, IO_APIC, LOCAL_APIC, disable_pic, system_call_entry};
+use arch::wrmsr;
+
+/// `IA32_STAR` MSR, holding the segment selectors of `syscall` and
+/// `sysret`.
+const IA32_STAR: u32 = 0xC0000081;
+/// `IA32_LSTAR` MSR, holding the `syscall` entry point.
+const IA32_LSTAR: u32 = 0xC0000082;
+/// `IA32_FMASK` MSR, holding the RFLAGS bits cleared by `syscall`.
+const IA32_FMASK: u32 = 0xC0000084;
 
 /// Initialize interrupt. Disable PIC and then initialize APIC
 /// together with keyboard interrupt on I/O APIC.
@@ -9,9 +18,37 @@ pub fn init() {
     {
         let mut local_apic = LOCAL_APIC.lock();
         let mut io_apic = IO_APIC.lock();
-        let local_apic_id = local_apic.id() as u8;
+        let local_apic_id = (local_apic.id() >> 24) as u8;
         io_apic.set_irq(0x1, local_apic_id, interrupt::KEYBOARD_INTERRUPT_CODE);
 
         local_apic.set_siv(0x1FF);
     }
+
+    unsafe { init_system_call() };
+}
+
+/// Initialize interrupt of an application processor. The PIC and
+/// the I/O APIC are already set up by the bootstrap processor, so only
+/// the local APIC and the timer are initialized.
+pub fn init_ap() {
+    IDT.load();
+
+    {
+        let mut local_apic = LOCAL_APIC.lock();
+        local_apic.set_siv(0x1FF);
+        local_apic.enable_calibrated_timer();
+    }
+
+    unsafe { init_system_call() };
+}
+
+/// Set up the `syscall` instruction. It enters the kernel code
+/// segment 0x08 at `system_call_entry` with TF, IF, DF and NT
+/// cleared. `sysret` returns to the 64-bit user code segment
+/// 0x18 + 16 = 0x28, with the user data segment 0x18 + 8 = 0x20.
+/// SCE in IA32_EFER is already enabled by the bootstrap code.
+unsafe fn init_system_call() {
+    wrmsr(IA32_STAR, ((0x18 | 0x3) << 48) | (0x08 << 32));
+    wrmsr(IA32_LSTAR, system_call_entry as u64);
+    wrmsr(IA32_FMASK, 0x4700);
 }
@@ -11,18 +11,24 @@ mod interrupt;
 /// Segmentation initialization code.
 mod segmentation;
 
+/// Application processor startup.
+mod smp;
+
 pub use self::paging::{KERNEL_PML4, KERNEL_PDPT, KERNEL_PD,
                        OBJECT_POOL_PT, OBJECT_POOL_START_VADDR,
                        LOCAL_APIC_PAGE_VADDR, IO_APIC_PAGE_VADDR};
-pub use self::segmentation::set_kernel_stack;
+pub use self::smp::start_application_processors;
 
-use ::kmain;
+use ::{kmain, kmain_ap};
 use super::{kernel_end_paddr, kernel_start_paddr, kernel_start_vaddr};
+use arch::{acpi, fpu, backtrace};
 
 use core::mem;
 use core::slice::{self, Iter};
 
 use common::{PAddr, MemoryRegion};
+use arch::paging::BASE_PAGE_LENGTH;
+use cmdline::KernelOptions;
 
 extern {
     /// Multiboot signature exposed by linker.
@@ -60,14 +66,16 @@ impl<'a> Iterator for FreeRegionsIterator<'a> {
 }
 
 /// Initialization information to be passed to `kmain`. It contains
-/// free regions and rinit and kernel memory region information. At
-/// most 16 free regions are supported.
+/// free regions, rinit and kernel memory region information, and the
+/// kernel command line options. At most 16 free regions are
+/// supported.
 #[derive(Debug)]
 pub struct InitInfo {
     free_regions_size: usize,
     free_regions: [Option<MemoryRegion>; 16],
     rinit_region: MemoryRegion,
     kernel_region: MemoryRegion,
+    options: KernelOptions,
 }
 
 impl InitInfo {
@@ -87,12 +95,20 @@ impl InitInfo {
         self.rinit_region
     }
 
-    /// Create a new `InitInfo` using a kernel region and a rinit region.
-    pub fn new(kernel_region: MemoryRegion, rinit_region: MemoryRegion) -> InitInfo {
+    /// Options parsed from the kernel command line.
+    pub fn options(&self) -> &KernelOptions {
+        &self.options
+    }
+
+    /// Create a new `InitInfo` using a kernel region, a rinit region
+    /// and kernel options.
+    pub fn new(kernel_region: MemoryRegion, rinit_region: MemoryRegion,
+               options: KernelOptions) -> InitInfo {
         InitInfo { free_regions_size: 0,
                    free_regions: [None; 16],
                    kernel_region: kernel_region,
-                   rinit_region: rinit_region }
+                   rinit_region: rinit_region,
+                   options: options }
     }
 
     /// Append a new free region to the `InitInfo`.
@@ -102,10 +118,11 @@ impl InitInfo {
     }
 }
 
-/// Read the multiboot structure. Construct an `InitInfo` with all
-/// free regions. A memory region that will be used for initial memory
-/// allocation is returned seperately. That region is always the same
-/// as the region of the kernel region.
+/// Read the multiboot structure. Parse and apply the kernel command
+/// line, and construct an `InitInfo` with all free regions. A memory
+/// region that will be used for initial memory allocation is returned
+/// seperately. That region is always the same as the region of the
+/// kernel region.
 fn bootstrap_archinfo() -> (InitInfo, MemoryRegion) {
     let bootinfo = unsafe {
         multiboot::Multiboot::new(multiboot_paddr(), |addr, size| {
@@ -114,7 +131,26 @@ fn bootstrap_archinfo() -> (InitInfo, MemoryRegion) {
         })
     }.unwrap();
 
-    let rinit_module = bootinfo.modules().unwrap().next().unwrap();
+    let options = KernelOptions::parse(bootinfo.command_line().unwrap_or(""));
+    if let Some(port) = options.serial_port {
+        super::debug::set_serial_port(port);
+    }
+    if let Some(level) = options.log_level {
+        ::logging::set_max_level(level);
+    }
+    log!("kernel options: {:?}", options);
+    backtrace::set_exit_on_panic(options.test.is_some());
+    load_kernel_symbols(&bootinfo);
+
+    let rinit_module = {
+        let mut modules = bootinfo.modules().unwrap();
+        match options.init_module() {
+            Some(name) => modules.find(|module| {
+                module.string.map(|string| name.matches(string)).unwrap_or(false)
+            }).expect("init module not found"),
+            None => modules.next().unwrap(),
+        }
+    };
     log!("rinit module: {:?}", rinit_module);
     
     let mut archinfo = InitInfo::new(
@@ -123,7 +159,8 @@ fn bootstrap_archinfo() -> (InitInfo, MemoryRegion) {
                           kernel_start_paddr().into(): usize),
         MemoryRegion::new(rinit_module.start,
                           rinit_module.end.into(): usize + 1 -
-                          rinit_module.start.into(): usize));
+                          rinit_module.start.into(): usize),
+        options);
     let mut alloc_region: Option<MemoryRegion> = None;
     
     for area in bootinfo.memory_regions().unwrap() {
@@ -135,6 +172,16 @@ fn bootstrap_archinfo() -> (InitInfo, MemoryRegion) {
 
         let mut cur_region = MemoryRegion::new(area.base_address(), area.length() as usize);
 
+        // Keep the application processor trampoline page out of the
+        // free regions.
+        let trampoline_end = PAddr::from(smp::AP_TRAMPOLINE_PADDR + BASE_PAGE_LENGTH);
+        if cur_region.start_paddr() < trampoline_end {
+            if cur_region.end_paddr() < trampoline_end {
+                continue;
+            }
+            cur_region.move_up(trampoline_end);
+        }
+
         if cur_region.skip_up(&archinfo.kernel_region()) {
             assert!(cur_region.skip_up(&archinfo.rinit_region()));
             alloc_region = Some(cur_region);
@@ -146,9 +193,26 @@ fn bootstrap_archinfo() -> (InitInfo, MemoryRegion) {
     (archinfo, alloc_region.unwrap())
 }
 
+/// Copy the kernel symbol table from the ELF sections provided by the
+/// boot loader, so that backtraces are symbolized. The sections lie
+/// in memory that is handed out as free memory later on.
+fn load_kernel_symbols<'a, F: Fn(PAddr, usize) -> Option<&'a [u8]>>(bootinfo: &multiboot::Multiboot<'a, F>) {
+    use elf::SHT_SYMTAB;
+
+    let loaded = bootinfo.elf_sections().and_then(|sections| {
+        let symtab = sections.iter().find(|section| section.shtype == SHT_SYMTAB)?;
+        let strtab = sections.get(symtab.link as usize)?;
+        unsafe { backtrace::load_symbols(bootinfo.elf_section_data(symtab)?,
+                                         bootinfo.elf_section_data(strtab)?) }
+    });
+    if loaded.is_none() {
+        log!("Kernel symbols not available, backtraces are not symbolized.");
+    }
+}
+
 /// Kernel entrypoint. This function calls `bootstrap_archinfo`, and
-/// then use the information to initialize paging, segmentation,
-/// interrupt, and APIC. It then jumps to `kmain`.
+/// then use the information to initialize paging, ACPI,
+/// segmentation, interrupt, APIC and FPU. It then jumps to `kmain`.
 #[lang="start"]
 #[no_mangle]
 #[allow(private_no_mangle_fns)]
@@ -160,8 +224,14 @@ pub fn kinit() {
     log!("alloc_region: {:?}", alloc_region);
 
     paging::init(&mut alloc_region);
+    acpi::init();
+    if let Some(io_apic) = acpi::ACPI.lock().primary_io_apic() {
+        unsafe { paging::map_io_apic(io_apic.address) };
+        ::arch::interrupt::IO_APIC.lock().set_gsi_base(io_apic.gsi_base);
+    }
     segmentation::init();
     interrupt::init();
+    unsafe { fpu::init(true) };
 
     archinfo.push_free_region(alloc_region);
 
@@ -176,3 +246,17 @@ pub fn kinit() {
 
     kmain(archinfo);
 }
+
+/// Application processor entrypoint, called from `start.S` with the
+/// CPU index and the kernel stack of the processor. This function
+/// initializes segmentation, interrupt and FPU for the processor, and
+/// then jumps to `kmain_ap`.
+#[no_mangle]
+#[allow(private_no_mangle_fns)]
+pub extern "C" fn kinit_ap(index: u64, kernel_stack: u64) -> ! {
+    unsafe { segmentation::init_ap(index as usize, kernel_stack) };
+    interrupt::init_ap();
+    unsafe { fpu::init(false) };
+
+    kmain_ap()
+}
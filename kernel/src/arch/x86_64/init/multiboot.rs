@@ -3,6 +3,7 @@ use core::str;
 use core::slice;
 
 use common::PAddr;
+use elf::SectionHeader;
 
 /// Value found in %eax after multiboot jumps to our entry point.
 #[allow(dead_code)]
@@ -172,6 +173,8 @@ impl<'a, F: Fn(PAddr, usize) -> Option<&'a [u8]>> Multiboot<'a, F> {
                has_modules, 3);
     check_flag!(doc = "If true, then the `syms` field is valid.",
                has_symbols, 4, 5);
+    check_flag!(doc = "If true, then the `syms` field holds ELF section headers.",
+               has_elf_sections, 5);
     check_flag!(doc = "If true, then the `mmap_addr` and `mmap_length` fields are valid.",
                has_memory_map, 6);
     check_flag!(doc = "If true, then the `drives_addr` and `drives_length` fields are valid.",
@@ -251,6 +254,30 @@ impl<'a, F: Fn(PAddr, usize) -> Option<&'a [u8]>> Multiboot<'a, F> {
         }
     }
 
+    /// ELF section headers of the kernel, if the boot loader provided
+    /// them.
+    pub fn elf_sections(&self) -> Option<&'a [SectionHeader]> {
+        if !self.has_elf_sections() || self.header.elf_symbols.size as usize != size_of::<SectionHeader>() {
+            return None;
+        }
+        let count = self.header.elf_symbols.num as usize;
+        unsafe {
+            (self.paddr_to_slice)(PAddr::from(self.header.elf_symbols.addr),
+                                  count * size_of::<SectionHeader>()).map(|slice| {
+                                      slice::from_raw_parts(slice.as_ptr() as *const SectionHeader, count)
+                                  })
+        }
+    }
+
+    /// Data of a kernel ELF section, at the address the boot loader
+    /// loaded it to. Returns `None` for sections that were not loaded.
+    pub fn elf_section_data(&self, section: &SectionHeader) -> Option<&'a [u8]> {
+        if section.addr == 0 {
+            return None;
+        }
+        (self.paddr_to_slice)(PAddr::from(section.addr), section.size as usize)
+    }
+
     /// Discover all memory regions in the multiboot memory map.
     pub fn memory_regions(&'a self) -> Option<MemoryMapIter<F>> {
         match self.has_memory_map() {
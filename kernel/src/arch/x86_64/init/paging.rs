@@ -6,6 +6,7 @@ use arch::paging::{PTEntry, PML4, PDPT, PD, PT,
                    pml4_index, pdpt_index, pd_index, pt_index,
                    BASE_PAGE_LENGTH, LARGE_PAGE_LENGTH};
 use arch::{KERNEL_BASE};
+use arch::cpu;
 use common::{PAddr, VAddr, MemoryRegion};
 use util::{block_count, align_up, ExternReadonlyObject, ExternMutex};
 
@@ -14,8 +15,14 @@ extern {
     static mut init_pd: PD;
     /// `kernel_stack_guard_page` exposed by linker.
     static kernel_stack_guard_page: u64;
+    /// `ap_stacks` exposed by linker.
+    static ap_stacks: u64;
 }
 
+/// Length of the kernel stack of an application processor. Must
+/// match `AP_STACK_SIZE` in `start.S`.
+const AP_STACK_LENGTH: usize = 0x10000;
+
 // Below should be used BEFORE switching to new page table structure.
 /// Virtual address as the base for all allocations. Those are used
 /// before switching to the new page structure.
@@ -70,6 +77,27 @@ fn kernel_stack_guard_page_vaddr() -> VAddr {
     unsafe { VAddr::from((&kernel_stack_guard_page as *const _) as u64) }
 }
 
+/// Whether the kernel page at `vaddr` is a stack guard page, left
+/// unmapped so that a stack overflow faults. These are the guard page
+/// of the initial stack, the lowest page of each application
+/// processor stack, and the guard pages of the per-CPU stacks.
+fn is_kernel_guard_page(vaddr: VAddr) -> bool {
+    if vaddr == kernel_stack_guard_page_vaddr() {
+        return true;
+    }
+
+    let ap_stacks_start = unsafe { (&ap_stacks as *const _) as usize };
+    let ap_stacks_end = ap_stacks_start + AP_STACK_LENGTH * (cpu::MAX_CPUS - 1);
+    let vaddr_usize = vaddr.into(): usize;
+    if ap_stacks_start <= vaddr_usize && vaddr_usize < ap_stacks_end &&
+        (vaddr_usize - ap_stacks_start) % AP_STACK_LENGTH < BASE_PAGE_LENGTH
+    {
+        return true;
+    }
+
+    cpu::is_stack_guard_page(vaddr)
+}
+
 /// Allocate the kernel PML4 using the given memory region and
 /// allocation base.
 fn alloc_kernel_pml4(region: &mut MemoryRegion, alloc_base: PAddr) -> Unique<PML4> {
@@ -169,21 +197,7 @@ fn alloc_object_pool_pt(region: &mut MemoryRegion, pd: &mut PD, alloc_base: PAdd
         }
 
         {
-            /// Write 64 bits to msr register.
-            #[allow(dead_code)]
-            unsafe fn wrmsr(msr: u32, value: u64) {
-                let low = value as u32;
-                let high = (value >> 32) as u32;
-                asm!("wrmsr" :: "{ecx}" (msr), "{eax}" (low), "{edx}" (high) : "memory" : "volatile" );
-            }
-
-            /// Read 64 bits msr register.
-            #[allow(unused_mut)]
-            unsafe fn rdmsr(msr: u32) -> u64 {
-                let (high, low): (u32, u32);
-                asm!("rdmsr" : "={eax}" (low), "={edx}" (high) : "{ecx}" (msr) : "memory" : "volatile");
-                ((high as u64) << 32) | (low as u64)
-            }
+            use arch::rdmsr;
 
             let apic_msr = unsafe { rdmsr(0x1B) };
             assert!(apic_msr & (1<<11) == (1<<11));
@@ -194,6 +208,8 @@ fn alloc_object_pool_pt(region: &mut MemoryRegion, pd: &mut PD, alloc_base: PAdd
         }
 
         {
+            // Default address, remapped by `map_io_apic` if the ACPI
+            // tables give another one.
             let io_apic_base = PAddr::from(0xfec00000: u64);
             let io_apic_pt_index = pt_index(IO_APIC_PAGE_VADDR);
             pt[io_apic_pt_index] = PTEntry::new(io_apic_base, PT_P | PT_RW | PT_PWT | PT_PCD);
@@ -207,6 +223,16 @@ fn alloc_object_pool_pt(region: &mut MemoryRegion, pd: &mut PD, alloc_base: PAdd
     pt_unique
 }
 
+/// Map the I/O APIC page to `paddr`, replacing the default address.
+/// Must be called after switching to the new page table.
+pub unsafe fn map_io_apic(paddr: PAddr) {
+    use arch::paging::{PTEntry, PT_P, PT_RW, PT_PWT, PT_PCD, flush};
+
+    let pt = &mut *(OBJECT_POOL_PT_VADDR.into(): usize as *mut PT);
+    pt[pt_index(IO_APIC_PAGE_VADDR)] = PTEntry::new(paddr, PT_P | PT_RW | PT_PWT | PT_PCD);
+    flush(IO_APIC_PAGE_VADDR);
+}
+
 /// Allocate one kernel page using `offset_size`.
 fn alloc_kernel_page(pt: &mut PT, offset_size: usize) {
     use arch::paging::{PT_P, PT_RW};
@@ -214,7 +240,7 @@ fn alloc_kernel_page(pt: &mut PT, offset_size: usize) {
     let paddr = kernel_start_paddr() + (offset_size * BASE_PAGE_LENGTH);
     let vaddr = kernel_start_vaddr() + (offset_size * BASE_PAGE_LENGTH);
 
-    log!("kernel page allocated at 0x{:x}", vaddr);
+    debug!("kernel page allocated at 0x{:x}", vaddr);
 
     pt[pt_index(vaddr)] = PTEntry::new(paddr, PT_P | PT_RW);
 }
@@ -234,11 +260,6 @@ fn alloc_kernel_pts(region: &mut MemoryRegion, pd: &mut PD, alloc_base: PAddr) {
     
     let kernel_page_size = block_count(kernel_end_paddr().into(): usize -
                                        kernel_start_paddr().into(): usize, BASE_PAGE_LENGTH);
-    let guard_page_index = (kernel_stack_guard_page_vaddr().into(): usize -
-                            kernel_start_vaddr().into(): usize) / BASE_PAGE_LENGTH;
-
-    log!("guard_page_index: {}", guard_page_index);
-
     for i in 0..kernel_page_size {
         if i % 512 == 0 {
             pd[pd_index(kernel_start_vaddr() + i * BASE_PAGE_LENGTH)] = PDEntry::new(region.start_paddr(), PD_P | PD_RW);
@@ -253,7 +274,7 @@ fn alloc_kernel_pts(region: &mut MemoryRegion, pd: &mut PD, alloc_base: PAddr) {
         let mut pt_unique = unsafe {
             Unique::new_unchecked((INITIAL_ALLOC_START_VADDR + offset).into(): usize as *mut PT) };
         
-        if i == guard_page_index {
+        if is_kernel_guard_page(kernel_start_vaddr() + i * BASE_PAGE_LENGTH) {
             alloc_kernel_guard_page(unsafe { pt_unique.as_mut() }, i % 512);
         } else {
             alloc_kernel_page(unsafe { pt_unique.as_mut() }, i % 512);
@@ -0,0 +1,97 @@
+use core::ptr;
+use common::PAddr;
+use arch::cpu::{self, MAX_CPUS};
+use arch::interrupt::{pit, LOCAL_APIC};
+use arch::acpi::ACPI;
+use arch::paging::BASE_PAGE_LENGTH;
+use arch::MemoryObject;
+use super::paging::KERNEL_PML4;
+
+/// Physical address the application processor trampoline is copied
+/// to. It must be page-aligned and below 1MB, and is kept out of the
+/// free regions. Must match `AP_TRAMPOLINE_PADDR` in `start.S`.
+pub const AP_TRAMPOLINE_PADDR: usize = 0x8000;
+
+extern {
+    /// Start of the real-mode trampoline exposed by linker.
+    static ap_trampoline: u8;
+    /// End of the real-mode trampoline exposed by linker.
+    static ap_trampoline_end: u8;
+    /// Kernel PML4 physical address loaded by application processors.
+    static mut ap_pml4_paddr: u64;
+    /// Index given to the next application processor that starts.
+    static ap_next_index: u64;
+}
+
+/// Start the application processors with INIT and startup IPIs, and
+/// wait until they have initialized. Each of them enters `kinit_ap`
+/// with its own index and kernel stack. The processors listed in the
+/// ACPI MADT are started one by one. Without a MADT, the IPIs are
+/// broadcast and every processor that responds in time is used.
+pub fn start_application_processors() {
+    unsafe {
+        let start = &ap_trampoline as *const u8;
+        let length = &ap_trampoline_end as *const u8 as usize - start as usize;
+        assert!(length <= BASE_PAGE_LENGTH);
+
+        let trampoline = MemoryObject::<u8>::slice(PAddr::from(AP_TRAMPOLINE_PADDR), length);
+        ptr::copy_nonoverlapping(start, trampoline.as_ptr(), length);
+        ap_pml4_paddr = KERNEL_PML4.paddr().into();
+    }
+
+    let page = (AP_TRAMPOLINE_PADDR / BASE_PAGE_LENGTH) as u8;
+    let mut targets = [0u8; MAX_CPUS - 1];
+    let mut target_count = 0;
+    {
+        let bsp_apic_id = (LOCAL_APIC.lock().id() >> 24) as u8;
+        for cpu in ACPI.lock().cpus().filter(|cpu| cpu.apic_id != bsp_apic_id) {
+            if target_count == targets.len() {
+                log!("Too many CPUs, not starting local APIC 0x{:x}.", cpu.apic_id);
+                continue;
+            }
+            targets[target_count] = cpu.apic_id;
+            target_count += 1;
+        }
+    }
+
+    let started = if target_count > 0 {
+        for &apic_id in targets[..target_count].iter() {
+            LOCAL_APIC.lock().send_init(apic_id);
+            unsafe { pit::sleep(10000) };
+            for _ in 0..2 {
+                LOCAL_APIC.lock().send_startup(apic_id, page);
+                unsafe { pit::sleep(200) };
+            }
+        }
+        target_count + 1
+    } else {
+        {
+            let mut local_apic = LOCAL_APIC.lock();
+            local_apic.broadcast_init();
+            unsafe { pit::sleep(10000) };
+            for _ in 0..2 {
+                local_apic.broadcast_startup(page);
+                unsafe { pit::sleep(200) };
+            }
+        }
+
+        // Processors that have not taken an index by now are
+        // considered absent.
+        unsafe { pit::sleep(10000) };
+        let next_index = unsafe { ptr::read_volatile(&ap_next_index) } as usize;
+        if next_index > MAX_CPUS { MAX_CPUS } else { next_index }
+    };
+
+    // Wait up to 100ms for the started processors to initialize.
+    for _ in 0..100 {
+        if cpu::count() >= started {
+            break;
+        }
+        unsafe { pit::sleep(1000) };
+    }
+    if cpu::count() < started {
+        log!("Only {} of {} CPUs have started.", cpu::count(), started);
+    }
+
+    log!("{} CPUs online", cpu::count());
+}
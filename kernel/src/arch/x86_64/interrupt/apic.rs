@@ -1,7 +1,10 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
 use common::*;
 use arch::init::{LOCAL_APIC_PAGE_VADDR, IO_APIC_PAGE_VADDR};
+use arch::acpi::ACPI;
 use util::{Mutex};
 use super::{InterruptVector};
+use super::pit;
 
 /// Local APIC pointer.
 #[derive(Debug)]
@@ -13,16 +16,24 @@ pub struct LocalAPIC {
 #[derive(Debug)]
 pub struct IOAPIC {
     address: VAddr,
+    /// First global system interrupt handled by the I/O APIC.
+    gsi_base: u32,
 }
 
-/// The local APIC static.
+/// Initial count of the timer computed by `enable_timer`, so that
+/// application processors can reuse it without calibrating again.
+static TIMER_INITIAL_COUNT: AtomicUsize = AtomicUsize::new(0x10000);
+
+/// The local APIC static. The page is mapped at the same address on
+/// every CPU, and accesses reach the local APIC of the current CPU.
 pub static LOCAL_APIC: Mutex<LocalAPIC> = Mutex::new(LocalAPIC {
     address: LOCAL_APIC_PAGE_VADDR
 });
 
 /// The I/O APIC static.
 pub static IO_APIC: Mutex<IOAPIC> = Mutex::new(IOAPIC {
-    address: IO_APIC_PAGE_VADDR
+    address: IO_APIC_PAGE_VADDR,
+    gsi_base: 0,
 });
 
 #[allow(dead_code)]
@@ -72,16 +83,100 @@ impl LocalAPIC {
         unsafe { self.write(0xB0, 0) }
     }
 
-    /// Enable timer with a specific value.
-    pub fn enable_timer(&mut self) {
+    /// Measure how many timer ticks happen per second, with the
+    /// divide configuration of 16 used by the timer. Uses the PIT as
+    /// the reference clock.
+    unsafe fn timer_frequency(&mut self) -> u64 {
+        self.write(0x3E0, 0x3);
+        // Masked, one-shot.
+        self.write(0x320, 1<<16);
+        self.write(0x380, 0xFFFFFFFF);
+        pit::sleep(10000);
+        let elapsed = 0xFFFFFFFF - self.read(0x390);
+        self.write(0x380, 0);
+
+        (elapsed as u64) * 100
+    }
+
+    /// Enable the periodic timer. If `hz` is given, the timer is
+    /// calibrated to fire at that frequency. Otherwise a fixed
+    /// initial count is used.
+    pub fn enable_timer(&mut self, hz: Option<u32>) {
         unsafe {
-            self.write(0x3E0, 0x3);
-            self.write(0x380, 0x10000);
-            self.write(0x320, (1<<17) | 0x40);
+            let initial_count = match hz {
+                Some(hz) => {
+                    let frequency = self.timer_frequency();
+                    log!("timer frequency is {} Hz", frequency);
+                    let count = frequency / (hz as u64);
+                    if count == 0 { 1 } else if count > 0xFFFFFFFF { 0xFFFFFFFF } else { count as u32 }
+                },
+                None => 0x10000,
+            };
+            TIMER_INITIAL_COUNT.store(initial_count as usize, Ordering::SeqCst);
+
+            self.start_timer(initial_count);
             log!("timer register is 0b{:b}", self.read(0x320));
         }
     }
 
+    /// Enable the periodic timer with the initial count of the last
+    /// `enable_timer`. Used by application processors, which cannot
+    /// use the PIT for calibration.
+    pub fn enable_calibrated_timer(&mut self) {
+        unsafe { self.start_timer(TIMER_INITIAL_COUNT.load(Ordering::SeqCst) as u32) }
+    }
+
+    /// Start the timer in periodic mode on the timer interrupt
+    /// vector.
+    unsafe fn start_timer(&mut self, initial_count: u32) {
+        self.write(0x3E0, 0x3);
+        self.write(0x380, initial_count);
+        self.write(0x320, (1<<17) | 0x40);
+    }
+
+    /// Send an inter-processor interrupt. `command` is the low word
+    /// of the interrupt command register, and `destination` the APIC
+    /// id of the target, ignored if `command` has a destination
+    /// shorthand. Waits until the interrupt is delivered.
+    unsafe fn send_ipi(&mut self, destination: u8, command: u32) {
+        self.write(0x310, (destination as u32) << 24);
+        self.write(0x300, command);
+        while self.read(0x300) & (1<<12) != 0 { }
+    }
+
+    /// Send a fixed interrupt with `vector` to the CPU with the given
+    /// APIC id.
+    pub fn send(&mut self, destination: u8, vector: u8) {
+        // No shorthand, level assert, physical destination, fixed.
+        unsafe { self.send_ipi(destination, (1<<14) | vector as u32) }
+    }
+
+    /// Send INIT to the CPU with the given APIC id.
+    pub fn send_init(&mut self, destination: u8) {
+        // No shorthand, level assert, INIT.
+        unsafe { self.send_ipi(destination, (1<<14) | (0b101<<8)) }
+    }
+
+    /// Send a startup IPI to the CPU with the given APIC id. It
+    /// starts in real mode at physical address `page * 0x1000`.
+    pub fn send_startup(&mut self, destination: u8, page: u8) {
+        // No shorthand, level assert, start up.
+        unsafe { self.send_ipi(destination, (1<<14) | (0b110<<8) | page as u32) }
+    }
+
+    /// Send INIT to all CPUs except the current one.
+    pub fn broadcast_init(&mut self) {
+        // All excluding self, level assert, INIT.
+        unsafe { self.send_ipi(0, (0b11<<18) | (1<<14) | (0b101<<8)) }
+    }
+
+    /// Send a startup IPI to all CPUs except the current one. They
+    /// start in real mode at physical address `page * 0x1000`.
+    pub fn broadcast_startup(&mut self, page: u8) {
+        // All excluding self, level assert, start up.
+        unsafe { self.send_ipi(0, (0b11<<18) | (1<<14) | (0b110<<8) | page as u32) }
+    }
+
     /// Current error status.
     pub fn error_status(&self) -> u32 {
         unsafe { self.read(0x280) }
@@ -127,12 +222,39 @@ impl IOAPIC {
         unsafe { self.read(0x2) }
     }
 
-    /// Set IRQ to an interrupt vector.
+    /// Set the first global system interrupt handled by the I/O
+    /// APIC, as given by the ACPI tables.
+    pub fn set_gsi_base(&mut self, gsi_base: u32) {
+        self.gsi_base = gsi_base;
+    }
+
+    /// Number of redirection entries, one for each global system
+    /// interrupt handled.
+    pub fn redirection_entries(&self) -> u32 {
+        ((self.version() >> 16) & 0xff) + 1
+    }
+
+    /// Set the ISA IRQ to an interrupt vector. The IRQ is routed
+    /// according to the ACPI interrupt source overrides.
     pub fn set_irq(&mut self, irq: u8, apic_id: u8, vector: InterruptVector) {
+        let route = ACPI.lock().isa_irq_route(irq);
+        self.set_gsi(route.gsi, apic_id, vector, route.active_low, route.level_triggered);
+    }
+
+    /// Set a global system interrupt to an interrupt vector, with
+    /// fixed delivery to the local APIC `apic_id`. Logs and does
+    /// nothing if the interrupt is not handled by this I/O APIC.
+    pub fn set_gsi(&mut self, gsi: u32, apic_id: u8, vector: InterruptVector,
+                   active_low: bool, level_triggered: bool) {
         let vector = vector as u8;
+        if gsi < self.gsi_base || gsi - self.gsi_base >= self.redirection_entries() {
+            log!("Global system interrupt {} is not handled by the I/O APIC.", gsi);
+            return;
+        }
+        let entry = gsi - self.gsi_base;
 
-        let low_index: u32 = 0x10 + (irq as u32) * 2;
-        let high_index: u32 = 0x10 + (irq as u32) * 2 + 1;
+        let low_index: u32 = 0x10 + entry * 2;
+        let high_index: u32 = 0x10 + entry * 2 + 1;
 
         let mut high = unsafe { self.read(high_index) };
         high &= !0xff000000;
@@ -141,9 +263,17 @@ impl IOAPIC {
 
         let mut low = unsafe { self.read(low_index) };
         low &= !(1<<16);
+        low &= !(1<<15);
+        low &= !(1<<13);
         low &= !(1<<11);
         low &= !0x700;
         low &= !0xff;
+        if active_low {
+            low |= 1<<13;
+        }
+        if level_triggered {
+            low |= 1<<15;
+        }
         low |= vector as u32;
         unsafe { self.write(low_index, low) };
     }
@@ -9,7 +9,6 @@ pub struct DescriptorTablePointer {
 }
 
 /// Load GDT table.
-#[allow(dead_code)]
 pub unsafe fn lgdt(gdt: &DescriptorTablePointer) {
     asm!("lgdt ($0)" :: "r" (gdt) : "memory");
 }
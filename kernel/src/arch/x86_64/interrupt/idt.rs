@@ -29,12 +29,14 @@ impl<'a> EntryOptions<'a> {
         EntryOptions(entry)
     }
 
-    /// Create a new entry with default settings.
+    /// Create a new entry with default settings. The entry does not
+    /// use the interrupt stack table, so that interrupts from user
+    /// space run on the kernel stack of the task, and interrupts of
+    /// kernel code on the current stack.
     fn new(entry: &'a mut Entry) -> Self {
         Self::minimal(entry)
             .set_present(true)
             .disable_interrupts(true)
-            .set_stack_index(0x1)
     }
 
     /// Set the entry to be present.
@@ -3,37 +3,130 @@ mod idt;
 /// Helpers for representing bit fields.
 mod bit_field;
 /// Functions and data-structures to load descriptor tables.
-mod dtables;
+pub mod dtables;
 /// Advanced Programmable Interrupt Controller.
 mod apic;
 /// Programmable Interrupt Controller.
 mod pic;
+/// Programmable Interval Timer, used for calibration and for delays
+/// during application processor startup.
+pub mod pit;
 
 /// Context switching related functionality.
 #[macro_use]
 mod switch;
 
 use common::*;
-use self::switch::{last_exception_return_value, switch_to_raw};
+use arch::{cpu, fpu, backtrace, MemoryObject};
+use self::switch::{last_exception_return_value, switch_to_raw, sysret_to_raw};
 
-pub use self::switch::{HandlerFunc, Registers};
+pub use self::switch::{HandlerFunc, Registers, ExceptionStackFrame, system_call_entry};
 pub use self::apic::{LOCAL_APIC, IO_APIC};
 pub use self::pic::{disable_pic};
 
 /// Interrupt vector type.
 pub type InterruptVector = u64;
 
+pub const DIVIDE_ERROR_INTERRUPT_CODE: InterruptVector = 0x00;
+pub const DEBUG_INTERRUPT_CODE: InterruptVector = 0x01;
+pub const BREAKPOINT_INTERRUPT_CODE: InterruptVector = 0x03;
+pub const OVERFLOW_INTERRUPT_CODE: InterruptVector = 0x04;
+pub const BOUND_RANGE_INTERRUPT_CODE: InterruptVector = 0x05;
+pub const INVALID_OPCODE_INTERRUPT_CODE: InterruptVector = 0x06;
+pub const INVALID_TSS_INTERRUPT_CODE: InterruptVector = 0x0A;
+pub const SEGMENT_NOT_PRESENT_INTERRUPT_CODE: InterruptVector = 0x0B;
+pub const STACK_SEGMENT_FAULT_INTERRUPT_CODE: InterruptVector = 0x0C;
+pub const GENERAL_PROTECTION_FAULT_INTERRUPT_CODE: InterruptVector = 0x0D;
+pub const X87_FLOATING_POINT_INTERRUPT_CODE: InterruptVector = 0x10;
+pub const ALIGNMENT_CHECK_INTERRUPT_CODE: InterruptVector = 0x11;
+pub const SIMD_FLOATING_POINT_INTERRUPT_CODE: InterruptVector = 0x13;
+pub const VIRTUALIZATION_INTERRUPT_CODE: InterruptVector = 0x14;
+pub const CONTROL_PROTECTION_INTERRUPT_CODE: InterruptVector = 0x15;
+/// Exception vectors below this one are reserved for CPU exceptions.
+pub const FIRST_EXTERNAL_INTERRUPT_CODE: InterruptVector = 0x20;
+/// Non-maskable interrupt. It can arrive at any time, even in the
+/// middle of an interrupt handler, so it runs on its own stack.
+pub const NMI_INTERRUPT_CODE: InterruptVector = 0x02;
+/// Raised when an exception cannot be delivered, for example when a
+/// page fault hits the kernel stack guard page. It runs on its own
+/// stack, as the current one may have overflowed.
+pub const DOUBLE_FAULT_INTERRUPT_CODE: InterruptVector = 0x08;
+/// Raised by FPU instructions while `CR0.TS` is set, so that the FPU
+/// state of the task can be restored lazily.
+pub const DEVICE_NOT_AVAILABLE_INTERRUPT_CODE: InterruptVector = 0x07;
+pub const PAGE_FAULT_INTERRUPT_CODE: InterruptVector = 0x0E;
 pub const TIMER_INTERRUPT_CODE: InterruptVector = 0x40;
+/// Inter-processor interrupt waking up an idle CPU.
+pub const WAKEUP_INTERRUPT_CODE: InterruptVector = 0x41;
+/// Inter-processor interrupt making a CPU return to the kernel, so
+/// that its TLB is flushed before it runs user code again.
+pub const TLB_SHOOTDOWN_INTERRUPT_CODE: InterruptVector = 0x42;
 pub const SPURIOUS_INTERRUPT_CODE: InterruptVector = 0xFF;
 pub const KEYBOARD_INTERRUPT_CODE: InterruptVector = 0x21;
 pub const SYSTEM_CALL_INTERRUPT_CODE: InterruptVector = 0x80;
 pub const DEBUG_CALL_INTERRUPT_CODE: InterruptVector = 0x81;
+/// Exception code of a system call made with the `syscall`
+/// instruction. It is not an interrupt vector.
+pub const SYSCALL_INSTRUCTION_CODE: u64 = 0x100;
 
+/// Handler of non-maskable interrupts. They are ignored.
+#[naked]
+unsafe extern "C" fn nmi_handler() {
+    asm!("iretq" :::: "volatile");
+}
+
+/// Handler of double faults. Swaps in the kernel `gs` base if the
+/// fault came from user-mode, and reports the fault with the
+/// exception frame above the error code.
+#[naked]
+unsafe extern "C" fn double_fault_handler() {
+    asm!("test qword ptr [rsp + 16], 3
+          jz 1f
+          swapgs
+       1:
+          lea rdi, [rsp + 8]
+          call $0"
+         :: "i"(double_fault as unsafe extern "C" fn(*const ExceptionStackFrame) -> !)
+         :: "volatile", "intel");
+}
+
+/// Report a double fault on the serial console, and halt the CPU.
+unsafe extern "C" fn double_fault(frame: *const ExceptionStackFrame) -> ! {
+    let frame = &*frame;
+    ::logging::force_unlock();
+    error!("Double fault on CPU {}: rip = 0x{:x}, rsp = 0x{:x}, cr2 = 0x{:x}",
+           cpu::current_index(), frame.instruction_pointer, frame.stack_pointer, cr2());
+    backtrace::print_current_task();
+    backtrace::print_backtrace();
+    loop {
+        asm!("cli; hlt" :::: "volatile");
+    }
+}
+
+return_to_raw_fn!(divide_error_return_to_raw, DIVIDE_ERROR_INTERRUPT_CODE);
+return_to_raw_fn!(debug_return_to_raw, DEBUG_INTERRUPT_CODE);
+return_to_raw_fn!(breakpoint_return_to_raw, BREAKPOINT_INTERRUPT_CODE);
+return_to_raw_fn!(overflow_return_to_raw, OVERFLOW_INTERRUPT_CODE);
+return_to_raw_fn!(bound_range_return_to_raw, BOUND_RANGE_INTERRUPT_CODE);
+return_to_raw_fn!(invalid_opcode_return_to_raw, INVALID_OPCODE_INTERRUPT_CODE);
+return_to_raw_fn!(x87_floating_point_return_to_raw, X87_FLOATING_POINT_INTERRUPT_CODE);
+return_to_raw_fn!(simd_floating_point_return_to_raw, SIMD_FLOATING_POINT_INTERRUPT_CODE);
+return_to_raw_fn!(virtualization_return_to_raw, VIRTUALIZATION_INTERRUPT_CODE);
+return_to_raw_fn!(device_not_available_return_to_raw, DEVICE_NOT_AVAILABLE_INTERRUPT_CODE);
 return_to_raw_fn!(timer_return_to_raw, TIMER_INTERRUPT_CODE);
+return_to_raw_fn!(wakeup_return_to_raw, WAKEUP_INTERRUPT_CODE);
+return_to_raw_fn!(tlb_shootdown_return_to_raw, TLB_SHOOTDOWN_INTERRUPT_CODE);
 return_to_raw_fn!(spurious_return_to_raw, SPURIOUS_INTERRUPT_CODE);
 return_to_raw_fn!(keyboard_return_to_raw, KEYBOARD_INTERRUPT_CODE);
 return_to_raw_fn!(system_call_return_to_raw, SYSTEM_CALL_INTERRUPT_CODE);
 return_to_raw_fn!(debug_call_return_to_raw, DEBUG_CALL_INTERRUPT_CODE);
+return_error_to_raw_fn!(page_fault_return_to_raw, PAGE_FAULT_INTERRUPT_CODE);
+return_error_to_raw_fn!(invalid_tss_return_to_raw, INVALID_TSS_INTERRUPT_CODE);
+return_error_to_raw_fn!(segment_not_present_return_to_raw, SEGMENT_NOT_PRESENT_INTERRUPT_CODE);
+return_error_to_raw_fn!(stack_segment_fault_return_to_raw, STACK_SEGMENT_FAULT_INTERRUPT_CODE);
+return_error_to_raw_fn!(general_protection_fault_return_to_raw, GENERAL_PROTECTION_FAULT_INTERRUPT_CODE);
+return_error_to_raw_fn!(alignment_check_return_to_raw, ALIGNMENT_CHECK_INTERRUPT_CODE);
+return_error_to_raw_fn!(control_protection_return_to_raw, CONTROL_PROTECTION_INTERRUPT_CODE);
 
 lazy_static! {
     /// The interrupt descriptor table static.
@@ -50,6 +143,31 @@ lazy_static! {
             .set_privilege_level(0x3);
         idt.set_handler(TIMER_INTERRUPT_CODE, timer_return_to_raw)
             .set_privilege_level(0x3);
+        idt.set_handler(WAKEUP_INTERRUPT_CODE, wakeup_return_to_raw);
+        idt.set_handler(TLB_SHOOTDOWN_INTERRUPT_CODE, tlb_shootdown_return_to_raw);
+        idt.set_handler(PAGE_FAULT_INTERRUPT_CODE, page_fault_return_to_raw);
+        idt.set_handler(DEVICE_NOT_AVAILABLE_INTERRUPT_CODE, device_not_available_return_to_raw);
+        idt.set_handler(DIVIDE_ERROR_INTERRUPT_CODE, divide_error_return_to_raw);
+        idt.set_handler(DEBUG_INTERRUPT_CODE, debug_return_to_raw);
+        idt.set_handler(BREAKPOINT_INTERRUPT_CODE, breakpoint_return_to_raw)
+            .set_privilege_level(0x3);
+        idt.set_handler(OVERFLOW_INTERRUPT_CODE, overflow_return_to_raw)
+            .set_privilege_level(0x3);
+        idt.set_handler(BOUND_RANGE_INTERRUPT_CODE, bound_range_return_to_raw);
+        idt.set_handler(INVALID_OPCODE_INTERRUPT_CODE, invalid_opcode_return_to_raw);
+        idt.set_handler(INVALID_TSS_INTERRUPT_CODE, invalid_tss_return_to_raw);
+        idt.set_handler(SEGMENT_NOT_PRESENT_INTERRUPT_CODE, segment_not_present_return_to_raw);
+        idt.set_handler(STACK_SEGMENT_FAULT_INTERRUPT_CODE, stack_segment_fault_return_to_raw);
+        idt.set_handler(GENERAL_PROTECTION_FAULT_INTERRUPT_CODE, general_protection_fault_return_to_raw);
+        idt.set_handler(X87_FLOATING_POINT_INTERRUPT_CODE, x87_floating_point_return_to_raw);
+        idt.set_handler(ALIGNMENT_CHECK_INTERRUPT_CODE, alignment_check_return_to_raw);
+        idt.set_handler(SIMD_FLOATING_POINT_INTERRUPT_CODE, simd_floating_point_return_to_raw);
+        idt.set_handler(VIRTUALIZATION_INTERRUPT_CODE, virtualization_return_to_raw);
+        idt.set_handler(CONTROL_PROTECTION_INTERRUPT_CODE, control_protection_return_to_raw);
+        idt.set_handler(NMI_INTERRUPT_CODE, nmi_handler)
+            .set_stack_index(cpu::NMI_IST_INDEX);
+        idt.set_handler(DOUBLE_FAULT_INTERRUPT_CODE, double_fault_handler)
+            .set_stack_index(cpu::DOUBLE_FAULT_IST_INDEX);
 
         idt
     };
@@ -63,40 +181,123 @@ pub enum Exception {
     DebugCall,
     Keyboard,
     Spurious,
-    Timer
+    Timer,
+    /// Wakeup sent by another CPU.
+    Wakeup,
+    /// TLB shootdown sent by another CPU.
+    TlbShootdown,
+    /// FPU instruction executed by a task without an FPU area.
+    DeviceNotAvailable,
+    /// System call made with `syscall`, with the number in `rdi`
+    /// and the arguments in `rsi`, `rdx`, `r10` and `r8`. Number 0
+    /// is reported as `SystemCall` instead, with the call in the task
+    /// buffer.
+    RegisterSystemCall {
+        number: u64,
+        args: [u64; 4],
+    },
+    /// Page fault at `vaddr`. `present` is set if the page was
+    /// present and the access violated its rights, and `write` if
+    /// the access was a write.
+    PageFault {
+        vaddr: VAddr,
+        present: bool,
+        write: bool,
+    },
+    /// Any other CPU exception raised by the task, such as a general
+    /// protection fault or an invalid opcode, with its vector and
+    /// error code.
+    Fault {
+        vector: InterruptVector,
+        error_code: Option<u64>,
+    },
 }
 
 impl Exception {
     /// Create a new Exception using an exception code and an optional
     /// error code.
-    fn new(code: u64, _error: Option<u64>) -> Exception {
+    fn new(code: u64, error: Option<u64>) -> Exception {
         match code {
+            PAGE_FAULT_INTERRUPT_CODE => {
+                let error = error.unwrap();
+                Exception::PageFault {
+                    vaddr: VAddr::from(unsafe { cr2() }),
+                    present: error & 0b1 != 0,
+                    write: error & 0b10 != 0,
+                }
+            },
+            DEVICE_NOT_AVAILABLE_INTERRUPT_CODE => Exception::DeviceNotAvailable,
             TIMER_INTERRUPT_CODE => Exception::Timer,
+            WAKEUP_INTERRUPT_CODE => Exception::Wakeup,
+            TLB_SHOOTDOWN_INTERRUPT_CODE => Exception::TlbShootdown,
             SPURIOUS_INTERRUPT_CODE => Exception::Spurious,
             KEYBOARD_INTERRUPT_CODE => Exception::Keyboard,
             SYSTEM_CALL_INTERRUPT_CODE => Exception::SystemCall,
             DEBUG_CALL_INTERRUPT_CODE => Exception::DebugCall,
+            code if code < FIRST_EXTERNAL_INTERRUPT_CODE => Exception::Fault {
+                vector: code,
+                error_code: error,
+            },
             _ => panic!(),
         }
     }
 
+    /// Create a new Exception for a system call made with `syscall`,
+    /// using the saved user registers.
+    fn from_registers(registers: &Registers) -> Exception {
+        if registers.rdi == 0 {
+            Exception::SystemCall
+        } else {
+            Exception::RegisterSystemCall {
+                number: registers.rdi,
+                args: [registers.rsi, registers.rdx, registers.r10, registers.r8],
+            }
+        }
+    }
+
     /// Send End of Interrupt signal if appropriate.
     pub unsafe fn send_eoi(&self) {
         match self {
             &Exception::Timer => LOCAL_APIC.lock().eoi(),
             &Exception::Keyboard => LOCAL_APIC.lock().eoi(),
+            &Exception::Wakeup => LOCAL_APIC.lock().eoi(),
+            &Exception::TlbShootdown => LOCAL_APIC.lock().eoi(),
             _ => (),
         }
     }
 }
 
+/// Contains the address that caused the last page fault.
+pub unsafe fn cr2() -> u64 {
+    let ret: u64;
+    asm!("mov %cr2, $0" : "=r" (ret));
+    ret
+}
+
 /// Represents a task runtime. Used by the task capability.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct TaskRuntime {
     instruction_pointer: u64,
     cpu_flags: u64,
     stack_pointer: u64,
-    registers: Registers
+    registers: Registers,
+    /// Whether the task last entered the kernel with `syscall`, so
+    /// that it can return with `sysret`.
+    sysret: bool,
+    /// Physical address of the area the FPU state of the task is
+    /// saved to, of `FPU_AREA_LENGTH` bytes. Tasks without an area
+    /// cannot use the FPU.
+    fpu_area: Option<PAddr>,
+    /// Index of the CPU the task last ran on.
+    last_cpu: Option<usize>,
+    /// FS base of the task, used for thread-local storage.
+    fs_base: u64,
+    /// GS base of the task, used for thread-local storage.
+    gs_base: u64,
+    /// Physical address of the kernel stack of the task, of
+    /// `KERNEL_STACK_LENGTH` bytes. The CPU switches to it when the
+    /// task enters the kernel from user-mode.
+    kernel_stack: Option<PAddr>,
 }
 
 impl Default for TaskRuntime {
@@ -106,6 +307,12 @@ impl Default for TaskRuntime {
             cpu_flags: 0b11001000000110,
             stack_pointer: 0x0,
             registers: Registers::default(),
+            sysret: false,
+            fpu_area: None,
+            last_cpu: None,
+            fs_base: 0,
+            gs_base: 0,
+            kernel_stack: None,
         }
     }
 }
@@ -118,23 +325,91 @@ impl TaskRuntime {
     /// `TaskRuntime` must have all values valid. `mode_change` must
     /// be set according to the task capability.
     pub unsafe fn switch_to(&mut self, mode_change: bool) -> Exception {
+        // An interrupt arrived while the kernel was running. Handle
+        // it before running the task.
+        if let Some(code) = cpu::current().pending_interrupt.take() {
+            let exception = Exception::new(code, None);
+            exception.send_eoi();
+            return exception;
+        }
+
         let code_seg: u64 = if mode_change { 0x28 | 0x3 } else { 0x8 | 0x0 };
         let data_seg: u64 = if mode_change { 0x30 | 0x3 } else { 0x10 | 0x0 };
 
-        switch::set_cur_registers(self.registers.clone());
-        switch_to_raw(self.stack_pointer, self.instruction_pointer, self.cpu_flags, code_seg, data_seg);
-        self.registers = switch::cur_registers();
+        let current_cpu = cpu::current_index();
+        fpu::enter(self.fpu_area, self.last_cpu == Some(current_cpu));
+        self.last_cpu = Some(current_cpu);
+
+        // Kept mapped while the task runs, with a guard page below
+        // it.
+        let kernel_stack = if mode_change {
+            let stack = MemoryObject::<u8>::guarded_slice(
+                self.kernel_stack.expect("User task has no kernel stack."), cpu::KERNEL_STACK_LENGTH);
+            cpu::current().set_kernel_stack(stack.as_ptr() as u64 + cpu::KERNEL_STACK_LENGTH as u64);
+            Some(stack)
+        } else {
+            None
+        };
+
+        loop {
+            switch::set_cur_registers(self.registers.clone());
+            if mode_change {
+                cpu::set_user_segment_bases(self.fs_base, self.gs_base);
+                cpu::enter_user();
+            }
+            cpu::current().in_task = true;
+            // `sysret` faults in ring 0 if the return address is not
+            // canonical, so such tasks go through `iretq` instead.
+            if mode_change && self.sysret && self.instruction_pointer < 0x0000800000000000 {
+                sysret_to_raw(self.stack_pointer, self.instruction_pointer, self.cpu_flags);
+            } else {
+                switch_to_raw(self.stack_pointer, self.instruction_pointer, self.cpu_flags, code_seg, data_seg);
+            }
+            if mode_change {
+                cpu::leave_user();
+            }
+            self.registers = switch::cur_registers();
+
+            let exception_info = last_exception_return_value().unwrap();
 
-        let exception_info = last_exception_return_value().unwrap();
+            self.instruction_pointer = exception_info.instruction_pointer;
+            self.cpu_flags = exception_info.cpu_flags;
+            self.stack_pointer = exception_info.stack_pointer;
+            self.sysret = exception_info.exception_code == SYSCALL_INSTRUCTION_CODE;
 
-        self.instruction_pointer = exception_info.instruction_pointer;
-        self.cpu_flags = exception_info.cpu_flags;
-        self.stack_pointer = exception_info.stack_pointer;
+            // The task used the FPU for the first time since it was
+            // switched to. Restore its state and retry the
+            // instruction.
+            if exception_info.exception_code == DEVICE_NOT_AVAILABLE_INTERRUPT_CODE {
+                if let Some(fpu_area) = self.fpu_area {
+                    fpu::device_not_available(fpu_area);
+                    continue;
+                }
+            }
+            fpu::leave(self.fpu_area);
+
+            let exception = if self.sysret {
+                Exception::from_registers(&self.registers)
+            } else {
+                Exception::new(exception_info.exception_code, exception_info.error_code)
+            };
+            exception.send_eoi();
+
+            return exception;
+        }
+    }
 
-        let exception = Exception::new(exception_info.exception_code, exception_info.error_code);
-        exception.send_eoi();
+    /// Set the kernel stack of the task. It must be
+    /// `KERNEL_STACK_LENGTH` bytes long.
+    pub fn set_kernel_stack(&mut self, kernel_stack: PAddr) {
+        self.kernel_stack = Some(kernel_stack);
+    }
 
-        return exception;
+    /// Set the area the FPU state of the task is saved to. The area
+    /// must have been initialized with `fpu::init_area`.
+    pub fn set_fpu_area(&mut self, fpu_area: PAddr) {
+        self.fpu_area = Some(fpu_area);
+        self.last_cpu = None;
     }
 
     /// Set the instruction pointer of the task runtime.
@@ -146,11 +421,34 @@ impl TaskRuntime {
     pub fn set_stack_pointer(&mut self, stack_pointer: VAddr) {
         self.stack_pointer = stack_pointer.into();
     }
+
+    /// Set the FS and GS base of the task runtime. They are loaded
+    /// every time the task is switched to. Returns `None` if a base
+    /// is not a lower-half canonical address, as loading it would
+    /// fault in the kernel.
+    pub fn set_tls_base(&mut self, fs_base: u64, gs_base: u64) -> Option<()> {
+        if fs_base >= 0x0000800000000000 || gs_base >= 0x0000800000000000 {
+            return None;
+        }
+        self.fs_base = fs_base;
+        self.gs_base = gs_base;
+        Some(())
+    }
+
+    /// Set the value returned to the task in `rax`.
+    pub fn set_return_value(&mut self, value: u64) {
+        self.registers.rax = value;
+    }
 }
 
-/// Enable interrupt. Not used.
-pub unsafe fn enable_interrupt() { }
-/// Disable interrupt. Not used.
-pub unsafe fn disable_interrupt() { }
+/// Enable interrupts in the kernel. Interrupts arriving while the
+/// kernel runs are left in `pending_interrupt`.
+pub unsafe fn enable_interrupt() {
+    asm!("sti" :::: "volatile");
+}
+/// Disable interrupts in the kernel.
+pub unsafe fn disable_interrupt() {
+    asm!("cli" :::: "volatile");
+}
 /// Set interrupt handler. Not used.
 pub unsafe fn set_interrupt_handler() { }
@@ -0,0 +1,36 @@
+use arch::{inportb, outportb};
+
+const PIT_CHANNEL2_DATA: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+const PIT_CHANNEL2_GATE: u16 = 0x61;
+
+/// Input clock frequency of the PIT in Hz.
+pub const PIT_FREQUENCY: u64 = 1193182;
+
+/// Busy-wait for the given number of microseconds, using PIT channel
+/// 2 in one-shot mode. Channel 2 is not connected to any interrupt,
+/// so this can be used before interrupts are set up.
+pub unsafe fn sleep(micros: u64) {
+    let mut ticks = PIT_FREQUENCY * micros / 1000000;
+
+    while ticks > 0 {
+        let count = if ticks > 0xffff { 0xffff } else { ticks };
+        ticks -= count;
+
+        // Disable the gate and the speaker output.
+        let gate = inportb(PIT_CHANNEL2_GATE) & !0x3;
+        outportb(PIT_CHANNEL2_GATE, gate);
+
+        // Channel 2, low byte then high byte, mode 0 (interrupt on
+        // terminal count), binary.
+        outportb(PIT_COMMAND, 0b10110000);
+        outportb(PIT_CHANNEL2_DATA, count as u8);
+        outportb(PIT_CHANNEL2_DATA, (count >> 8) as u8);
+
+        // Start counting, and wait for the output to go high.
+        outportb(PIT_CHANNEL2_GATE, gate | 0x1);
+        while inportb(PIT_CHANNEL2_GATE) & 0x20 == 0 { }
+
+        outportb(PIT_CHANNEL2_GATE, gate);
+    }
+}
@@ -1,9 +1,12 @@
-use arch::init;
+use arch::cpu;
+use arch::cpu::{CPU_RSP_AFTER_SAVING_REGISTERS, CPU_SYSRET_STACK_POINTER,
+                CPU_RAX, CPU_RBX, CPU_RCX, CPU_RDX, CPU_RSI, CPU_RDI, CPU_RBP,
+                CPU_R8, CPU_R9, CPU_R10, CPU_R11, CPU_R12, CPU_R13, CPU_R14, CPU_R15};
 
 /// Interrupt handler function type.
 pub type HandlerFunc = unsafe extern "C" fn();
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy)]
 #[repr(C)]
 pub struct ExceptionStackFrame {
     pub instruction_pointer: u64,
@@ -24,7 +27,8 @@ pub struct ExceptionInfo {
     pub exception_code: u64
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
 pub struct Registers {
     pub rax: u64,
     pub rbx: u64,
@@ -53,16 +57,13 @@ impl Default for Registers {
     }
 }
 
-pub static mut RSP_AFTER_SAVING_REGISTERS: u64 = 0;
-
-unsafe extern "C" fn set_kernel_stack(addr: u64) {
-    init::set_kernel_stack(addr);
-}
-
 pub unsafe fn switch_to_raw(stack_vaddr: u64, code_start: u64, cpu_flags: u64, code_seg: u64, data_seg: u64) {
     switch_to_raw_naked(stack_vaddr, code_start, cpu_flags, code_seg, data_seg);
 }
 
+/// Switch to a task with `iretq`. The registers of the task are read
+/// from the per-CPU data through `gs`, and the kernel `gs` base is
+/// swapped out if the task runs in user-mode.
 #[naked]
 #[inline(never)]
 pub unsafe extern "C" fn switch_to_raw_naked(stack_vaddr: u64, code_start: u64, cpu_flags: u64, code_seg: u64, data_seg: u64) {
@@ -83,7 +84,7 @@ pub unsafe extern "C" fn switch_to_raw_naked(stack_vaddr: u64, code_start: u64,
        push r13
        push r14
        push r15
-       mov [$1], rsp
+       mov gs:[$0], rsp
 
        push r8 /* data seg */
        push rdi /* stack vaddr */
@@ -91,46 +92,46 @@ pub unsafe extern "C" fn switch_to_raw_naked(stack_vaddr: u64, code_start: u64,
        push rcx /* code seg */
        push rsi /* code start */
 
-       mov rdi, rsp
-       call $0
-
-       mov rax, [$2]
-       mov rbx, [$3]
-       mov rcx, [$4]
-       mov rdx, [$5]
-       mov rsi, [$6]
-       mov rdi, [$7]
-       mov r8, [$8]
-       mov r9, [$9]
-       mov r10, [$10]
-       mov r11, [$11]
-       mov r12, [$12]
-       mov r13, [$13]
-       mov r14, [$14]
-       mov r15, [$15]
-       mov rbp, [$16]
-
+       mov rax, gs:[$1]
+       mov rbx, gs:[$2]
+       mov rcx, gs:[$3]
+       mov rdx, gs:[$4]
+       mov rsi, gs:[$5]
+       mov rdi, gs:[$6]
+       mov r8, gs:[$7]
+       mov r9, gs:[$8]
+       mov r10, gs:[$9]
+       mov r11, gs:[$10]
+       mov r12, gs:[$11]
+       mov r13, gs:[$12]
+       mov r14, gs:[$13]
+       mov r15, gs:[$14]
+       mov rbp, gs:[$15]
+
+       test qword ptr [rsp + 8], 3
+       jz 1f
+       swapgs
+    1:
        iretq
     "
     ::
-         "i"(set_kernel_stack as unsafe extern "C" fn(u64)),
-         "i"(&RSP_AFTER_SAVING_REGISTERS),
-
-         "i"(&CUR_REGISTERS.rax),
-         "i"(&CUR_REGISTERS.rbx),
-         "i"(&CUR_REGISTERS.rcx),
-         "i"(&CUR_REGISTERS.rdx),
-         "i"(&CUR_REGISTERS.rsi),
-         "i"(&CUR_REGISTERS.rdi),
-         "i"(&CUR_REGISTERS.r8),
-         "i"(&CUR_REGISTERS.r9),
-         "i"(&CUR_REGISTERS.r10),
-         "i"(&CUR_REGISTERS.r11),
-         "i"(&CUR_REGISTERS.r12),
-         "i"(&CUR_REGISTERS.r13),
-         "i"(&CUR_REGISTERS.r14),
-         "i"(&CUR_REGISTERS.r15),
-         "i"(&CUR_REGISTERS.rbp),
+         "i"(CPU_RSP_AFTER_SAVING_REGISTERS),
+
+         "i"(CPU_RAX),
+         "i"(CPU_RBX),
+         "i"(CPU_RCX),
+         "i"(CPU_RDX),
+         "i"(CPU_RSI),
+         "i"(CPU_RDI),
+         "i"(CPU_R8),
+         "i"(CPU_R9),
+         "i"(CPU_R10),
+         "i"(CPU_R11),
+         "i"(CPU_R12),
+         "i"(CPU_R13),
+         "i"(CPU_R14),
+         "i"(CPU_R15),
+         "i"(CPU_RBP),
 
          "{r8}"(data_seg),
          "{rdi}"(stack_vaddr),
@@ -141,35 +142,235 @@ pub unsafe extern "C" fn switch_to_raw_naked(stack_vaddr: u64, code_start: u64,
     "volatile", "intel");
 }
 
-static mut CUR_EXCEPTION_STACK_FRAME: Option<ExceptionStackFrame> = None;
-static mut CUR_EXCEPTION_ERROR_CODE: Option<u64> = None;
-static mut CUR_EXCEPTION_CODE: Option<u64> = None;
-pub static mut CUR_REGISTERS: Registers = Registers {
-    rax: 0, rbx: 0, rcx: 0, rdx: 0, rsi: 0, rdi: 0,
-    r8: 0, r9: 0, r10: 0, r11: 0, r12: 0, r13: 0, r14: 0, r15: 0, rbp: 0
-};
+pub unsafe fn sysret_to_raw(stack_vaddr: u64, code_start: u64, cpu_flags: u64) {
+    sysret_to_raw_naked(stack_vaddr, code_start, cpu_flags);
+}
+
+/// Like `switch_to_raw_naked`, but return to user-space with
+/// `sysret`. Only used for tasks that entered the kernel through
+/// `syscall`, with `code_start` in canonical form.
+#[naked]
+#[inline(never)]
+pub unsafe extern "C" fn sysret_to_raw_naked(stack_vaddr: u64, code_start: u64, cpu_flags: u64) {
+    asm!("
+       /* save registers */
+       push rax
+       push rbx
+       push rcx
+       push rdx
+       push rbp
+       push rsi
+       push rdi
+       push r8
+       push r9
+       push r10
+       push r11
+       push r12
+       push r13
+       push r14
+       push r15
+       mov gs:[$0], rsp
+       mov gs:[$1], rdi /* stack vaddr */
+
+       mov r11, rdx /* cpu flags */
+       mov rcx, rsi /* code start */
+
+       mov rax, gs:[$2]
+       mov rbx, gs:[$3]
+       mov rdx, gs:[$4]
+       mov rsi, gs:[$5]
+       mov rdi, gs:[$6]
+       mov r8, gs:[$7]
+       mov r9, gs:[$8]
+       mov r10, gs:[$9]
+       mov r12, gs:[$10]
+       mov r13, gs:[$11]
+       mov r14, gs:[$12]
+       mov r15, gs:[$13]
+       mov rbp, gs:[$14]
+
+       mov rsp, gs:[$1]
+       swapgs
+       sysretq
+    "
+    ::
+         "i"(CPU_RSP_AFTER_SAVING_REGISTERS),
+         "i"(CPU_SYSRET_STACK_POINTER),
+
+         "i"(CPU_RAX),
+         "i"(CPU_RBX),
+         "i"(CPU_RDX),
+         "i"(CPU_RSI),
+         "i"(CPU_RDI),
+         "i"(CPU_R8),
+         "i"(CPU_R9),
+         "i"(CPU_R10),
+         "i"(CPU_R12),
+         "i"(CPU_R13),
+         "i"(CPU_R14),
+         "i"(CPU_R15),
+         "i"(CPU_RBP),
+
+         "{rdi}"(stack_vaddr),
+         "{rsi}"(code_start),
+         "{rdx}"(cpu_flags)
+    ::
+    "volatile", "intel");
+}
+
+/// Entry point of the `syscall` instruction. `syscall` does not
+/// switch stacks, so the kernel `gs` base is swapped in and the user
+/// registers are saved first, and then the kernel stack saved by the
+/// switch functions is restored, the same way as in
+/// `return_to_raw_fn!`. The user instruction pointer and flags are in
+/// `rcx` and `r11`.
+#[naked]
+#[inline(never)]
+pub unsafe extern "C" fn system_call_entry() {
+    asm!("swapgs
+          mov gs:[$2], rax
+          mov gs:[$3], rbx
+          mov gs:[$4], rcx
+          mov gs:[$5], rdx
+          mov gs:[$6], rsi
+          mov gs:[$7], rdi
+          mov gs:[$8], r8
+          mov gs:[$9], r9
+          mov gs:[$10], r10
+          mov gs:[$11], r11
+          mov gs:[$12], r12
+          mov gs:[$13], r13
+          mov gs:[$14], r14
+          mov gs:[$15], r15
+          mov gs:[$16], rbp
+
+          mov rdi, rcx
+          mov rsi, r11
+          mov rdx, rsp
+          mov rsp, gs:[$1]
+          call $0
+
+          pop r15
+          pop r14
+          pop r13
+          pop r12
+          pop r11
+          pop r10
+          pop r9
+          pop r8
+          pop rdi
+          pop rsi
+          pop rbp
+          pop rdx
+          pop rcx
+          pop rbx
+          pop rax"
+         ::
+
+         "i"(store_system_call_stack as unsafe extern "C" fn(u64, u64, u64)),
+         "i"(CPU_RSP_AFTER_SAVING_REGISTERS),
+
+         "i"(CPU_RAX),
+         "i"(CPU_RBX),
+         "i"(CPU_RCX),
+         "i"(CPU_RDX),
+         "i"(CPU_RSI),
+         "i"(CPU_RDI),
+         "i"(CPU_R8),
+         "i"(CPU_R9),
+         "i"(CPU_R10),
+         "i"(CPU_R11),
+         "i"(CPU_R12),
+         "i"(CPU_R13),
+         "i"(CPU_R14),
+         "i"(CPU_R15),
+         "i"(CPU_RBP)
+         :: "volatile", "intel");
+}
 
 pub unsafe fn set_cur_registers(registers: Registers) {
-    CUR_REGISTERS = registers;
+    cpu::current().registers = registers;
 }
 
 pub unsafe fn cur_registers() -> Registers {
-    CUR_REGISTERS.clone()
+    cpu::current().registers
+}
+
+/// Panic if an exception interrupted the kernel itself rather than
+/// a task. Hardware interrupts taken in the kernel are handled by
+/// `interrupted_kernel`, and NMIs have their own handler, so this is
+/// always a fault in the kernel.
+unsafe fn check_kernel_fault(exception: &ExceptionStackFrame, exception_code: u64) {
+    if exception.code_segment & 0x3 != 0 || cpu::current().in_task {
+        return;
+    }
+
+    if exception_code == ::arch::interrupt::PAGE_FAULT_INTERRUPT_CODE {
+        panic!("page fault in kernel at 0x{:x}, address 0x{:x}",
+               exception.instruction_pointer, ::arch::interrupt::cr2());
+    }
+    panic!("exception 0x{:x} in kernel at 0x{:x}", exception_code, exception.instruction_pointer);
 }
 
+/// Handle an exception taken in ring 0, with the registers of the
+/// interrupted code saved on its stack. Hardware interrupts arriving
+/// while the kernel itself runs, for example during a long system
+/// call, are left for the scheduler in `pending_interrupt`, and the
+/// kernel resumes with interrupts disabled. Returns 1 if a
+/// kernel-mode task was interrupted instead, and the exception
+/// should be recorded as usual, or 0 to resume the kernel.
+pub unsafe extern "C" fn interrupted_kernel(exception_raw: *mut ExceptionStackFrame, exception_code: u64) -> u64 {
+    let cpu = cpu::current();
+    if cpu.in_task {
+        return 1;
+    }
+
+    if exception_code < ::arch::interrupt::FIRST_EXTERNAL_INTERRUPT_CODE {
+        check_kernel_fault(&*exception_raw, exception_code);
+    }
+    cpu.pending_interrupt = Some(exception_code);
+    (*exception_raw).cpu_flags &= !(1 << 9);
+    0
+}
+
+/// Record an exception, to be handled after returning to the
+/// scheduler.
 pub unsafe extern "C" fn store_exception_stack(exception_raw: *const ExceptionStackFrame, exception_code: u64) {
     let exception = &*exception_raw;
-    CUR_EXCEPTION_STACK_FRAME = Some(exception.clone());
-    CUR_EXCEPTION_ERROR_CODE = None;
-    CUR_EXCEPTION_CODE = Some(exception_code);
+    let cpu = cpu::current();
+    cpu.in_task = false;
+    cpu.exception_stack_frame = Some(exception.clone());
+    cpu.exception_error_code = None;
+    cpu.exception_code = Some(exception_code);
+}
+
+/// Record a `syscall` entry the same way as an exception, with the
+/// user segments `sysret` returns to.
+pub unsafe extern "C" fn store_system_call_stack(instruction_pointer: u64, cpu_flags: u64, stack_pointer: u64) {
+    let cpu = cpu::current();
+    cpu.in_task = false;
+    cpu.exception_stack_frame = Some(ExceptionStackFrame {
+        instruction_pointer: instruction_pointer,
+        code_segment: 0x28 | 0x3,
+        cpu_flags: cpu_flags,
+        stack_pointer: stack_pointer,
+        stack_segment: 0x20 | 0x3,
+    });
+    cpu.exception_error_code = None;
+    cpu.exception_code = Some(::arch::interrupt::SYSCALL_INSTRUCTION_CODE);
 }
 
-#[allow(dead_code)]
+/// Record an exception with an error code, like
+/// `store_exception_stack`.
 pub unsafe extern "C" fn store_error_exception_stack(exception_raw: *const ExceptionStackFrame, error_code: u64, exception_code: u64) {
     let exception = &*exception_raw;
-    CUR_EXCEPTION_STACK_FRAME = Some(exception.clone());
-    CUR_EXCEPTION_ERROR_CODE = Some(error_code);
-    CUR_EXCEPTION_CODE = Some(exception_code);
+    check_kernel_fault(exception, exception_code);
+
+    let cpu = cpu::current();
+    cpu.in_task = false;
+    cpu.exception_stack_frame = Some(exception.clone());
+    cpu.exception_error_code = Some(error_code);
+    cpu.exception_code = Some(exception_code);
 }
 
 macro_rules! return_to_raw_fn {
@@ -177,30 +378,64 @@ macro_rules! return_to_raw_fn {
         #[naked]
         #[inline(never)]
         pub unsafe extern "C" fn $name() {
-            use ::arch::interrupt::switch::{RSP_AFTER_SAVING_REGISTERS, CUR_REGISTERS};
-
-            asm!("mov [$2], rax
-                  mov [$3], rbx
-                  mov [$4], rcx
-                  mov [$5], rdx
-                  mov [$6], rsi
-                  mov [$7], rdi
-                  mov [$8], r8
-                  mov [$9], r9
-                  mov [$10], r10
-                  mov [$11], r11
-                  mov [$12], r12
-                  mov [$13], r13
-                  mov [$14], r14
-                  mov [$15], r15
-                  mov [$16], rbp
+            use ::arch::cpu::*;
+
+            asm!("test qword ptr [rsp + 8], 3
+                  jnz 2f
+
+                  /* ring 0: save the scratch registers on the
+                     current stack, and resume the kernel unless a
+                     kernel-mode task was interrupted */
+                  push rax
+                  push rcx
+                  push rdx
+                  push rsi
+                  push rdi
+                  push r8
+                  push r9
+                  push r10
+                  push r11
+                  lea rdi, [rsp + 72]
+                  mov rsi, $17
+                  call $18
+                  test rax, rax
+                  pop r11
+                  pop r10
+                  pop r9
+                  pop r8
+                  pop rdi
+                  pop rsi
+                  pop rdx
+                  pop rcx
+                  pop rax
+                  jnz 1f
+                  iretq
+
+               2:
+                  swapgs
+               1:
+                  mov gs:[$2], rax
+                  mov gs:[$3], rbx
+                  mov gs:[$4], rcx
+                  mov gs:[$5], rdx
+                  mov gs:[$6], rsi
+                  mov gs:[$7], rdi
+                  mov gs:[$8], r8
+                  mov gs:[$9], r9
+                  mov gs:[$10], r10
+                  mov gs:[$11], r11
+                  mov gs:[$12], r12
+                  mov gs:[$13], r13
+                  mov gs:[$14], r14
+                  mov gs:[$15], r15
+                  mov gs:[$16], rbp
 
                   mov rsi, $17
                   mov rdi, rsp
                   sub rsp, 8
                   call $0
 
-                  mov rsp, [$1]
+                  mov rsp, gs:[$1]
                   pop r15
                   pop r14
                   pop r13
@@ -219,53 +454,57 @@ macro_rules! return_to_raw_fn {
                  ::
 
                  "i"(::arch::interrupt::switch::store_exception_stack as unsafe extern "C" fn(*const ::arch::interrupt::switch::ExceptionStackFrame, u64)),
-                 "i"(&RSP_AFTER_SAVING_REGISTERS),
-
-                 "i"(&CUR_REGISTERS.rax),
-                 "i"(&CUR_REGISTERS.rbx),
-                 "i"(&CUR_REGISTERS.rcx),
-                 "i"(&CUR_REGISTERS.rdx),
-                 "i"(&CUR_REGISTERS.rsi),
-                 "i"(&CUR_REGISTERS.rdi),
-                 "i"(&CUR_REGISTERS.r8),
-                 "i"(&CUR_REGISTERS.r9),
-                 "i"(&CUR_REGISTERS.r10),
-                 "i"(&CUR_REGISTERS.r11),
-                 "i"(&CUR_REGISTERS.r12),
-                 "i"(&CUR_REGISTERS.r13),
-                 "i"(&CUR_REGISTERS.r14),
-                 "i"(&CUR_REGISTERS.r15),
-                 "i"(&CUR_REGISTERS.rbp),
-
-                 "i"($exception_code)
+                 "i"(CPU_RSP_AFTER_SAVING_REGISTERS),
+
+                 "i"(CPU_RAX),
+                 "i"(CPU_RBX),
+                 "i"(CPU_RCX),
+                 "i"(CPU_RDX),
+                 "i"(CPU_RSI),
+                 "i"(CPU_RDI),
+                 "i"(CPU_R8),
+                 "i"(CPU_R9),
+                 "i"(CPU_R10),
+                 "i"(CPU_R11),
+                 "i"(CPU_R12),
+                 "i"(CPU_R13),
+                 "i"(CPU_R14),
+                 "i"(CPU_R15),
+                 "i"(CPU_RBP),
+
+                 "i"($exception_code),
+                 "i"(::arch::interrupt::switch::interrupted_kernel as unsafe extern "C" fn(*mut ::arch::interrupt::switch::ExceptionStackFrame, u64) -> u64)
                  :: "volatile", "intel");
         }
     )
 }
 
-#[allow(unused_macros)]
 macro_rules! return_error_to_raw_fn {
     ($name: ident, $exception_code: expr) => (
         #[naked]
         #[inline(never)]
         pub unsafe extern "C" fn $name() {
-            use ::arch::interrupt::switch::{RSP_AFTER_SAVING_REGISTERS, CUR_REGISTERS};
-
-            asm!("mov [$2], rax
-                  mov [$3], rbx
-                  mov [$4], rcx
-                  mov [$5], rdx
-                  mov [$6], rsi
-                  mov [$7], rdi
-                  mov [$8], r8
-                  mov [$9], r9
-                  mov [$10], r10
-                  mov [$11], r11
-                  mov [$12], r12
-                  mov [$13], r13
-                  mov [$14], r14
-                  mov [$15], r15
-                  mov [$16], rbp
+            use ::arch::cpu::*;
+
+            asm!("test qword ptr [rsp + 16], 3
+                  jz 1f
+                  swapgs
+               1:
+                  mov gs:[$2], rax
+                  mov gs:[$3], rbx
+                  mov gs:[$4], rcx
+                  mov gs:[$5], rdx
+                  mov gs:[$6], rsi
+                  mov gs:[$7], rdi
+                  mov gs:[$8], r8
+                  mov gs:[$9], r9
+                  mov gs:[$10], r10
+                  mov gs:[$11], r11
+                  mov gs:[$12], r12
+                  mov gs:[$13], r13
+                  mov gs:[$14], r14
+                  mov gs:[$15], r15
+                  mov gs:[$16], rbp
 
                   mov rdx, $17
                   pop rsi
@@ -273,7 +512,7 @@ macro_rules! return_error_to_raw_fn {
                   sub rsp, 8
                   call $0
 
-                  mov rsp, [$1]
+                  mov rsp, gs:[$1]
                   pop r15
                   pop r14
                   pop r13
@@ -292,23 +531,23 @@ macro_rules! return_error_to_raw_fn {
                  ::
 
                  "i"(::arch::interrupt::switch::store_error_exception_stack as unsafe extern "C" fn(*const ::arch::interrupt::switch::ExceptionStackFrame, u64, u64)),
-                 "i"(&RSP_AFTER_SAVING_REGISTERS),
-
-                 "i"(&CUR_REGISTERS.rax),
-                 "i"(&CUR_REGISTERS.rbx),
-                 "i"(&CUR_REGISTERS.rcx),
-                 "i"(&CUR_REGISTERS.rdx),
-                 "i"(&CUR_REGISTERS.rsi),
-                 "i"(&CUR_REGISTERS.rdi),
-                 "i"(&CUR_REGISTERS.r8),
-                 "i"(&CUR_REGISTERS.r9),
-                 "i"(&CUR_REGISTERS.r10),
-                 "i"(&CUR_REGISTERS.r11),
-                 "i"(&CUR_REGISTERS.r12),
-                 "i"(&CUR_REGISTERS.r13),
-                 "i"(&CUR_REGISTERS.r14),
-                 "i"(&CUR_REGISTERS.r15),
-                 "i"(&CUR_REGISTERS.rbp),
+                 "i"(CPU_RSP_AFTER_SAVING_REGISTERS),
+
+                 "i"(CPU_RAX),
+                 "i"(CPU_RBX),
+                 "i"(CPU_RCX),
+                 "i"(CPU_RDX),
+                 "i"(CPU_RSI),
+                 "i"(CPU_RDI),
+                 "i"(CPU_R8),
+                 "i"(CPU_R9),
+                 "i"(CPU_R10),
+                 "i"(CPU_R11),
+                 "i"(CPU_R12),
+                 "i"(CPU_R13),
+                 "i"(CPU_R14),
+                 "i"(CPU_R15),
+                 "i"(CPU_RBP),
 
                  "i"($exception_code)
                  : "rdi" : "volatile", "intel");
@@ -318,15 +557,16 @@ macro_rules! return_error_to_raw_fn {
 
 pub fn last_exception_return_value() -> Option<ExceptionInfo> {
     unsafe {
-        CUR_EXCEPTION_STACK_FRAME.clone().map(|exp| {
+        let cpu = cpu::current();
+        cpu.exception_stack_frame.clone().map(|exp| {
             ExceptionInfo {
                 instruction_pointer: exp.instruction_pointer,
                 code_segment: exp.code_segment,
                 cpu_flags: exp.cpu_flags,
                 stack_pointer: exp.stack_pointer,
                 stack_segment: exp.stack_segment,
-                error_code: CUR_EXCEPTION_ERROR_CODE,
-                exception_code: CUR_EXCEPTION_CODE.unwrap()
+                error_code: cpu.exception_error_code,
+                exception_code: cpu.exception_code.unwrap()
             }
         })
     }
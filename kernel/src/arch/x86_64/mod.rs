@@ -17,6 +17,20 @@ mod interrupt;
 /// Segment descriptor and task state segment representation.
 mod segmentation;
 
+/// Per-CPU data, reached through the `gs` segment base.
+mod cpu;
+
+/// ACPI table parsing, for discovering CPUs and interrupt
+/// controllers.
+mod acpi;
+
+/// FPU, SSE and AVX state, saved and restored lazily per task.
+mod fpu;
+
+/// Kernel backtraces and panic reports, symbolized with the kernel
+/// symbols from multiboot.
+mod backtrace;
+
 /// Architecture-specific capabilities. Re-exported also in `kernel::cap`.
 #[macro_use]
 pub mod cap;
@@ -67,15 +81,44 @@ pub unsafe fn io_wait() {
     outportb(0x80, 0)
 }
 
-pub fn enable_timer() {
-    interrupt::LOCAL_APIC.lock().enable_timer();
+/// Write 64 bits to msr register.
+pub unsafe fn wrmsr(msr: u32, value: u64) {
+    let low = value as u32;
+    let high = (value >> 32) as u32;
+    asm!("wrmsr" :: "{ecx}" (msr), "{eax}" (low), "{edx}" (high) : "memory" : "volatile" );
+}
+
+/// Read 64 bits msr register.
+pub unsafe fn rdmsr(msr: u32) -> u64 {
+    let (high, low): (u32, u32);
+    asm!("rdmsr" : "={eax}" (low), "={edx}" (high) : "{ecx}" (msr) : "memory" : "volatile");
+    ((high as u64) << 32) | (low as u64)
+}
+
+/// Enable the scheduler timer, optionally at a given frequency.
+pub fn enable_timer(hz: Option<u32>) {
+    interrupt::LOCAL_APIC.lock().enable_timer(hz);
+}
+
+/// Index of the current CPU. The bootstrap processor is 0.
+pub fn cpu_index() -> usize {
+    cpu::current_index()
+}
+
+/// Top of the stack the idle task of the current CPU runs on.
+pub fn idle_stack() -> VAddr {
+    VAddr::from(cpu::idle_stack_top())
 }
 
 // Public interfaces
-pub use self::paging::{MemoryObject};
+pub use self::paging::{MemoryObject, pml4_index, pdpt_index, pd_index, has_huge_pages};
 pub use self::interrupt::{enable_interrupt, disable_interrupt, set_interrupt_handler,
                           Exception, TaskRuntime};
-pub use self::init::{InitInfo};
+pub use self::init::{InitInfo, start_application_processors};
+pub use self::cpu::{MAX_CPUS, KERNEL_STACK_LENGTH, KERNEL_STACK_ALIGNMENT, set_idle, wake_up,
+                    set_current_task};
+pub use self::fpu::{FPU_AREA_LENGTH, FPU_AREA_ALIGNMENT, init_area as init_fpu_area};
+pub use self::backtrace::report_panic;
 // pub use self::cap::{ArchCap, PageHalf, PageFull};
 pub use self::addr::{PAddr, VAddr};
 
@@ -1,4 +1,5 @@
 use common::{PAddr, VAddr};
+use super::fpu::cpuid;
 
 #[macro_use]
 mod macros;
@@ -16,7 +17,6 @@ pub const BASE_PAGE_LENGTH: usize = 4096; // 4 KiB
 pub const LARGE_PAGE_LENGTH: usize = 1024 * 1024 * 2; // 2 MiB
 
 /// Huge page length in x86_64 (1 GiB).
-#[allow(dead_code)]
 pub const HUGE_PAGE_LENGTH: usize = 1024 * 1024 * 1024; // 1 GiB
 
 /// Cache line length in x86_64 (64 Bytes).
@@ -27,18 +27,25 @@ pub const CACHE_LINE_LENGTH: usize = 64; // 64 Bytes
 pub const MAXPHYADDR: u64 = 52;
 
 /// Mask to find the physical address of an entry in a page-table.
-const ADDRESS_MASK: u64 = ((1 << MAXPHYADDR) - 1) & !0xfff;
+pub const ADDRESS_MASK: u64 = ((1 << MAXPHYADDR) - 1) & !0xfff;
 
 pub use self::table::*;
 pub use self::with::{MemoryObject};
 
 /// Contains page-table root pointer.
-unsafe fn cr3() -> u64 {
+pub unsafe fn cr3() -> u64 {
     let ret: u64;
     asm!("mov %cr3, $0" : "=r" (ret));
     ret
 }
 
+/// Whether the CPU supports huge (1 GiB) pages, as reported by
+/// `CPUID.80000001H:EDX.Page1GB`.
+pub fn has_huge_pages() -> bool {
+    let (_, _, _, edx) = unsafe { cpuid(0x80000001, 0) };
+    edx & (1 << 26) != 0
+}
+
 /// Switch page-table PML4 pointer.
 unsafe fn cr3_write(val: u64) {
     asm!("mov $0, %cr3" :: "r" (val) : "memory");
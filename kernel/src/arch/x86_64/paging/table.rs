@@ -159,6 +159,8 @@ impl PDPTEntry {
                 is_page_level_cache_disabled, PDPT_PCD);
     check_flag!(doc = "Accessed; indicates whether this entry has been used for linear-address translation.",
                 is_accessed, PDPT_A);
+    check_flag!(doc = "Page size; if set this entry maps a 1-GByte page; otherwise, this entry references a page directory.",
+                is_page, PDPT_PS);
     check_flag!(doc = "Indirectly determines the memory type used to access the 1-GByte page referenced by this entry. if not PDPT_PS this is ignored.",
                 is_pat, PDPT_PAT);
     check_flag!(doc = "If IA32_EFER.NXE = 1, execute-disable. If 1, instruction fetches are not allowed from the 512-GByte region.",
@@ -260,6 +262,15 @@ bitflags! {
         const PT_D       = bit!(6),
         /// Global; if CR4.PGE = 1, determines whether the translation is global (see Section 4.10); ignored otherwise
         const PT_G       = bit!(8),
+        /// Available to software; marks a read-only copy-on-write page.
+        const PT_COW     = bit!(9),
+        /// Available to software; marks a page whose memory is shared
+        /// with other mappings or is device memory, which cloning an
+        /// address space never copies.
+        const PT_SHARED  = bit!(10),
+        /// Available to software; marks a non-present guard page in
+        /// the object pool, so that no memory object is mapped there.
+        const PT_GUARD   = bit!(11),
         /// If IA32_EFER.NXE = 1, execute-disable
         /// If 1, instruction fetches are not allowed from the 512-GByte region.
         const PT_XD      = bit!(63),
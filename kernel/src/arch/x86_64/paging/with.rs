@@ -1,7 +1,7 @@
 // TODO Disable interrupt before entering those.
 use core::mem::{size_of};
 use util::{align_down, block_count};
-use super::{PTEntry, PT_P, PT_RW, flush, BASE_PAGE_LENGTH};
+use super::{PTEntry, PT_P, PT_RW, PT_GUARD, flush, BASE_PAGE_LENGTH};
 use arch::init::{OBJECT_POOL_PT, OBJECT_POOL_START_VADDR};
 use common::PAddr;
 
@@ -68,11 +68,24 @@ impl<T: ?Sized> MemoryObject<T> {
 
     /// Get a slice from the current memory object.
     pub unsafe fn slice(paddr: PAddr, size: usize) -> Self where T: Sized {
+        Self::map(paddr, size, 0)
+    }
+
+    /// Get a slice like `slice`, with an unmapped guard page below
+    /// it, so that a stack overflowing the slice faults instead of
+    /// writing to another memory object.
+    pub unsafe fn guarded_slice(paddr: PAddr, size: usize) -> Self where T: Sized {
+        Self::map(paddr, size, 1)
+    }
+
+    /// Map `size` objects at `paddr`, after `guard_page_size`
+    /// reserved but unmapped pages.
+    unsafe fn map(paddr: PAddr, size: usize, guard_page_size: usize) -> Self where T: Sized {
         let aligned = align_down(paddr, BASE_PAGE_LENGTH);
         let before_start = paddr.into(): usize - aligned.into(): usize;
         let size = size_of::<T>() * size;
-        let required_page_size = block_count((paddr + size).into(): usize - aligned.into(): usize,
-                                             BASE_PAGE_LENGTH);
+        let required_page_size = guard_page_size +
+            block_count((paddr + size).into(): usize - aligned.into(): usize, BASE_PAGE_LENGTH);
 
         let mut object_pool = OBJECT_POOL_PT.lock();
         let mapping_start_index: usize = {
@@ -81,7 +94,7 @@ impl<T: ?Sized> MemoryObject<T> {
             for i in 0..object_pool.len() {
                 let mut available = true;
                 for j in 0..required_page_size {
-                    if object_pool[i + j].is_present() {
+                    if object_pool[i + j].is_present() || object_pool[i + j].contains(PT_GUARD) {
                         available = false;
                         break;
                     }
@@ -97,12 +110,17 @@ impl<T: ?Sized> MemoryObject<T> {
         }.unwrap();
 
 
-        for i in 0..required_page_size {
-            object_pool[mapping_start_index + i] = PTEntry::new(aligned + (i * BASE_PAGE_LENGTH), PT_P | PT_RW);
+        for i in 0..guard_page_size {
+            object_pool[mapping_start_index + i] = PT_GUARD;
+        }
+        for i in guard_page_size..required_page_size {
+            object_pool[mapping_start_index + i] = PTEntry::new(aligned + ((i - guard_page_size) * BASE_PAGE_LENGTH),
+                                                                PT_P | PT_RW);
             flush(OBJECT_POOL_START_VADDR + (mapping_start_index * BASE_PAGE_LENGTH) + i * BASE_PAGE_LENGTH);
         }
 
-        let vaddr = OBJECT_POOL_START_VADDR + ((mapping_start_index * BASE_PAGE_LENGTH) + before_start);
+        let vaddr = OBJECT_POOL_START_VADDR + (((mapping_start_index + guard_page_size) * BASE_PAGE_LENGTH) +
+                                               before_start);
 
         MemoryObject::<T> {
             paddr: paddr,
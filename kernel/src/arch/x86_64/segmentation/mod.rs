@@ -138,6 +138,11 @@ impl SegmentDescriptor {
     }
 }
 
+/// Load the task state register.
+pub unsafe fn load_tr(sel: SegmentSelector) {
+    asm!("ltr $0" :: "r" (sel.bits()));
+}
+
 /// Reload stack segment register.
 pub unsafe fn load_ss(sel: SegmentSelector) {
     asm!("movw $0, %ss " :: "r" (sel.bits()) : "memory");
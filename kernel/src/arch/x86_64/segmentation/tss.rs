@@ -1,6 +1,7 @@
 /// Represents a Task State Segment. It holds the kernel stack
 /// information used by interrupts.
 #[repr(packed)]
+#[derive(Clone, Copy)]
 #[allow(dead_code)]
 pub struct TaskStateSegment {
     _reserved1: u32,
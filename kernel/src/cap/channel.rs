@@ -1,23 +1,30 @@
 use common::*;
+use core::cmp;
 use core::convert::From;
 use util::RwLock;
 use util::managed_arc::{ManagedArc, ManagedArcAny};
-use abi::ChannelMessage;
-use super::{UntypedDescriptor, TaskCap, TaskBufferPageCap};
+use abi::{ChannelMessage, ChannelReceive, CapType, CapInfo, CapDetail,
+          CHANNEL_MESSAGE_CAPS, CHANNEL_MESSAGE_PAGES};
+use super::{UntypedDescriptor, CPoolCap, TaskCap, TaskBufferPageCap, RawPageCap, Identify, PAGE_LENGTH, drop_any};
 
 #[derive(Debug)]
 pub enum ChannelValue {
     Raw(u64),
     Cap(ManagedArcAny),
     Payload(TaskBufferPageCap),
+    Compound([Option<ManagedArcAny>; CHANNEL_MESSAGE_CAPS], TaskBufferPageCap),
+    Bulk([Option<RawPageCap>; CHANNEL_MESSAGE_PAGES], usize),
 }
 
 impl ChannelValue {
+    /// Take the value of a message put by the task `source_root`.
+    /// Returns `None` if a capability of the message cannot be
+    /// found, or the task has no capability pool or task buffer.
     pub fn from_message(message: ChannelMessage, source_root: TaskCap) -> Option<ChannelValue> {
         match message {
             ChannelMessage::Raw(value) => Some(ChannelValue::Raw(value)),
             ChannelMessage::Cap(Some(caddr)) => {
-                let source_root = source_root.read().upgrade_cpool().unwrap();
+                let source_root = source_root.read().upgrade_cpool()?;
                 let obj = source_root.lookup_upgrade_any(caddr);
                 if obj.is_some() {
                     Some(ChannelValue::Cap(obj.unwrap()))
@@ -27,33 +34,247 @@ impl ChannelValue {
             },
             ChannelMessage::Cap(None) => None,
             ChannelMessage::Payload => {
-                let source_root = source_root.read().upgrade_buffer().unwrap();
+                let source_root = source_root.read().upgrade_buffer()?;
                 Some(ChannelValue::Payload(source_root))
-            }
+            },
+            ChannelMessage::Compound(caddrs) => {
+                let source_cpool = source_root.read().upgrade_cpool()?;
+                let mut caps: [Option<ManagedArcAny>; CHANNEL_MESSAGE_CAPS] = Default::default();
+                for i in 0..CHANNEL_MESSAGE_CAPS {
+                    if let Some(caddr) = caddrs[i] {
+                        caps[i] = source_cpool.lookup_upgrade_any(caddr);
+                        if caps[i].is_none() {
+                            drop_caps(caps);
+                            return None;
+                        }
+                    }
+                }
+                match source_root.read().upgrade_buffer() {
+                    Some(source_buffer) => Some(ChannelValue::Compound(caps, source_buffer)),
+                    None => {
+                        drop_caps(caps);
+                        None
+                    },
+                }
+            },
+            ChannelMessage::Bulk(caddrs, length) => {
+                let source_cpool = source_root.read().upgrade_cpool()?;
+                let mut pages: [Option<RawPageCap>; CHANNEL_MESSAGE_PAGES] = Default::default();
+                for i in 0..CHANNEL_MESSAGE_PAGES {
+                    if let Some(caddr) = caddrs[i] {
+                        pages[i] = Some(source_cpool.lookup_upgrade(caddr)?);
+                    }
+                }
+                Some(ChannelValue::Bulk(pages, length))
+            },
         }
     }
 
-    pub fn to_message(value: ChannelValue, target_root: TaskCap) -> ChannelMessage {
+    /// Give a value taken from a channel to the task `target_root`,
+    /// and describe it as a message. Capabilities that cannot be put
+    /// into its capability pool are dropped.
+    pub fn to_message(value: ChannelValue, target_root: TaskCap, receive: &ChannelReceive) -> ChannelMessage {
         match value {
             ChannelValue::Raw(value) => ChannelMessage::Raw(value),
             ChannelValue::Cap(arc) => {
-                let target_root = target_root.read().upgrade_cpool().unwrap();
-                let target_desc = target_root.read();
-                let index = target_desc.downgrade_any_free(arc);
-                ChannelMessage::Cap(index.map(|i| { CAddr::from(i as u8) }))
+                let target_root = target_root.read().upgrade_cpool();
+                let target_root = match target_root {
+                    Some(target_root) => target_root,
+                    None => {
+                        drop_any(arc);
+                        return ChannelMessage::Cap(None);
+                    },
+                };
+                match receive.slots[0] {
+                    Some(caddr) => match target_root.lookup_downgrade_any_at_if_free(arc, caddr) {
+                        Ok(()) => ChannelMessage::Cap(Some(caddr)),
+                        Err(arc) => {
+                            log!("Receive slot is not free, dropping the capability.");
+                            drop_any(arc);
+                            ChannelMessage::Cap(None)
+                        },
+                    },
+                    None => {
+                        let target_desc = target_root.read();
+                        let index = target_desc.downgrade_any_free(arc);
+                        ChannelMessage::Cap(index.map(|i| { CAddr::from(i as u8) }))
+                    },
+                }
             },
             ChannelValue::Payload(buffer_cap) => {
-                let source_buffer = buffer_cap.read().read();
-                let mut target_buffer_cap = target_root.read().upgrade_buffer().unwrap();
-                let mut target_buffer = target_buffer_cap.write().write();
-                target_buffer.payload_length = source_buffer.payload_length;
-                for i in 0..source_buffer.payload_length {
-                    target_buffer.payload_data[i] = source_buffer.payload_data[i];
-                }
+                copy_payload(buffer_cap, target_root);
                 ChannelMessage::Payload
+            },
+            ChannelValue::Compound(mut caps, buffer_cap) => {
+                let target_cpool = target_root.read().upgrade_cpool();
+                let slots = target_cpool.as_ref().and_then(|target_cpool| {
+                    receive_slots(target_cpool, &caps, &receive.slots)
+                });
+                let caddrs = match slots {
+                    Some(caddrs) => {
+                        let target_cpool = target_cpool.unwrap();
+                        match put_caps(&target_cpool, caps, &caddrs) {
+                            Ok(()) => caddrs,
+                            Err(caps) => {
+                                log!("Receive slot was filled during the transfer, dropping the capabilities.");
+                                drop_caps(caps);
+                                Default::default()
+                            },
+                        }
+                    },
+                    None => {
+                        drop_caps(caps);
+                        Default::default()
+                    },
+                };
+                copy_payload(buffer_cap, target_root);
+                ChannelMessage::Compound(caddrs)
+            },
+            ChannelValue::Bulk(pages, length) => {
+                let target_cpool = target_root.read().upgrade_cpool();
+                let target_cpool = match target_cpool {
+                    Some(target_cpool) => target_cpool,
+                    None => return ChannelMessage::Bulk(receive.pages, 0),
+                };
+                let mut copied = 0;
+                for i in 0..CHANNEL_MESSAGE_PAGES {
+                    let source: Option<RawPageCap> = pages[i].clone();
+                    let target: Option<RawPageCap> = receive.pages[i].and_then(|caddr| {
+                        target_cpool.lookup_upgrade(caddr)
+                    });
+                    if copied >= length || source.is_none() || target.is_none() {
+                        break;
+                    }
+
+                    let (source, target) = (source.unwrap(), target.unwrap());
+                    let count = cmp::min(length - copied, PAGE_LENGTH);
+                    if source.read().start_paddr() != target.read().start_paddr() {
+                        let source_page = source.read().read();
+                        let mut target_page = target.write().write();
+                        target_page.0[..count].copy_from_slice(&source_page.0[..count]);
+                    }
+                    copied += count;
+                }
+                ChannelMessage::Bulk(receive.pages, copied)
+            },
+        }
+    }
+}
+
+/// Choose the slot in `target_cpool` each capability of a compound
+/// message is put in: the slot supplied by the receiver, or a free
+/// slot of the top-level pool. Returns `None` if a supplied slot is
+/// not free, or two capabilities would be put in the same slot, so
+/// that either all capabilities are transferred or none. The slots
+/// are only checked here, see `put_caps` for a slot filled before
+/// the capabilities are put.
+fn receive_slots(target_cpool: &CPoolCap, caps: &[Option<ManagedArcAny>; CHANNEL_MESSAGE_CAPS],
+                 slots: &[Option<CAddr>; CHANNEL_MESSAGE_CAPS]) -> Option<[Option<CAddr>; CHANNEL_MESSAGE_CAPS]> {
+    let mut caddrs: [Option<CAddr>; CHANNEL_MESSAGE_CAPS] = Default::default();
+    let mut resolved: [Option<(usize, usize)>; CHANNEL_MESSAGE_CAPS] = Default::default();
+
+    for i in 0..CHANNEL_MESSAGE_CAPS {
+        if caps[i].is_none() {
+            continue;
+        }
+
+        let caddr = match slots[i] {
+            Some(caddr) => caddr,
+            None => {
+                let size = target_cpool.read().size();
+                let index = (0..size).find(|index| {
+                    let slot = target_cpool.lookup_slot(CAddr::from(*index as u8));
+                    target_cpool.read().is_free(*index) && !resolved.contains(&slot)
+                })?;
+                CAddr::from(index as u8)
+            },
+        };
+
+        let slot = target_cpool.lookup_slot(caddr);
+        if slot.is_none() || !target_cpool.lookup_free(caddr, 1) || resolved.contains(&slot) {
+            return None;
+        }
+        caddrs[i] = Some(caddr);
+        resolved[i] = slot;
+    }
+
+    Some(caddrs)
+}
+
+/// Put the capabilities of a compound message in the slots chosen by
+/// `receive_slots`. Each slot is checked again as it is filled. If
+/// one was filled in the meantime, the capabilities already put are
+/// removed again, which drops them, and the others are returned to
+/// be dropped as well.
+fn put_caps(target_cpool: &CPoolCap, caps: [Option<ManagedArcAny>; CHANNEL_MESSAGE_CAPS],
+            caddrs: &[Option<CAddr>; CHANNEL_MESSAGE_CAPS]) -> Result<(), [Option<ManagedArcAny>; CHANNEL_MESSAGE_CAPS]> {
+    let mut caps = caps;
+    let mut put: [Option<PAddr>; CHANNEL_MESSAGE_CAPS] = Default::default();
+
+    for i in 0..CHANNEL_MESSAGE_CAPS {
+        if let Some(arc) = caps[i].take() {
+            let paddr = arc.paddr();
+            if let Err(arc) = target_cpool.lookup_downgrade_any_at_if_free(arc, caddrs[i].unwrap()) {
+                caps[i] = Some(arc);
+                for j in 0..i {
+                    if let Some(paddr) = put[j] {
+                        target_cpool.lookup_remove_if(caddrs[j].unwrap(), paddr);
+                    }
+                }
+                return Err(caps);
             }
+            put[i] = Some(paddr);
         }
     }
+
+    Ok(())
+}
+
+/// Drop capabilities taken from a capability pool for a compound
+/// message that could not be sent.
+fn drop_caps(caps: [Option<ManagedArcAny>; CHANNEL_MESSAGE_CAPS]) {
+    let mut caps = caps;
+    for i in 0..CHANNEL_MESSAGE_CAPS {
+        if let Some(arc) = caps[i].take() {
+            drop_any(arc);
+        }
+    }
+}
+
+/// Drop a value that is no longer in a channel. Capabilities it holds
+/// are dropped through `drop_any`, as a `ManagedArcAny` cannot be
+/// dropped directly.
+fn drop_value(value: ChannelValue) {
+    match value {
+        ChannelValue::Cap(arc) => drop_any(arc),
+        ChannelValue::Compound(caps, _) => drop_caps(caps),
+        _ => (),
+    }
+}
+
+/// Copy the payload in the task buffer `buffer_cap` into the task
+/// buffer of `target_root`, if it has one.
+fn copy_payload(buffer_cap: TaskBufferPageCap, target_root: TaskCap) {
+    let source_buffer = buffer_cap.read().read();
+    let target_buffer_cap = target_root.read().upgrade_buffer();
+    let mut target_buffer_cap = match target_buffer_cap {
+        Some(target_buffer_cap) => target_buffer_cap,
+        None => {
+            log!("Payload receiver has no task buffer.");
+            return;
+        },
+    };
+    let mut target_buffer = target_buffer_cap.write().write();
+    match source_buffer.payload() {
+        Some(payload) => {
+            target_buffer.payload_length = payload.len();
+            target_buffer.payload_data[0..payload.len()].copy_from_slice(payload);
+        },
+        None => {
+            log!("Payload length {} is out of range.", source_buffer.payload_length);
+            target_buffer.payload_length = 0;
+        },
+    }
 }
 
 /// Channel descriptor.
@@ -71,7 +292,9 @@ pub type ChannelCap = ManagedArc<RwLock<ChannelDescriptor>>;
 
 impl ChannelCap {
     /// Create a channel capability from an untyped capability.
-    pub fn retype_from(untyped: &mut UntypedDescriptor) -> Self {
+    /// Returns `None` if the untyped capability does not have enough
+    /// space left.
+    pub fn retype_from(untyped: &mut UntypedDescriptor) -> Option<Self> {
         let mut arc: Option<Self> = None;
 
         unsafe { untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
@@ -83,15 +306,19 @@ impl ChannelCap {
             );
 
             arc.clone().unwrap().into()
-        }) };
+        })? };
 
-        arc.unwrap()
+        arc
     }
 }
 
 impl ChannelDescriptor {
-    /// Put a value to the channel.
+    /// Put a value to the channel. A value that is still pending is
+    /// replaced and dropped.
     pub fn put(&mut self, value: ChannelValue) {
+        if let Some(pending) = self.value.take() {
+            drop_value(pending);
+        }
         self.value = Some(value);
     }
 
@@ -100,4 +327,20 @@ impl ChannelDescriptor {
     pub fn take(&mut self) -> Option<ChannelValue> {
         self.value.take()
     }
+
+    /// Whether a value is waiting in the channel.
+    pub fn is_pending(&self) -> bool {
+        self.value.is_some()
+    }
+}
+
+impl Identify for ChannelCap {
+    fn identify(&self) -> CapInfo {
+        CapInfo {
+            cap_type: CapType::Channel,
+            paddr: self.paddr().into(),
+            length: Self::inner_length(),
+            detail: CapDetail::Channel { pending: self.read().is_pending() },
+        }
+    }
 }
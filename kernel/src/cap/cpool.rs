@@ -4,7 +4,8 @@ use core::ops::Deref;
 use util::RwLock;
 use util::managed_arc::{ManagedArc, ManagedArcAny, ManagedWeakPool256Arc};
 
-use super::{UntypedDescriptor};
+use abi::{CapType, CapInfo, CapDetail};
+use super::{UntypedDescriptor, Identify};
 
 /// Capability pool descriptor.
 #[derive(Debug)]
@@ -25,6 +26,15 @@ fn downgrade_at_owning<T: Any>(arc: ManagedArc<T>, index: usize, desc: &CPoolDes
     desc.downgrade_at(&arc, index)
 }
 
+fn downgrade_at_if_free_owning<T: Any>(arc: ManagedArc<T>, index: usize, desc: &CPoolDescriptor) -> Result<(), ManagedArcAny>
+    where ManagedArc<T>: Any {
+    if desc.weak_pool.read().downgrade_at_if_free(&arc, index) {
+        Ok(())
+    } else {
+        Err(arc.into())
+    }
+}
+
 fn downgrade_free_owning<T: Any>(arc: ManagedArc<T>, desc: &CPoolDescriptor) -> Option<usize>
     where ManagedArc<T>: Any {
     desc.downgrade_free(&arc)
@@ -64,40 +74,68 @@ impl CPoolDescriptor {
         doto_any!(arc, downgrade_at_owning, index, self)
     }
 
+    /// Downgrade a `ManagedArcAny` into the capability pool (weak
+    /// pool) at a specified index, if the entry is empty. The entry
+    /// is checked and filled atomically. Returns the capability back
+    /// if the entry is not empty.
+    pub fn downgrade_any_at_if_free(&self, arc: ManagedArcAny, index: usize) -> Result<(), ManagedArcAny> {
+        doto_any!(arc, downgrade_at_if_free_owning, index, self)
+    }
+
     /// Downgrade a `ManagedArcAny` into the capability pool (weak
     /// pool) at a free index.
     pub fn downgrade_any_free(&self, arc: ManagedArcAny) -> Option<usize> {
         doto_any!(arc, downgrade_free_owning, self)
     }
 
+    /// Remove the capability at `index`, if it is the one whose
+    /// `ManagedArcInner` is at `paddr`. Returns whether it was
+    /// removed.
+    pub fn remove_if(&self, index: usize, paddr: PAddr) -> bool {
+        self.weak_pool.read().remove_ptr(index, paddr)
+    }
+
+    /// Whether the entry at `index` is empty.
+    pub fn is_free(&self, index: usize) -> bool {
+        self.weak_pool.read().is_free(index)
+    }
+
     /// Size of the capability pool.
     pub fn size(&self) -> usize {
         256
     }
+
+    /// Number of empty entries.
+    pub fn free_count(&self) -> usize {
+        (0..self.size()).filter(|i| self.is_free(*i)).count()
+    }
 }
 
 impl CPoolCap {
     /// Create a capability pool capability from an untyped
-    /// capability.
-    pub fn retype_from(untyped: &mut UntypedDescriptor) -> Self {
-        let mut arc: Option<Self> = None;
+    /// capability. Returns `None` if the untyped capability does not
+    /// have enough space left.
+    pub fn retype_from(untyped: &mut UntypedDescriptor) -> Option<Self> {
+        untyped.transaction(|untyped| {
+            let mut arc: Option<Self> = None;
 
-        let weak_pool = unsafe { ManagedWeakPool256Arc::create(
-            untyped.allocate(ManagedWeakPool256Arc::inner_length(),
-                             ManagedWeakPool256Arc::inner_alignment())) };
+            let weak_pool = unsafe { ManagedWeakPool256Arc::create(
+                untyped.allocate(ManagedWeakPool256Arc::inner_length(),
+                                 ManagedWeakPool256Arc::inner_alignment())?) };
 
-        unsafe { untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
-            arc = Some(
-                Self::new(paddr, RwLock::new(CPoolDescriptor {
-                    weak_pool: weak_pool,
-                    next: next_child,
-                }))
-            );
+            unsafe { untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
+                arc = Some(
+                    Self::new(paddr, RwLock::new(CPoolDescriptor {
+                        weak_pool: weak_pool,
+                        next: next_child,
+                    }))
+                );
 
-            arc.clone().unwrap().into()
-        }) };
+                arc.clone().unwrap().into()
+            })? };
 
-        arc.unwrap()
+            arc
+        })
     }
 
     fn lookup<R, F: FnOnce(Option<(&CPoolDescriptor, usize)>) -> R>(&self, caddr: CAddr, f: F) -> R {
@@ -138,6 +176,22 @@ impl CPoolCap {
         })
     }
 
+    /// Check whether `count` consecutive slots, starting at the
+    /// specified capability address, are all empty. Returns `false`
+    /// if `count` is 0 or the slots go past the end of the pool.
+    pub fn lookup_free(&self, caddr: CAddr, count: usize) -> bool {
+        self.lookup(caddr, |data| {
+            data.map_or(false, |(cpool, index)| {
+                match index.checked_add(count) {
+                    Some(end) if count > 0 && end <= cpool.size() => {
+                        (index..end).all(|i| cpool.is_free(i))
+                    },
+                    _ => false,
+                }
+            })
+        })
+    }
+
     /// Downgrade a capability into the capability pool at a specified capability address.
     pub fn lookup_downgrade_at<T: Any>(&self, arc: &ManagedArc<T>, caddr: CAddr)
         where ManagedArc<T>: Any {
@@ -147,11 +201,58 @@ impl CPoolCap {
         });
     }
 
+    /// Resolve a capability address to the capability pool it
+    /// points into, identified by the address of its descriptor, and
+    /// the index in that pool. Two capability addresses refer to the
+    /// same slot if they resolve to the same value.
+    pub fn lookup_slot(&self, caddr: CAddr) -> Option<(usize, usize)> {
+        self.lookup(caddr, |data| {
+            data.map(|(cpool, index)| {
+                (cpool as *const CPoolDescriptor as usize, index)
+            })
+        })
+    }
+
     /// Downgrade a `ManagedArcAny` into the capability pool at a specified capability address.
-    pub fn lookup_downgrade_any_at<T: Any>(&self, arc: ManagedArcAny, caddr: CAddr) {
+    pub fn lookup_downgrade_any_at(&self, arc: ManagedArcAny, caddr: CAddr) {
         self.lookup(caddr, |data| {
             let (cpool, index) = data.unwrap();
             cpool.downgrade_any_at(arc, index);
         });
     }
+
+    /// Remove the capability at a specified capability address, if
+    /// it is the one whose `ManagedArcInner` is at `paddr`. Returns
+    /// whether it was removed.
+    pub fn lookup_remove_if(&self, caddr: CAddr, paddr: PAddr) -> bool {
+        self.lookup(caddr, |data| {
+            data.map_or(false, |(cpool, index)| {
+                cpool.remove_if(index, paddr)
+            })
+        })
+    }
+
+    /// Downgrade a `ManagedArcAny` into the capability pool at a
+    /// specified capability address, if the slot is empty. Returns the
+    /// capability back if the address does not resolve to a slot, or
+    /// the slot is not empty.
+    pub fn lookup_downgrade_any_at_if_free(&self, arc: ManagedArcAny, caddr: CAddr) -> Result<(), ManagedArcAny> {
+        self.lookup(caddr, |data| {
+            match data {
+                Some((cpool, index)) => cpool.downgrade_any_at_if_free(arc, index),
+                None => Err(arc),
+            }
+        })
+    }
+}
+
+impl Identify for CPoolCap {
+    fn identify(&self) -> CapInfo {
+        CapInfo {
+            cap_type: CapType::CPool,
+            paddr: self.paddr().into(),
+            length: Self::inner_length(),
+            detail: CapDetail::CPool { free: self.read().free_count() },
+        }
+    }
 }
@@ -29,7 +29,7 @@ mod channel;
 
 pub use self::untyped::{UntypedDescriptor, UntypedCap};
 pub use self::cpool::{CPoolDescriptor, CPoolCap};
-pub use self::task::{TaskDescriptor, TaskCap, TaskStatus, idle, task_iter};
+pub use self::task::{TaskDescriptor, TaskCap, TaskStatus, idle, task_iter, wake_channel_waiters};
 pub use self::channel::{ChannelDescriptor, ChannelCap, ChannelValue};
 
 pub use arch::cap::{TopPageTableCap, PageCap, PAGE_LENGTH};
@@ -41,6 +41,53 @@ use core::mem::drop;
 use util::managed_arc::{ManagedArcAny, ManagedArc};
 
 pub use abi::{SetDefault, TaskBuffer};
+use abi::{CapType, CapInfo, CapDetail, PageRights, CachePolicy};
+
+/// A capability that can describe itself to user-space.
+pub trait Identify {
+    /// Information about the capability, returned by `CapIdentify`.
+    fn identify(&self) -> CapInfo;
+}
+
+/// A capability the page system calls can act on. Capabilities that
+/// are not pages keep the default methods, which return `None`.
+pub trait Mappable {
+    /// Map the page in `pml4` at `vaddr`, creating missing page
+    /// tables from `untyped`. Pages zeroed a chunk at a time are only
+    /// mapped once zeroed, so this returns the length left to zero,
+    /// and the page is mapped when it is 0.
+    fn map_page(&self, _pml4: &mut TopPageTableCap, _vaddr: VAddr, _rights: PageRights,
+                _cache: CachePolicy, _untyped: &mut UntypedDescriptor) -> Option<usize> {
+        None
+    }
+
+    /// Unmap the page from the address space it is mapped in.
+    fn unmap_page(&self) -> Option<()> {
+        None
+    }
+
+    /// Change the rights and cache policy of the mapped page.
+    fn remap_page(&self, _rights: PageRights, _cache: CachePolicy) -> Option<()> {
+        None
+    }
+
+    /// Derive a copy of the page sharing its memory, allocated from
+    /// `untyped`.
+    fn derive_page(&self, _untyped: &mut UntypedDescriptor) -> Option<ManagedArcAny> {
+        None
+    }
+
+    /// Revoke all copies derived from the page.
+    fn revoke_page(&self) -> Option<()> {
+        None
+    }
+}
+
+impl Mappable for CPoolCap { }
+impl Mappable for UntypedCap { }
+impl Mappable for TaskCap { }
+impl Mappable for ChannelCap { }
+
 /// Raw page struct representing a whole page.
 pub struct RawPage(pub [u8; PAGE_LENGTH]);
 /// Raw page capability. Represents a page with no other information.
@@ -56,6 +103,30 @@ impl SetDefault for RawPage {
     }
 }
 
+impl Identify for RawPageCap {
+    fn identify(&self) -> CapInfo {
+        let desc = self.read();
+        CapInfo {
+            cap_type: CapType::RawPage,
+            paddr: desc.start_paddr().into(),
+            length: desc.length(),
+            detail: CapDetail::None,
+        }
+    }
+}
+
+impl Identify for TaskBufferPageCap {
+    fn identify(&self) -> CapInfo {
+        let desc = self.read();
+        CapInfo {
+            cap_type: CapType::TaskBufferPage,
+            paddr: desc.start_paddr().into(),
+            length: desc.length(),
+            detail: CapDetail::None,
+        }
+    }
+}
+
 /// Create a managed Arc (capability) from an address of an kernel
 /// object (architecture-specific or general). The `type_id` should be
 /// a [TypeId](https://doc.rust-lang.org/std/any/struct.TypeId.html)
@@ -90,3 +161,61 @@ pub unsafe fn upgrade_any(ptr: PAddr, type_id: TypeId) -> Option<ManagedArcAny>
 pub fn drop_any(any: ManagedArcAny) {
     doto_any!(any, drop)
 }
+
+fn identify<T: Identify>(cap: T) -> CapInfo {
+    cap.identify()
+}
+
+/// Identify an `any` capability, for both general and
+/// architecture-specific capabilities.
+pub fn identify_any(any: ManagedArcAny) -> CapInfo {
+    doto_any!(any, identify)
+}
+
+fn map_page<T: Mappable>(cap: T, pml4: &mut TopPageTableCap, vaddr: VAddr, rights: PageRights,
+                         cache: CachePolicy, untyped: &mut UntypedDescriptor) -> Option<usize> {
+    cap.map_page(pml4, vaddr, rights, cache, untyped)
+}
+
+/// Map an `any` capability with `Mappable::map_page`.
+pub fn map_page_any(any: ManagedArcAny, pml4: &mut TopPageTableCap, vaddr: VAddr, rights: PageRights,
+                    cache: CachePolicy, untyped: &mut UntypedDescriptor) -> Option<usize> {
+    doto_any!(any, map_page, pml4, vaddr, rights, cache, untyped)
+}
+
+fn unmap_page<T: Mappable>(cap: T) -> Option<()> {
+    cap.unmap_page()
+}
+
+/// Unmap an `any` capability with `Mappable::unmap_page`.
+pub fn unmap_page_any(any: ManagedArcAny) -> Option<()> {
+    doto_any!(any, unmap_page)
+}
+
+fn remap_page<T: Mappable>(cap: T, rights: PageRights, cache: CachePolicy) -> Option<()> {
+    cap.remap_page(rights, cache)
+}
+
+/// Remap an `any` capability with `Mappable::remap_page`.
+pub fn remap_page_any(any: ManagedArcAny, rights: PageRights, cache: CachePolicy) -> Option<()> {
+    doto_any!(any, remap_page, rights, cache)
+}
+
+fn derive_page<T: Mappable>(cap: T, untyped: &mut UntypedDescriptor) -> Option<ManagedArcAny> {
+    cap.derive_page(untyped)
+}
+
+/// Derive a copy of an `any` capability with `Mappable::derive_page`.
+pub fn derive_page_any(any: ManagedArcAny, untyped: &mut UntypedDescriptor) -> Option<ManagedArcAny> {
+    doto_any!(any, derive_page, untyped)
+}
+
+fn revoke_page<T: Mappable>(cap: T) -> Option<()> {
+    cap.revoke_page()
+}
+
+/// Revoke the copies of an `any` capability with
+/// `Mappable::revoke_page`.
+pub fn revoke_page_any(any: ManagedArcAny) -> Option<()> {
+    doto_any!(any, revoke_page)
+}
@@ -1,10 +1,12 @@
 use common::*;
 use core::iter::Iterator;
+use core::mem;
 use util::{RwLock, Mutex};
 use util::managed_arc::{ManagedArc, ManagedArcAny, ManagedWeakPool3Arc};
-use arch::{TaskRuntime, Exception};
+use arch::{self, TaskRuntime, Exception};
+use abi::{CapType, CapInfo, CapDetail, TaskState};
 
-use super::{UntypedDescriptor, TopPageTableCap, CPoolCap, TaskBufferPageCap, ChannelCap};
+use super::{UntypedDescriptor, TopPageTableCap, CPoolCap, TaskBufferPageCap, ChannelCap, Identify};
 
 /// Switch to an idle task that runs in kernel-mode. This is used when
 /// no other tasks is runnable. Like normal context switching, this
@@ -18,7 +20,9 @@ pub fn idle() -> Exception {
 
     let mut task_runtime = TaskRuntime::default();
     task_runtime.set_instruction_pointer(VAddr::from(idle_task as *const () as u64));
+    task_runtime.set_stack_pointer(arch::idle_stack());
 
+    arch::set_current_task(None);
     unsafe {
         task_runtime.switch_to(false)
     }
@@ -30,6 +34,35 @@ pub enum TaskStatus {
     Active,
     ChannelWait(ChannelCap),
     Inactive,
+    /// Stopped by the kernel. The task is not scheduled again.
+    Faulted,
+    /// Stopped by the task itself, with the given exit code. The task
+    /// is not scheduled again unless it is set active, which should
+    /// only be done after setting its instruction and stack pointers.
+    Dead(u64),
+}
+
+impl TaskStatus {
+    /// The status as reported to user-space.
+    pub fn state(&self) -> TaskState {
+        match *self {
+            TaskStatus::Active => TaskState::Active,
+            TaskStatus::ChannelWait(_) => TaskState::ChannelWait,
+            TaskStatus::Inactive => TaskState::Inactive,
+            TaskStatus::Faulted => TaskState::Faulted,
+            TaskStatus::Dead(code) => TaskState::Dead(code),
+        }
+    }
+}
+
+/// Changes to the runtime of a task made while it runs. They are
+/// applied again when the task returns to the kernel, as its runtime
+/// is then written back from the CPU that ran it.
+#[derive(Debug, Default)]
+struct PendingRuntime {
+    instruction_pointer: Option<VAddr>,
+    stack_pointer: Option<VAddr>,
+    tls_base: Option<(u64, u64)>,
 }
 
 /// Task descriptor.
@@ -37,9 +70,17 @@ pub enum TaskStatus {
 pub struct TaskDescriptor {
     weak_pool: ManagedWeakPool3Arc,
     runtime: TaskRuntime,
+    /// Whether the task is running, with its runtime copied out by
+    /// `switch_to`.
+    running: bool,
+    pending: PendingRuntime,
     next: Option<ManagedArcAny>,
     next_task: Option<TaskCap>,
-    status: TaskStatus
+    status: TaskStatus,
+    /// Whether a CPU has claimed the task to run it.
+    claimed: bool,
+    /// Bit mask of the CPUs the task may run on.
+    affinity: u64,
 }
 /// Task capability. Reference-counted smart pointer to task
 /// descriptor.
@@ -48,43 +89,123 @@ pub struct TaskDescriptor {
 pub type TaskCap = ManagedArc<RwLock<TaskDescriptor>>;
 
 impl TaskCap {
-    /// Create a task capability from an untyped capability.
-    pub fn retype_from(untyped: &mut UntypedDescriptor) -> Self {
-        let mut arc: Option<Self> = None;
+    /// Create a task capability from an untyped capability. Returns
+    /// `None` if the untyped capability does not have enough space
+    /// left.
+    pub fn retype_from(untyped: &mut UntypedDescriptor) -> Option<Self> {
+        let arc = untyped.transaction(|untyped| {
+            let mut arc: Option<Self> = None;
+
+            let weak_pool = unsafe { ManagedWeakPool3Arc::create(
+                untyped.allocate(ManagedWeakPool3Arc::inner_length(),
+                                 ManagedWeakPool3Arc::inner_alignment())?) };
+
+            let fpu_area = unsafe { untyped.allocate(arch::FPU_AREA_LENGTH,
+                                                     arch::FPU_AREA_ALIGNMENT)? };
+            unsafe { arch::init_fpu_area(fpu_area) };
+            let kernel_stack = unsafe { untyped.allocate(arch::KERNEL_STACK_LENGTH,
+                                                         arch::KERNEL_STACK_ALIGNMENT)? };
+            let mut runtime = TaskRuntime::default();
+            runtime.set_fpu_area(fpu_area);
+            runtime.set_kernel_stack(kernel_stack);
+
+            unsafe { untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
+                arc = Some(
+                    Self::new(paddr, RwLock::new(TaskDescriptor {
+                        weak_pool: weak_pool,
+                        runtime: runtime,
+                        running: false,
+                        pending: PendingRuntime::default(),
+                        next: next_child,
+                        next_task: None,
+                        status: TaskStatus::Inactive,
+                        claimed: false,
+                        affinity: !0,
+                    }))
+                );
+
+                arc.clone().unwrap().into()
+            })? };
+
+            arc
+        });
 
-        let weak_pool = unsafe { ManagedWeakPool3Arc::create(
-            untyped.allocate(ManagedWeakPool3Arc::inner_length(),
-                             ManagedWeakPool3Arc::inner_alignment())) };
+        if let Some(ref arc) = arc {
+            register_task(arc.clone());
+        }
 
-        unsafe { untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
-            arc = Some(
-                Self::new(paddr, RwLock::new(TaskDescriptor {
-                    weak_pool: weak_pool,
-                    runtime: TaskRuntime::default(),
-                    next: next_child,
-                    next_task: None,
-                    status: TaskStatus::Inactive,
-                }))
-            );
+        arc
+    }
 
-            arc.clone().unwrap().into()
-        }) };
+    /// Switch to the task. The function is returned when exception
+    /// happens. The task must have been claimed by the current CPU.
+    ///
+    /// The task lock is not held while the task runs, so that other
+    /// CPUs can still access the task. Changes to the instruction
+    /// pointer, stack pointer and TLS base made in the meantime are
+    /// applied after the runtime is written back.
+    pub fn switch_to(&self) -> Exception {
+        let (pml4, mut runtime) = {
+            let mut task = self.write();
+            assert!(task.claimed);
+            task.running = true;
+            (task.upgrade_top_page_table(), task.runtime.clone())
+        };
 
-        register_task(arc.clone().unwrap());
+        if let Some(pml4) = pml4 {
+            pml4.write().switch_to();
+        }
+        arch::set_current_task(Some(self.paddr()));
+        let exception = unsafe { runtime.switch_to(true) };
 
-        arc.unwrap()
+        let mut task = self.write();
+        task.runtime = runtime;
+        task.running = false;
+        let pending = mem::replace(&mut task.pending, PendingRuntime::default());
+        if let Some(instruction_pointer) = pending.instruction_pointer {
+            task.runtime.set_instruction_pointer(instruction_pointer);
+        }
+        if let Some(stack_pointer) = pending.stack_pointer {
+            task.runtime.set_stack_pointer(stack_pointer);
+        }
+        if let Some((fs_base, gs_base)) = pending.tls_base {
+            let _ = task.runtime.set_tls_base(fs_base, gs_base);
+        }
+        exception
     }
 }
 
 impl TaskDescriptor {
     /// Set the task's instruction pointer.
     pub fn set_instruction_pointer(&mut self, instruction_pointer: VAddr) {
-        self.runtime.set_instruction_pointer(instruction_pointer)
+        self.runtime.set_instruction_pointer(instruction_pointer);
+        if self.running {
+            self.pending.instruction_pointer = Some(instruction_pointer);
+        }
     }
 
     /// Set the task's stack pointer.
     pub fn set_stack_pointer(&mut self, stack_pointer: VAddr) {
-        self.runtime.set_stack_pointer(stack_pointer)
+        self.runtime.set_stack_pointer(stack_pointer);
+        if self.running {
+            self.pending.stack_pointer = Some(stack_pointer);
+        }
+    }
+
+    /// Set the task's FS and GS base. Returns `None` if a base is not
+    /// a valid user address.
+    pub fn set_tls_base(&mut self, fs_base: u64, gs_base: u64) -> Option<()> {
+        self.runtime.set_tls_base(fs_base, gs_base)?;
+        if self.running {
+            self.pending.tls_base = Some((fs_base, gs_base));
+        }
+        Some(())
+    }
+
+    /// Set the value returned to the task from a register system
+    /// call.
+    pub fn set_return_value(&mut self, value: u64) {
+        self.runtime.set_return_value(value)
     }
 
     /// Set the task's root capability pool.
@@ -127,13 +248,43 @@ impl TaskDescriptor {
         self.status = status;
     }
 
-    /// Switch to the task. The function is returned when exception
-    /// happens.
-    pub fn switch_to(&mut self) -> Exception {
-        if let Some(pml4) = self.upgrade_top_page_table() {
-            pml4.write().switch_to();
+    /// Bit mask of the CPUs the task may run on.
+    pub fn affinity(&self) -> u64 {
+        self.affinity
+    }
+
+    /// Set the bit mask of the CPUs the task may run on.
+    pub fn set_affinity(&mut self, affinity: u64) {
+        self.affinity = affinity;
+    }
+
+    /// Claim the task for the current CPU, so that no other CPU runs
+    /// it at the same time. Returns `false` if the task is already
+    /// claimed, or if its affinity excludes the current CPU.
+    pub fn claim(&mut self) -> bool {
+        if self.claimed || self.affinity & (1 << arch::cpu_index()) == 0 {
+            false
+        } else {
+            self.claimed = true;
+            true
+        }
+    }
+
+    /// Release a task claimed with `claim`.
+    pub fn release(&mut self) {
+        assert!(self.claimed);
+        self.claimed = false;
+    }
+}
+
+impl Identify for TaskCap {
+    fn identify(&self) -> CapInfo {
+        CapInfo {
+            cap_type: CapType::Task,
+            paddr: self.paddr().into(),
+            length: Self::inner_length(),
+            detail: CapDetail::Task(self.read().status().state()),
         }
-        unsafe { self.runtime.switch_to(true) }
     }
 }
 
@@ -142,7 +293,8 @@ static FIRST_TASK: Mutex<Option<TaskCap>> = Mutex::new(None);
 
 /// Register a new task. Using `FIRST_TASK` static, this forms a
 /// linked-list that allows an iterator to iterate over all created
-/// tasks.
+/// tasks. New tasks are inserted after the first task, so iterators
+/// on other CPUs are not invalidated.
 fn register_task(cap: TaskCap) {
     let mut first_task = FIRST_TASK.lock();
     if first_task.is_none() {
@@ -178,6 +330,21 @@ impl Iterator for TaskIterator {
     }
 }
 
+/// Wake up idle CPUs that may run a task waiting on `chan`, after a
+/// value is put in the channel.
+pub fn wake_channel_waiters(chan: &ChannelCap) {
+    let mut mask = 0;
+    for task_cap in task_iter() {
+        let task = task_cap.read();
+        if let TaskStatus::ChannelWait(ref waiting) = task.status {
+            if waiting.paddr() == chan.paddr() {
+                mask |= task.affinity;
+            }
+        }
+    }
+    arch::wake_up(mask);
+}
+
 /// Return a task iterator using `FIRST_TASK`.
 pub fn task_iter() -> TaskIterator {
     TaskIterator {
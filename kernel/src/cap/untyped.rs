@@ -1,6 +1,8 @@
 use common::*;
 use util::{RwLock, align_up};
 use util::managed_arc::{ManagedArc, ManagedArcAny};
+use abi::{UntypedInfo, CapType, CapInfo, CapDetail};
+use super::Identify;
 
 /// Untyped descriptor.
 #[derive(Debug)]
@@ -8,7 +10,9 @@ pub struct UntypedDescriptor {
     start_paddr: PAddr,
     length: usize,
     watermark: PAddr,
-    first_child: Option<ManagedArcAny>
+    first_child: Option<ManagedArcAny>,
+    child_count: usize,
+    next: Option<ManagedArcAny>,
 }
 /// Untyped capability. Reference-counted smart pointer to untyped
 /// descriptor.
@@ -35,8 +39,40 @@ impl UntypedCap {
             length: length,
             watermark: des_paddr + UntypedCap::inner_length(),
             first_child: None,
+            child_count: 0,
+            next: None,
         }))
     }
+
+    /// Create a child untyped capability of `1 << bits` bytes from
+    /// an untyped capability. The child region is aligned to its
+    /// length, and its descriptor is allocated in the parent, so the
+    /// whole child region is free.
+    pub fn retype_from(untyped: &mut UntypedDescriptor, bits: usize) -> Option<Self> {
+        untyped.transaction(|untyped| {
+            let mut arc: Option<Self> = None;
+
+            let length = 1 << bits;
+            let start_paddr = unsafe { untyped.allocate(length, length)? };
+
+            unsafe { untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
+                arc = Some(
+                    Self::new(paddr, RwLock::new(UntypedDescriptor {
+                        start_paddr: start_paddr,
+                        length: length,
+                        watermark: start_paddr,
+                        first_child: None,
+                        child_count: 0,
+                        next: next_child,
+                    }))
+                );
+
+                arc.clone().unwrap().into()
+            })? };
+
+            arc
+        })
+    }
 }
 
 impl UntypedDescriptor {
@@ -50,21 +86,78 @@ impl UntypedDescriptor {
         self.start_paddr
     }
 
+    /// Current watermark. Memory below it has been allocated.
+    pub fn watermark(&self) -> PAddr {
+        self.watermark
+    }
+
+    /// Number of bytes left above the watermark.
+    pub fn remaining(&self) -> usize {
+        (self.start_paddr + self.length).into(): usize - self.watermark.into(): usize
+    }
+
+    /// Number of capabilities derived from this untyped region.
+    pub fn child_count(&self) -> usize {
+        self.child_count
+    }
+
+    /// Information about this untyped region, as reported to
+    /// user-space.
+    pub fn info(&self) -> UntypedInfo {
+        UntypedInfo {
+            start_paddr: self.start_paddr.into(),
+            length: self.length,
+            watermark: self.watermark.into(),
+            remaining: self.remaining(),
+            child_count: self.child_count,
+        }
+    }
+
     /// Allocate a memory region using the given length and
     /// alignment. Shift the watermark of the current descriptor
-    /// passing over the allocated region.
-    pub unsafe fn allocate(&mut self, length: usize, alignment: usize) -> PAddr {
+    /// passing over the allocated region. Returns `None` if the
+    /// region does not have enough space left.
+    pub unsafe fn allocate(&mut self, length: usize, alignment: usize) -> Option<PAddr> {
         let paddr = align_up(self.watermark, alignment);
-        assert!(paddr + length <= self.start_paddr + self.length);
+        if paddr.into(): usize + length > (self.start_paddr + self.length).into(): usize {
+            return None;
+        }
 
         self.watermark = paddr + length;
-        paddr
+        Some(paddr)
     }
 
     /// Derive and allocate a memory region to a capability that
-    /// requires memory region.
-    pub unsafe fn derive<F>(&mut self, length: usize, alignment: usize, f: F) where F: FnOnce(PAddr, Option<ManagedArcAny>) -> ManagedArcAny {
-        let paddr = self.allocate(length, alignment);
+    /// requires memory region. Returns `None`, without calling `f`,
+    /// if the region does not have enough space left.
+    pub unsafe fn derive<F>(&mut self, length: usize, alignment: usize, f: F) -> Option<()> where F: FnOnce(PAddr, Option<ManagedArcAny>) -> ManagedArcAny {
+        let paddr = self.allocate(length, alignment)?;
         self.first_child = Some(f(paddr, self.first_child.take()));
+        self.child_count += 1;
+        Some(())
+    }
+
+    /// Run `f`, which may do several allocations in this untyped
+    /// region. If `f` returns `None`, the watermark is moved back, so
+    /// that a retype failing half-way does not leak memory.
+    pub fn transaction<R, F>(&mut self, f: F) -> Option<R> where F: FnOnce(&mut UntypedDescriptor) -> Option<R> {
+        let watermark = self.watermark;
+        let result = f(self);
+        if result.is_none() {
+            self.watermark = watermark;
+        }
+        result
+    }
+}
+
+impl Identify for UntypedCap {
+    fn identify(&self) -> CapInfo {
+        let desc = self.read();
+        CapInfo {
+            cap_type: CapType::Untyped,
+            paddr: desc.start_paddr().into(),
+            length: desc.length(),
+            detail: CapDetail::Untyped(desc.info()),
+        }
     }
 }
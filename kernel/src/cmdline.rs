@@ -0,0 +1,117 @@
+use core::{fmt, str};
+use logging::Level;
+
+/// Maximum length of a module name given on the command line.
+pub const MODULE_NAME_LENGTH: usize = 64;
+
+/// A module name given on the command line. The multiboot structure
+/// is no longer accessible after paging is initialized, so the name
+/// is copied out into a fixed-size buffer.
+#[derive(Clone, Copy)]
+pub struct ModuleName {
+    length: usize,
+    data: [u8; MODULE_NAME_LENGTH],
+}
+
+impl ModuleName {
+    /// Create a new module name. Returns `None` if the name is empty
+    /// or too long.
+    fn new(name: &str) -> Option<ModuleName> {
+        if name.len() == 0 || name.len() > MODULE_NAME_LENGTH {
+            return None;
+        }
+
+        let mut data = [0u8; MODULE_NAME_LENGTH];
+        data[..name.len()].copy_from_slice(name.as_bytes());
+        Some(ModuleName { length: name.len(), data: data })
+    }
+
+    /// The module name as a string.
+    pub fn as_str(&self) -> &str {
+        unsafe { str::from_utf8_unchecked(&self.data[..self.length]) }
+    }
+
+    /// Check whether a multiboot module string refers to this
+    /// name. Only the first word of the module string is considered,
+    /// and it matches either in full, by its last path component, or
+    /// by its last path component without extension.
+    pub fn matches(&self, string: &str) -> bool {
+        let path = string.split(' ').next().unwrap_or("");
+        let file = path.rsplit('/').next().unwrap_or("");
+        let stem = file.split('.').next().unwrap_or("");
+
+        path == self.as_str() || file == self.as_str() || stem == self.as_str()
+    }
+}
+
+impl fmt::Debug for ModuleName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+/// Kernel options parsed from the multiboot command line. Options
+/// are space-separated `key=value` pairs. Words without `=`, unknown
+/// keys and malformed values are ignored.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KernelOptions {
+    /// `log=error|info|debug`: the most verbose level of kernel log
+    /// messages to print.
+    pub log_level: Option<Level>,
+    /// `serial=<port>`: I/O port of the serial console used for
+    /// kernel logging.
+    pub serial_port: Option<u16>,
+    /// `timer_hz=<hz>`: frequency of the scheduler timer.
+    pub timer_hz: Option<u32>,
+    /// `init=<name>`: the multiboot module to start as the rinit
+    /// program.
+    pub init: Option<ModuleName>,
+    /// `test=<name>`: the multiboot module to start as a test
+    /// program. Takes precedence over `init`.
+    pub test: Option<ModuleName>,
+}
+
+impl KernelOptions {
+    /// Parse kernel options from a command line.
+    pub fn parse(command_line: &str) -> KernelOptions {
+        let mut options = KernelOptions::default();
+
+        for word in command_line.split(' ') {
+            let mut pair = word.splitn(2, '=');
+            let key = pair.next().unwrap();
+            let value = match pair.next() {
+                Some(value) => value,
+                None => continue,
+            };
+
+            match key {
+                "log" => options.log_level = Level::parse(value),
+                "serial" => options.serial_port = parse_number(value)
+                    .and_then(|port| if port <= 0xffff { Some(port as u16) } else { None }),
+                "timer_hz" => options.timer_hz = parse_number(value)
+                    .and_then(|hz| if hz > 0 && hz <= 0xffffffff { Some(hz as u32) } else { None }),
+                "init" => options.init = ModuleName::new(value),
+                "test" => options.test = ModuleName::new(value),
+                _ => (),
+            }
+        }
+
+        options
+    }
+
+    /// Name of the module that should be started as the rinit
+    /// program, if it is not simply the first module.
+    pub fn init_module(&self) -> Option<&ModuleName> {
+        self.test.as_ref().or(self.init.as_ref())
+    }
+}
+
+/// Parse a decimal number, or a hexadecimal number prefixed with
+/// `0x`.
+fn parse_number(value: &str) -> Option<u64> {
+    if value.starts_with("0x") {
+        u64::from_str_radix(&value[2..], 16).ok()
+    } else {
+        u64::from_str_radix(value, 10).ok()
+    }
+}
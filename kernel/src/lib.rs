@@ -45,6 +45,9 @@ pub mod unwind;
 /// Logging writer for use with the log macro.
 mod logging;
 
+/// Kernel command line options.
+mod cmdline;
+
 /// Utils for managed Arc, spinning guard, memory objects and others.
 #[macro_use]
 mod util;
@@ -68,20 +71,28 @@ use common::*;
 use arch::{InitInfo, Exception};
 use cap::{UntypedCap, CPoolCap, RawPageCap, TaskBufferPageCap, TopPageTableCap, TaskCap, TaskStatus, ChannelCap, ChannelValue, PAGE_LENGTH};
 use core::ops::DerefMut;
-use abi::SystemCall;
-use util::MemoryObject;
+use abi::{SystemCall, PageRights, CachePolicy, DecodeError, ABI_VERSION, STATUS_MALFORMED};
+use util::{MemoryObject, Mutex};
 use core::any::TypeId;
 
+/// Channel receiving keyboard input. It is shared by the schedulers
+/// of all CPUs.
+static KEYBOARD_CHANNEL: Mutex<Option<ChannelCap>> = Mutex::new(None);
+
+/// Rights of rinit pages holding data: readable and writable, but not
+/// executable.
+const RINIT_DATA_RIGHTS: PageRights = PageRights { read: true, write: true, execute: false };
+
 /// Map a stack for the rinit program using the given physical address
 /// and stack size.
 fn map_rinit_stack(rinit_stack_vaddr: VAddr, rinit_stack_size: usize,
                    cpool: &mut CPoolCap, untyped: &mut UntypedCap, rinit_pml4: &mut TopPageTableCap) {
     for i in 0..rinit_stack_size {
-        let mut rinit_stack_page = RawPageCap::retype_from(untyped.write().deref_mut());
+        let mut rinit_stack_page = RawPageCap::retype_from(untyped.write().deref_mut()).unwrap();
         cpool.read().downgrade_free(&rinit_stack_page);
         rinit_pml4.map(rinit_stack_vaddr + i * PAGE_LENGTH, &rinit_stack_page,
-                       untyped.write().deref_mut(),
-                       cpool.write().deref_mut());
+                       RINIT_DATA_RIGHTS, CachePolicy::WriteBack,
+                       untyped.write().deref_mut()).unwrap();
     }
 }
 
@@ -89,27 +100,25 @@ fn map_rinit_stack(rinit_stack_vaddr: VAddr, rinit_stack_size: usize,
 fn map_rinit_buffer(rinit_buffer_vaddr: VAddr,
                     cpool: &mut CPoolCap, untyped: &mut UntypedCap, rinit_pml4: &mut TopPageTableCap)
                     -> TaskBufferPageCap {
-    let rinit_buffer_page = TaskBufferPageCap::retype_from(untyped.write().deref_mut());
+    let rinit_buffer_page = TaskBufferPageCap::retype_from(untyped.write().deref_mut()).unwrap();
     cpool.read().downgrade_free(&rinit_buffer_page);
     rinit_pml4.map(rinit_buffer_vaddr, &rinit_buffer_page,
-                   untyped.write().deref_mut(),
-                   cpool.write().deref_mut());
+                   RINIT_DATA_RIGHTS, CachePolicy::WriteBack,
+                   untyped.write().deref_mut()).unwrap();
     return rinit_buffer_page;
 }
 
-/// Bootstrap paging for the rinit program. This creates stacks and
-/// task buffers for both a "parent" and a "child".
+/// Bootstrap paging for the rinit program. This creates its stack
+/// and task buffer. Further threads are created by rinit itself.
 fn bootstrap_rinit_paging(archinfo: &InitInfo, cpool: &mut CPoolCap, untyped: &mut UntypedCap) -> (TopPageTableCap, TaskBufferPageCap, VAddr, VAddr) {
     use elf::{ElfBinary};
 
     let rinit_stack_vaddr = VAddr::from(0x80000000: usize);
-    let rinit_child_stack_vaddr = VAddr::from(0x70000000: usize);
     let rinit_stack_size = 4;
     let rinit_buffer_vaddr = VAddr::from(0x90001000: usize);
     let rinit_vga_vaddr = VAddr::from(0x90002000: usize);
-    let rinit_child_buffer_vaddr = VAddr::from(0x90003000: usize);
 
-    let mut rinit_pml4 = TopPageTableCap::retype_from(untyped.write().deref_mut());
+    let mut rinit_pml4 = TopPageTableCap::retype_from(untyped.write().deref_mut()).unwrap();
     cpool.read().downgrade_free(&rinit_pml4);
 
     let slice_object = unsafe { MemoryObject::<u8>::slice(archinfo.rinit_region().start_paddr(),
@@ -123,24 +132,30 @@ fn bootstrap_rinit_paging(archinfo: &InitInfo, cpool: &mut CPoolCap, untyped: &m
     log!("entry = 0x{:x}", rinit_entry);
 
     for p in bin.program_headers() {
-        use elf::{PT_LOAD};
+        use elf::{PT_LOAD, PF_W, PF_X};
 
         if p.progtype == PT_LOAD {
             log!("pheader = {}", p);
 
+            let rights = PageRights {
+                read: true,
+                write: p.flags.0 & PF_W.0 != 0,
+                execute: p.flags.0 & PF_X.0 != 0,
+            };
+
             let mut next_page_vaddr = VAddr::from(p.vaddr);
             let mut offset = 0x0;
             let end_vaddr = VAddr::from(p.vaddr + p.memsz as usize);
 
             while next_page_vaddr <= end_vaddr {
                 use core::cmp::{min};
-                log!("mapping from: 0x{:x}", next_page_vaddr);
+                debug!("mapping from: 0x{:x}", next_page_vaddr);
 
-                let page_cap = RawPageCap::retype_from(untyped.write().deref_mut());
+                let page_cap = RawPageCap::retype_from(untyped.write().deref_mut()).unwrap();
                 cpool.read().downgrade_free(&page_cap);
                 rinit_pml4.map(next_page_vaddr, &page_cap,
-                               untyped.write().deref_mut(),
-                               cpool.write().deref_mut());
+                               rights, CachePolicy::WriteBack,
+                               untyped.write().deref_mut()).unwrap();
 
                 let mut page = page_cap.write();
                 let page_length = page.length();
@@ -163,27 +178,22 @@ fn bootstrap_rinit_paging(archinfo: &InitInfo, cpool: &mut CPoolCap, untyped: &m
     log!("mapping the rinit stack ...");
     map_rinit_stack(rinit_stack_vaddr, rinit_stack_size, cpool, untyped, &mut rinit_pml4);
 
-    log!("mapping the child rinit stack ...");
-    map_rinit_stack(rinit_child_stack_vaddr, rinit_stack_size, cpool, untyped, &mut rinit_pml4);
-
     log!("mapping the rinit task buffer ...");
     let rinit_buffer_page = map_rinit_buffer(rinit_buffer_vaddr, cpool, untyped, &mut rinit_pml4);
-    let rinit_child_buffer_page = map_rinit_buffer(rinit_child_buffer_vaddr, cpool, untyped, &mut rinit_pml4);
-
-    cpool.read().downgrade_at(&rinit_child_buffer_page, 250);
 
     log!("mapping the rinit vga buffer ...");
-    let rinit_vga_page = unsafe { RawPageCap::bootstrap(PAddr::from(0xb8000: usize), untyped.write().deref_mut()) };
+    let rinit_vga_page = unsafe { RawPageCap::bootstrap_device(PAddr::from(0xb8000: usize), untyped.write().deref_mut()).unwrap() };
     cpool.read().downgrade_free(&rinit_vga_page);
     rinit_pml4.map(rinit_vga_vaddr, &rinit_vga_page,
-                   untyped.write().deref_mut(),
-                   cpool.write().deref_mut());
+                   RINIT_DATA_RIGHTS, CachePolicy::WriteBack,
+                   untyped.write().deref_mut()).unwrap();
 
     (rinit_pml4, rinit_buffer_page, VAddr::from(rinit_entry), rinit_stack_vaddr + (PAGE_LENGTH * rinit_stack_size - 4))
 }
 
-/// The kernel main function. It initialize the rinit program, and
-/// then run a loop to switch to all available tasks.
+/// The kernel main function. It initialize the rinit program, starts
+/// the application processors, and then run a loop to switch to all
+/// available tasks.
 #[no_mangle]
 pub fn kmain(archinfo: InitInfo)
 {
@@ -195,7 +205,7 @@ pub fn kmain(archinfo: InitInfo)
 
         let untyped = unsafe { UntypedCap::bootstrap(cpool_target_region.start_paddr(),
                                                      cpool_target_region.length()) };
-        let cpool = CPoolCap::retype_from(untyped.write().deref_mut());
+        let cpool = CPoolCap::retype_from(untyped.write().deref_mut()).unwrap();
 
         cpool.read().downgrade_at(&cpool, 0);
         cpool.read().downgrade_free(&untyped);
@@ -229,7 +239,7 @@ pub fn kmain(archinfo: InitInfo)
     {
         let (rinit_pml4, rinit_buffer_page, rinit_entry, rinit_stack) =
             bootstrap_rinit_paging(&archinfo, &mut cpool_cap, &mut untyped_cap);
-        let rinit_task_cap = TaskCap::retype_from(untyped_cap.write().deref_mut());
+        let rinit_task_cap = TaskCap::retype_from(untyped_cap.write().deref_mut()).unwrap();
         let mut rinit_task = rinit_task_cap.write();
         rinit_task.set_instruction_pointer(rinit_entry);
         rinit_task.set_stack_pointer(rinit_stack);
@@ -239,96 +249,207 @@ pub fn kmain(archinfo: InitInfo)
         rinit_task.downgrade_buffer(&rinit_buffer_page);
     }
 
-    let keyboard_cap = ChannelCap::retype_from(untyped_cap.write().deref_mut());
+    let keyboard_cap = ChannelCap::retype_from(untyped_cap.write().deref_mut()).unwrap();
     cpool_cap.read().downgrade_at(&keyboard_cap, 254);
+    *KEYBOARD_CHANNEL.lock() = Some(keyboard_cap);
 
-    let util_chan_cap = ChannelCap::retype_from(untyped_cap.write().deref_mut());
+    let util_chan_cap = ChannelCap::retype_from(untyped_cap.write().deref_mut()).unwrap();
     cpool_cap.read().downgrade_at(&util_chan_cap, 255);
 
     log!("hello, world!");
-    arch::enable_timer();
+    arch::enable_timer(archinfo.options().timer_hz);
+    arch::start_application_processors();
+
+    schedule()
+}
+
+/// The kernel main function of application processors. It runs the
+/// same loop as the bootstrap processor.
+pub fn kmain_ap() -> ! {
+    schedule()
+}
+
+/// Loop to switch to all available tasks. It runs on every CPU, and
+/// a task is claimed before it runs so that it runs on at most one
+/// CPU at a time.
+fn schedule() -> ! {
     loop {
         let mut idle = true;
+        // Marked idle before looking for tasks, so that wakeups sent
+        // in the meantime are not lost.
+        arch::set_idle(true);
 
         for task_cap in cap::task_iter() {
-            let status = task_cap.read().status();
-            let exception = match status {
-                TaskStatus::Inactive => None,
-                TaskStatus::Active => {
-                    idle = false;
-                    Some(task_cap.write().switch_to())
-                },
-                TaskStatus::ChannelWait(ref chan) => {
-                    let value = chan.write().take();
-                    if let Some(value) = value {
-                        let system_call: SystemCall = {
-                            let buffer_cap = task_cap.read().upgrade_buffer().unwrap();
-                            let buffer_desc = buffer_cap.read();
-                            let buffer = buffer_desc.read();
-                            buffer.call.clone().unwrap()
-                        };
-                        let ret_system_call = match system_call {
-                            SystemCall::ChannelTake {
-                                request, ..
-                            } => {
-                                Some(SystemCall::ChannelTake {
-                                    request: request,
-                                    response: Some(ChannelValue::to_message(
-                                        value,
-                                        task_cap.clone()))
-                                })
-                            }
-                            _ => panic!(),
-                        };
-                        if ret_system_call.is_some() {
-                            let buffer_cap = task_cap.read().upgrade_buffer().unwrap();
-                            let mut buffer_desc = buffer_cap.write();
-                            let mut buffer = buffer_desc.write();
-                            buffer.call = ret_system_call;
-                        }
+            if !task_cap.write().claim() {
+                continue;
+            }
+            if run_task(&task_cap) {
+                idle = false;
+            }
+            task_cap.write().release();
+        }
+
+        if idle {
+            let exception = cap::idle();
+            match exception {
+                Exception::Keyboard => handle_keyboard(),
+                _ => (),
+            }
+        }
+        arch::set_idle(false);
+    }
+}
+
+/// Write the reply to a system call into the task buffer. A reply
+/// that does not fit stops the task, and returns `None`.
+fn write_reply(task_cap: &TaskCap, buffer_cap: &TaskBufferPageCap, reply: SystemCall) -> Option<()> {
+    let call = reply.encode();
+    {
+        let mut buffer_desc = buffer_cap.write();
+        let mut buffer = buffer_desc.write();
+        match call {
+            Ok(call) => buffer.call = call,
+            Err(_) => buffer.call.status = STATUS_MALFORMED,
+        }
+    }
+
+    if call.is_err() {
+        log!("System call reply does not fit in the task buffer, stopping the task.");
+        task_cap.write().set_status(TaskStatus::Faulted);
+        return None;
+    }
+    Some(())
+}
+
+/// Run a claimed task if it is runnable, and handle the exception
+/// that returned it to the kernel. Returns whether the task has run.
+fn run_task(task_cap: &TaskCap) -> bool {
+    let status = task_cap.read().status();
+    let exception = match status {
+        TaskStatus::Inactive | TaskStatus::Faulted | TaskStatus::Dead(_) => None,
+        TaskStatus::Active => Some(task_cap.switch_to()),
+        TaskStatus::ChannelWait(ref chan) => {
+            let buffer_cap = task_cap.read().upgrade_buffer();
+            let request = buffer_cap.as_ref().and_then(|buffer_cap| {
+                let buffer_desc = buffer_cap.read();
+                let buffer = buffer_desc.read();
+                match SystemCall::decode(&buffer.call) {
+                    Ok(SystemCall::ChannelTake { request, .. }) => Some(request),
+                    _ => None,
+                }
+            });
+            if buffer_cap.is_none() || request.is_none() {
+                log!("Task buffer no longer holds a channel take, stopping the task.");
+                task_cap.write().set_status(TaskStatus::Faulted);
+                None
+            } else {
+                let value = chan.write().take();
+                if let Some(value) = value {
+                    let request = request.unwrap();
+                    let response = ChannelValue::to_message(value, task_cap.clone(), &request.1);
+                    let reply = SystemCall::ChannelTake {
+                        request: request,
+                        response: Some(response),
+                    };
+                    if write_reply(task_cap, &buffer_cap.unwrap(), reply).is_some() {
                         task_cap.write().set_status(TaskStatus::Active);
-                        Some(task_cap.write().switch_to())
+                        Some(task_cap.switch_to())
                     } else {
                         None
                     }
+                } else {
+                    None
                 }
+            }
+        }
+    };
+    let ran = exception.is_some();
+    match exception {
+        Some(Exception::SystemCall) => {
+            let cpool_cap = task_cap.read().upgrade_cpool();
+            let buffer_cap = task_cap.read().upgrade_buffer();
+            if cpool_cap.is_none() || buffer_cap.is_none() {
+                log!("System call without a capability pool or task buffer, stopping the task.");
+                task_cap.write().set_status(TaskStatus::Faulted);
+                return true;
+            }
+            let cpool_cap = cpool_cap.unwrap();
+            let buffer_cap = buffer_cap.unwrap();
+            let system_call = {
+                let buffer_desc = buffer_cap.read();
+                let buffer = buffer_desc.read();
+                SystemCall::decode(&buffer.call).and_then(|call| {
+                    if system_calls::validate(&call, &buffer) {
+                        Ok(call)
+                    } else {
+                        Err(DecodeError::Malformed)
+                    }
+                })
             };
-            match exception {
-                Some(Exception::SystemCall) => {
-                    let cpool_cap = task_cap.read().upgrade_cpool().unwrap();
-                    let system_call: SystemCall = {
-                        let buffer_cap = task_cap.read().upgrade_buffer().unwrap();
-                        let buffer_desc = buffer_cap.read();
-                        let buffer = buffer_desc.read();
-                        buffer.call.clone().unwrap()
-                    };
+            match system_call {
+                Ok(system_call) => {
+                    // Interrupts arriving during a long system call
+                    // are left pending, and handled once it returns.
+                    unsafe { arch::enable_interrupt() };
                     let ret_system_call = system_calls::handle(
                         system_call,
                         task_cap.clone(),
                         cpool_cap.clone());
+                    unsafe { arch::disable_interrupt() };
                     if ret_system_call.is_some() {
-                        let buffer_cap = task_cap.read().upgrade_buffer().unwrap();
+                        let _ = write_reply(task_cap, &buffer_cap, ret_system_call.unwrap());
+                    }
+                },
+                Err(error) => {
+                    log!("Malformed system call ({:?}), stopping the task.", error);
+                    {
                         let mut buffer_desc = buffer_cap.write();
                         let mut buffer = buffer_desc.write();
-                        buffer.call = ret_system_call;
+                        buffer.call.version = ABI_VERSION;
+                        buffer.call.status = error.status();
                     }
+                    task_cap.write().set_status(TaskStatus::Faulted);
                 },
-                Some(Exception::Keyboard) => {
-                    keyboard_cap.write().put(ChannelValue::Raw(unsafe { arch::inportb(0x60) } as u64));
-                },
-                _ => (),
             }
-        }
-
-        if idle {
-            let exception = cap::idle();
-            match exception {
-                Exception::Keyboard => {
-                    keyboard_cap.write().put(ChannelValue::Raw(unsafe { arch::inportb(0x60) } as u64));
-                },
-                _ => (),
+        },
+        Some(Exception::RegisterSystemCall { number, args }) => {
+            let result = match task_cap.read().upgrade_cpool() {
+                Some(cpool_cap) => system_calls::handle_register(number, args, cpool_cap),
+                None => 1,
+            };
+            task_cap.write().set_return_value(result);
+        },
+        Some(Exception::Keyboard) => handle_keyboard(),
+        Some(Exception::PageFault { vaddr, present, write }) => {
+            let resolved = present && write && task_cap.read().upgrade_top_page_table()
+                .and_then(|pml4| pml4.resolve_copy_on_write(vaddr))
+                .is_some();
+            if !resolved {
+                log!("Page fault at 0x{:x}, stopping the task.", vaddr);
+                task_cap.write().set_status(TaskStatus::Faulted);
             }
-        }
+        },
+        Some(Exception::DeviceNotAvailable) => {
+            log!("FPU used by a task without an FPU area, stopping the task.");
+            task_cap.write().set_status(TaskStatus::Faulted);
+        },
+        Some(Exception::Fault { vector, error_code }) => {
+            log!("Exception 0x{:x} with error code {:?} in a task, stopping the task.",
+                 vector, error_code);
+            task_cap.write().set_status(TaskStatus::Faulted);
+        },
+        _ => (),
+    }
+    ran
+}
+
+/// Put the pending keyboard scan code in the keyboard channel.
+fn handle_keyboard() {
+    let value = unsafe { arch::inportb(0x60) } as u64;
+    let keyboard_cap = KEYBOARD_CHANNEL.lock().clone();
+    if let Some(keyboard_cap) = keyboard_cap {
+        keyboard_cap.write().put(ChannelValue::Raw(value));
+        cap::wake_channel_waiters(&keyboard_cap);
     }
 }
 
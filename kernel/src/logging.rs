@@ -4,16 +4,62 @@ use core::fmt;
 /// A formatter object
 pub struct Writer(bool);
 
+/// Level of a log message, ordered from the least to the most
+/// verbose.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+	Error = 0,
+	Info = 1,
+	Debug = 2,
+}
+
+impl Level {
+	/// Parse a level from its name, as used by the `log=` kernel
+	/// option.
+	pub fn parse(name: &str) -> Option<Level> {
+		match name {
+			"error" => Some(Level::Error),
+			"info" => Some(Level::Info),
+			"debug" => Some(Level::Debug),
+			_ => None,
+		}
+	}
+}
+
+/// The most verbose level that is currently printed.
+static MAX_LEVEL: atomic::AtomicUsize = atomic::AtomicUsize::new(Level::Info as usize);
+
+/// Set the most verbose level of messages to be printed.
+pub fn set_max_level(level: Level) {
+	MAX_LEVEL.store(level as usize, atomic::Ordering::Relaxed);
+}
+
 /// A primitive lock for the logging output
 ///
 /// This is not really a lock. Since there is no threading at the moment, all
 /// it does is prevent writing when a collision would occur.
 static LOGGING_LOCK: atomic::AtomicBool = atomic::ATOMIC_BOOL_INIT;
 
+/// Release the logging lock, even if it is held by code that was
+/// interrupted and will not run again, so that a fatal error can
+/// still be reported.
+///
+/// # Safety
+///
+/// Messages being written may be interleaved with the next one.
+pub unsafe fn force_unlock() {
+	LOGGING_LOCK.store(false, atomic::Ordering::Release);
+}
+
 impl Writer
 {
-	/// Obtain a logger for the specified module
-	pub fn get(module: &str) -> Writer {
+	/// Obtain a logger for the specified module. If the level is
+	/// filtered out, the returned logger discards all output.
+	pub fn get(module: &str, level: Level) -> Writer {
+		if level as usize > MAX_LEVEL.load(atomic::Ordering::Relaxed) {
+			return Writer(false);
+		}
+
 		// This "acquires" the lock (actually just disables output if paralel writes are attempted
 		let mut ret = Writer( ! LOGGING_LOCK.swap(true, atomic::Ordering::Acquire) );
 		
@@ -6,6 +6,24 @@ macro_rules! log{
 	( $($arg:tt)* ) => ({
 		// Import the Writer trait (required by write!)
 		use core::fmt::Write;
-		let _ = write!(&mut ::logging::Writer::get(module_path!()), $($arg)*);
+		let _ = write!(&mut ::logging::Writer::get(module_path!(), ::logging::Level::Info), $($arg)*);
+	})
+}
+
+/// Log a message at the debug level. These are only printed with the
+/// `log=debug` kernel option.
+macro_rules! debug{
+	( $($arg:tt)* ) => ({
+		use core::fmt::Write;
+		let _ = write!(&mut ::logging::Writer::get(module_path!(), ::logging::Level::Debug), $($arg)*);
+	})
+}
+
+/// Log a message at the error level. These are printed unless
+/// logging is turned off entirely.
+macro_rules! error{
+	( $($arg:tt)* ) => ({
+		use core::fmt::Write;
+		let _ = write!(&mut ::logging::Writer::get(module_path!(), ::logging::Level::Error), $($arg)*);
 	})
 }
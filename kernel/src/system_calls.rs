@@ -1,7 +1,99 @@
 use common::*;
+use core::any::Any;
 use core::ops::DerefMut;
-use cap::{self, UntypedCap, CPoolCap, RawPageCap, TaskBufferPageCap, TopPageTableCap, TaskCap, TaskStatus, ChannelCap, ChannelValue};
-use abi::SystemCall;
+use cap::{self, UntypedDescriptor, UntypedCap, CPoolCap, RawPageCap, TaskBufferPageCap, TopPageTableCap, TaskCap, TaskStatus, ChannelCap, ChannelValue};
+use arch::cap::{PDPTCap, PDCap, PTCap, LargePageCap, HugePageCap};
+use util::managed_arc::{ManagedArc, ManagedArcAny};
+use abi::{SystemCall, CapType, PageRights, CachePolicy, VSpaceMapping,
+          TaskBuffer, ChannelMessage, REGISTER_CALL_CHANNEL_PUT_RAW};
+
+/// Smallest size, in bits, of an untyped capability created by
+/// `UntypedSplit`.
+const UNTYPED_SPLIT_MIN_BITS: usize = 12;
+/// Largest size, in bits, of an untyped capability created by
+/// `UntypedSplit`.
+const UNTYPED_SPLIT_MAX_BITS: usize = 47;
+
+/// Retype `count` capabilities using `f` from an untyped capability,
+/// and put them into consecutive slots starting at `target`. Nothing
+/// is retyped unless all target slots are empty. Stops at the first
+/// capability that does not fit in the untyped region.
+fn retype_n<T: Any, F>(source: &UntypedCap, cpool: &CPoolCap, target: CAddr, count: usize, f: F)
+    where ManagedArc<T>: Any, F: Fn(&mut UntypedDescriptor) -> Option<ManagedArc<T>> {
+    if !cpool.lookup_free(target, count) {
+        log!("Retype target slots are not free.");
+        return;
+    }
+
+    for i in 0..count {
+        let cap = match f(source.write().deref_mut()) {
+            Some(cap) => cap,
+            None => {
+                log!("Untyped region exhausted after {} of {} retypes.", i, count);
+                return;
+            },
+        };
+        cpool.lookup_downgrade_at(&cap, target.offset(i).unwrap());
+    }
+}
+
+/// Derive a copy of a page for `PageDerive`, and put it at `target`.
+fn page_derive(page: ManagedArcAny, untyped: &UntypedCap, cpool: &CPoolCap, target: CAddr) {
+    if !cpool.lookup_free(target, 1) {
+        log!("Page derive target slot is not free.");
+        cap::drop_any(page);
+        return;
+    }
+
+    match cap::derive_page_any(page, untyped.write().deref_mut()) {
+        Some(copy) => cpool.lookup_downgrade_any_at(copy, target),
+        None => log!("Page derive failed."),
+    }
+}
+
+/// Register system call handling function, for calls made with
+/// `syscall` and their arguments in registers. Returns the value put
+/// in the task's `rax`: 0 on success, and 1 on failure.
+pub fn handle_register(number: u64, args: [u64; 4], cpool: CPoolCap) -> u64 {
+    match number {
+        REGISTER_CALL_CHANNEL_PUT_RAW => {
+            let chan_option: Option<ChannelCap> = cpool.lookup_upgrade(CAddr::from_raw(args[0], args[1]));
+            if let Some(chan) = chan_option {
+                chan.write().put(ChannelValue::Raw(args[2]));
+                cap::wake_channel_waiters(&chan);
+                0
+            } else {
+                1
+            }
+        },
+        _ => {
+            log!("Unknown register system call {}.", number);
+            1
+        },
+    }
+}
+
+/// Check a decoded system call against the task buffer it was read
+/// from, before it is handled. `SystemCall::decode` only checks the
+/// encoding, so this catches requests that are well-formed but out of
+/// range.
+pub fn validate(call: &SystemCall, buffer: &TaskBuffer) -> bool {
+    match *call {
+        SystemCall::Print { request } => {
+            request.1 <= request.0.len() &&
+                ::core::str::from_utf8(&request.0[0..request.1]).is_ok()
+        },
+        SystemCall::TaskExit { request } => {
+            request.2 <= request.1.len() &&
+                ::core::str::from_utf8(&request.1[0..request.2]).is_ok()
+        },
+        SystemCall::ChannelPut { request: (_, ChannelMessage::Payload) } |
+        SystemCall::ChannelPut { request: (_, ChannelMessage::Compound(_)) } => {
+            buffer.payload().is_some()
+        },
+        _ => true,
+    }
+}
 
 /// System call handling function. Dispatch based on the type of the
 /// system call.
@@ -53,8 +145,10 @@ pub fn handle(call: SystemCall, task_cap: TaskCap, cpool: CPoolCap) -> Option<Sy
             use core::str;
             let buffer = request.0.clone();
             let slice = &buffer[0..request.1];
-            let s = str::from_utf8(slice).unwrap();
-            log!("Userspace print: {}", s);
+            match str::from_utf8(slice) {
+                Ok(s) => log!("Userspace print: {}", s),
+                Err(_) => log!("Userspace print: invalid UTF-8"),
+            }
 
             None
         },
@@ -65,7 +159,7 @@ pub fn handle(call: SystemCall, task_cap: TaskCap, cpool: CPoolCap) -> Option<Sy
             if source.is_some() {
                 let source = source.unwrap();
                 let target = RawPageCap::retype_from(source.write().deref_mut());
-                let result = cpool.read().downgrade_free(&target);
+                let result = target.and_then(|target| cpool.read().downgrade_free(&target));
 
                 Some(SystemCall::RetypeRawPageFree {
                     request: request,
@@ -84,23 +178,159 @@ pub fn handle(call: SystemCall, task_cap: TaskCap, cpool: CPoolCap) -> Option<Sy
             let pml4_cap: Option<TopPageTableCap> = cpool.lookup_upgrade(toplevel_table);
             if page_cap.is_some() && untyped_cap.is_some() && pml4_cap.is_some() {
                 let untyped_cap = untyped_cap.unwrap();
-                pml4_cap.unwrap().map(vaddr, &page_cap.unwrap(),
-                                      untyped_cap.write().deref_mut(),
-                                      cpool.write().deref_mut());
-                log!("Map raw page okay.");
+                let rights = PageRights { read: true, write: true, execute: true };
+                let result = pml4_cap.unwrap().map(vaddr, &page_cap.unwrap(),
+                                                   rights, CachePolicy::WriteBack,
+                                                   untyped_cap.write().deref_mut());
+                if result.is_some() {
+                    log!("Map raw page okay.");
+                } else {
+                    log!("Map raw page failed: untyped region exhausted.");
+                }
             } else {
                 log!("Map raw page failed.");
             }
             None
         }
+        SystemCall::PageMap {
+            untyped, toplevel_table, request, ..
+        } => {
+            let (vaddr, page, rights, cache) = request;
+            let vaddr: VAddr = VAddr::from(vaddr);
+            let untyped_cap: Option<UntypedCap> = cpool.lookup_upgrade(untyped);
+            let pml4_cap: Option<TopPageTableCap> = cpool.lookup_upgrade(toplevel_table);
+            let result = if !rights.read {
+                log!("Page map failed: pages are always readable.");
+                None
+            } else if let (Some(untyped_cap), Some(mut pml4_cap)) = (untyped_cap, pml4_cap) {
+                let result = cpool.lookup_upgrade_any(page).and_then(|page_cap| {
+                    cap::map_page_any(page_cap, &mut pml4_cap, vaddr, rights, cache,
+                                      untyped_cap.write().deref_mut())
+                });
+                if result.is_none() {
+                    log!("Page map failed.");
+                }
+                result
+            } else {
+                log!("Page map failed.");
+                None
+            };
+
+            Some(SystemCall::PageMap {
+                untyped: untyped,
+                toplevel_table: toplevel_table,
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::PageUnmap {
+            request,
+        } => {
+            let result = cpool.lookup_upgrade_any(request).and_then(cap::unmap_page_any);
+            if result.is_none() {
+                log!("Page unmap failed.");
+            }
+            None
+        },
+        SystemCall::PageRemap {
+            request,
+        } => {
+            let (page, rights, cache) = request;
+            let result = if !rights.read {
+                None
+            } else {
+                cpool.lookup_upgrade_any(page).and_then(|page_cap| {
+                    cap::remap_page_any(page_cap, rights, cache)
+                })
+            };
+            if result.is_none() {
+                log!("Page remap failed.");
+            }
+            None
+        },
+        SystemCall::VSpaceLookup {
+            request, ..
+        } => {
+            let (table, vaddr) = request;
+            let pml4_cap: Option<TopPageTableCap> = cpool.lookup_upgrade(table);
+            let mapping = pml4_cap.and_then(|pml4_cap| {
+                pml4_cap.read().translate(VAddr::from(vaddr))
+            }).map(|(paddr, flags, level)| {
+                VSpaceMapping {
+                    paddr: paddr.into(),
+                    flags: flags,
+                    level: level,
+                }
+            });
+
+            Some(SystemCall::VSpaceLookup {
+                request: request,
+                response: mapping,
+            })
+        },
+        SystemCall::VSpaceClone {
+            request, ..
+        } => {
+            let (untyped, source, target, mode) = request;
+            let untyped_cap: Option<UntypedCap> = cpool.lookup_upgrade(untyped);
+            let source_cap: Option<TopPageTableCap> = cpool.lookup_upgrade(source);
+            let result = if let (Some(untyped_cap), Some(source_cap)) = (untyped_cap, source_cap) {
+                let target_cap: Option<TopPageTableCap> = if cpool.lookup_free(target, 1) {
+                    let target_cap = TopPageTableCap::retype_from(untyped_cap.write().deref_mut());
+                    if let Some(ref target_cap) = target_cap {
+                        cpool.lookup_downgrade_at(target_cap, target);
+                    }
+                    target_cap
+                } else {
+                    cpool.lookup_upgrade(target)
+                };
+                target_cap.and_then(|target_cap| source_cap.clone_vspace(&target_cap, &untyped_cap, mode))
+            } else {
+                None
+            };
+            if result.is_none() {
+                log!("Address space clone failed.");
+            }
+
+            Some(SystemCall::VSpaceClone {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::PageDerive {
+            request,
+        } => {
+            let (untyped, page, target) = request;
+            let untyped_cap: Option<UntypedCap> = cpool.lookup_upgrade(untyped);
+            if let Some(untyped_cap) = untyped_cap {
+                match cpool.lookup_upgrade_any(page) {
+                    Some(page_cap) => page_derive(page_cap, &untyped_cap, &cpool, target),
+                    None => log!("Page derive failed: not a page."),
+                }
+            } else {
+                log!("Page derive failed.");
+            }
+            None
+        },
+        SystemCall::PageRevoke {
+            request,
+        } => {
+            let result = cpool.lookup_upgrade_any(request).and_then(cap::revoke_page_any);
+            if result.is_none() {
+                log!("Page revoke failed: not a page.");
+            }
+            None
+        },
         SystemCall::RetypeCPool {
             request,
         } => {
             let source: Option<UntypedCap> = cpool.lookup_upgrade(request.0);
             if source.is_some() {
                 let source = source.unwrap();
-                let target = CPoolCap::retype_from(source.write().deref_mut());
-                let _ = cpool.lookup_downgrade_at(&target, request.1);
+                match CPoolCap::retype_from(source.write().deref_mut()) {
+                    Some(target) => { let _ = cpool.lookup_downgrade_at(&target, request.1); },
+                    None => log!("Retype failed: untyped region exhausted."),
+                }
             }
 
             None
@@ -111,12 +341,156 @@ pub fn handle(call: SystemCall, task_cap: TaskCap, cpool: CPoolCap) -> Option<Sy
             let source: Option<UntypedCap> = cpool.lookup_upgrade(request.0);
             if source.is_some() {
                 let source = source.unwrap();
-                let target = TaskCap::retype_from(source.write().deref_mut());
-                let _ = cpool.lookup_downgrade_at(&target, request.1);
+                match TaskCap::retype_from(source.write().deref_mut()) {
+                    Some(target) => { let _ = cpool.lookup_downgrade_at(&target, request.1); },
+                    None => log!("Retype failed: untyped region exhausted."),
+                }
+            }
+
+            None
+        },
+        SystemCall::RetypePDPT {
+            request,
+        } => {
+            let source: Option<UntypedCap> = cpool.lookup_upgrade(request.0);
+            if source.is_some() {
+                retype_n(&source.unwrap(), &cpool, request.1, 1, PDPTCap::retype_from);
             }
 
             None
         },
+        SystemCall::RetypePD {
+            request,
+        } => {
+            let source: Option<UntypedCap> = cpool.lookup_upgrade(request.0);
+            if source.is_some() {
+                retype_n(&source.unwrap(), &cpool, request.1, 1, PDCap::retype_from);
+            }
+
+            None
+        },
+        SystemCall::RetypePT {
+            request,
+        } => {
+            let source: Option<UntypedCap> = cpool.lookup_upgrade(request.0);
+            if source.is_some() {
+                retype_n(&source.unwrap(), &cpool, request.1, 1, PTCap::retype_from);
+            }
+
+            None
+        },
+        SystemCall::MapPDPT {
+            request,
+        } => {
+            let (pml4, vaddr, pdpt) = request;
+            let pml4_cap: Option<TopPageTableCap> = cpool.lookup_upgrade(pml4);
+            let pdpt_cap: Option<PDPTCap> = cpool.lookup_upgrade(pdpt);
+            let result = match (pml4_cap, pdpt_cap) {
+                (Some(mut pml4_cap), Some(pdpt_cap)) =>
+                    pml4_cap.map_pdpt(::arch::pml4_index(VAddr::from(vaddr)), &pdpt_cap),
+                _ => None,
+            };
+            if result.is_none() {
+                log!("Map PDPT failed.");
+            }
+
+            None
+        },
+        SystemCall::MapPD {
+            request,
+        } => {
+            let (pdpt, vaddr, pd) = request;
+            let pdpt_cap: Option<PDPTCap> = cpool.lookup_upgrade(pdpt);
+            let pd_cap: Option<PDCap> = cpool.lookup_upgrade(pd);
+            let result = match (pdpt_cap, pd_cap) {
+                (Some(mut pdpt_cap), Some(pd_cap)) =>
+                    pdpt_cap.map_pd(::arch::pdpt_index(VAddr::from(vaddr)), &pd_cap),
+                _ => None,
+            };
+            if result.is_none() {
+                log!("Map PD failed.");
+            }
+
+            None
+        },
+        SystemCall::MapPT {
+            request,
+        } => {
+            let (pd, vaddr, pt) = request;
+            let pd_cap: Option<PDCap> = cpool.lookup_upgrade(pd);
+            let pt_cap: Option<PTCap> = cpool.lookup_upgrade(pt);
+            let result = match (pd_cap, pt_cap) {
+                (Some(mut pd_cap), Some(pt_cap)) =>
+                    pd_cap.map_pt(::arch::pd_index(VAddr::from(vaddr)), &pt_cap),
+                _ => None,
+            };
+            if result.is_none() {
+                log!("Map PT failed.");
+            }
+
+            None
+        },
+        SystemCall::UntypedSplit {
+            request,
+        } => {
+            let (source, bits, count, target) = request;
+            let source: Option<UntypedCap> = cpool.lookup_upgrade(source);
+            if source.is_some() && bits >= UNTYPED_SPLIT_MIN_BITS && bits <= UNTYPED_SPLIT_MAX_BITS {
+                retype_n(&source.unwrap(), &cpool, target, count, |untyped| {
+                    UntypedCap::retype_from(untyped, bits)
+                });
+            } else {
+                log!("Untyped split failed.");
+            }
+
+            None
+        },
+        SystemCall::RetypeN {
+            request,
+        } => {
+            let (source, cap_type, count, target) = request;
+            let source: Option<UntypedCap> = cpool.lookup_upgrade(source);
+            if source.is_some() {
+                let source = source.unwrap();
+                match cap_type {
+                    CapType::CPool => retype_n(&source, &cpool, target, count, CPoolCap::retype_from),
+                    CapType::Task => retype_n(&source, &cpool, target, count, TaskCap::retype_from),
+                    CapType::RawPage => retype_n(&source, &cpool, target, count, RawPageCap::retype_from),
+                    CapType::TaskBufferPage => retype_n(&source, &cpool, target, count, TaskBufferPageCap::retype_from),
+                    CapType::Channel => retype_n(&source, &cpool, target, count, ChannelCap::retype_from),
+                    CapType::PML4 => retype_n(&source, &cpool, target, count, TopPageTableCap::retype_from),
+                    CapType::PDPT => retype_n(&source, &cpool, target, count, PDPTCap::retype_from),
+                    CapType::PD => retype_n(&source, &cpool, target, count, PDCap::retype_from),
+                    CapType::PT => retype_n(&source, &cpool, target, count, PTCap::retype_from),
+                    CapType::LargePage => retype_n(&source, &cpool, target, count, LargePageCap::retype_from),
+                    CapType::HugePage if !::arch::has_huge_pages() => log!("Huge pages are not supported by the CPU."),
+                    CapType::HugePage => retype_n(&source, &cpool, target, count, HugePageCap::retype_from),
+                    CapType::Untyped => log!("Untyped capabilities are created by UntypedSplit."),
+                }
+            }
+
+            None
+        },
+        SystemCall::UntypedInfo {
+            request, ..
+        } => {
+            let source: Option<UntypedCap> = cpool.lookup_upgrade(request);
+
+            Some(SystemCall::UntypedInfo {
+                request: request,
+                response: source.map(|source| source.read().info()),
+            })
+        },
+        SystemCall::CapIdentify {
+            request, ..
+        } => {
+            let target = cpool.lookup_upgrade_any(request);
+
+            Some(SystemCall::CapIdentify {
+                request: request,
+                response: target.map(cap::identify_any),
+            })
+        },
         SystemCall::TaskSetInstructionPointer {
             request,
         } => {
@@ -142,50 +516,113 @@ pub fn handle(call: SystemCall, task_cap: TaskCap, cpool: CPoolCap) -> Option<Sy
         SystemCall::TaskSetCPool {
             request,
         } => {
-            let target_task: TaskCap = cpool.lookup_upgrade(request.0).unwrap();
-            let target_cpool: CPoolCap = cpool.lookup_upgrade(request.1).unwrap();
-            target_task.read().downgrade_cpool(&target_cpool);
+            let target_task: Option<TaskCap> = cpool.lookup_upgrade(request.0);
+            let target_cpool: Option<CPoolCap> = cpool.lookup_upgrade(request.1);
+            if target_task.is_some() && target_cpool.is_some() {
+                target_task.unwrap().read().downgrade_cpool(&target_cpool.unwrap());
+            } else {
+                log!("Task set cpool failed.");
+            }
 
             None
         },
         SystemCall::TaskSetTopPageTable {
             request,
         } => {
-            let target_task: TaskCap = cpool.lookup_upgrade(request.0).unwrap();
-            let target_table: TopPageTableCap = cpool.lookup_upgrade(request.1).unwrap();
-            target_task.read().downgrade_top_page_table(&target_table);
+            let target_task: Option<TaskCap> = cpool.lookup_upgrade(request.0);
+            let target_table: Option<TopPageTableCap> = cpool.lookup_upgrade(request.1);
+            if target_task.is_some() && target_table.is_some() {
+                target_task.unwrap().read().downgrade_top_page_table(&target_table.unwrap());
+            } else {
+                log!("Task set top page table failed.");
+            }
 
             None
         },
         SystemCall::TaskSetBuffer {
             request,
         } => {
-            let target_task: TaskCap = cpool.lookup_upgrade(request.0).unwrap();
-            let target_buffer: TaskBufferPageCap = cpool.lookup_upgrade(request.1).unwrap();
-            target_task.read().downgrade_buffer(&target_buffer);
+            let target_task: Option<TaskCap> = cpool.lookup_upgrade(request.0);
+            let target_buffer: Option<TaskBufferPageCap> = cpool.lookup_upgrade(request.1);
+            if target_task.is_some() && target_buffer.is_some() {
+                target_task.unwrap().read().downgrade_buffer(&target_buffer.unwrap());
+            } else {
+                log!("Task set buffer failed.");
+            }
 
             None
         },
         SystemCall::TaskSetActive {
             request,
         } => {
-            let target_task: TaskCap = cpool.lookup_upgrade(request).unwrap();
-            target_task.write().set_status(TaskStatus::Active);
+            let target_task: Option<TaskCap> = cpool.lookup_upgrade(request);
+            if let Some(target_task) = target_task {
+                let affinity = {
+                    let mut target = target_task.write();
+                    target.set_status(TaskStatus::Active);
+                    target.affinity()
+                };
+                ::arch::wake_up(affinity);
+            } else {
+                log!("Task set active failed.");
+            }
 
             None
         },
         SystemCall::TaskSetInactive {
             request,
         } => {
-            let target_task: TaskCap = cpool.lookup_upgrade(request).unwrap();
-            target_task.write().set_status(TaskStatus::Inactive);
+            let target_task: Option<TaskCap> = cpool.lookup_upgrade(request);
+            if let Some(target_task) = target_task {
+                target_task.write().set_status(TaskStatus::Inactive);
+            } else {
+                log!("Task set inactive failed.");
+            }
+
+            None
+        },
+        SystemCall::TaskSetAffinity {
+            request,
+        } => {
+            let target_task: Option<TaskCap> = cpool.lookup_upgrade(request.0);
+            if let Some(target_task) = target_task {
+                target_task.write().set_affinity(request.1);
+                ::arch::wake_up(request.1);
+            } else {
+                log!("Task set affinity failed.");
+            }
+
+            None
+        },
+        SystemCall::TaskSetTLSBase {
+            request,
+        } => {
+            let target_task: Option<TaskCap> = match request.0 {
+                Some(target) => cpool.lookup_upgrade(target),
+                None => Some(task_cap.clone()),
+            };
+            let result = target_task.and_then(|target_task| {
+                target_task.write().set_tls_base(request.1, request.2)
+            });
+            if result.is_none() {
+                log!("Task set TLS base failed.");
+            }
+
+            None
+        },
+        SystemCall::TaskExit {
+            request,
+        } => {
+            let message = ::core::str::from_utf8(&request.1[0..request.2]).unwrap();
+            log!("Task exited with code {}: {}", request.0, message);
+            task_cap.write().set_status(TaskStatus::Dead(request.0));
 
             None
         },
         SystemCall::ChannelTake {
             request, ..
         } => {
-            let mut chan_option: Option<ChannelCap> = cpool.lookup_upgrade(request);
+            let mut chan_option: Option<ChannelCap> = cpool.lookup_upgrade(request.0);
             if let Some(chan) = chan_option {
                 task_cap.write().set_status(TaskStatus::ChannelWait(chan))
             }
@@ -200,6 +637,9 @@ pub fn handle(call: SystemCall, task_cap: TaskCap, cpool: CPoolCap) -> Option<Sy
                 let value = ChannelValue::from_message(request.1.clone(), task_cap.clone());
                 if value.is_some() {
                     chan.write().put(value.unwrap());
+                    cap::wake_channel_waiters(&chan);
+                } else {
+                    log!("Channel put failed: the message cannot be sent.");
                 }
             }
 
@@ -2,8 +2,11 @@
 #[no_mangle]
 pub extern "C" fn rust_begin_unwind(args: ::core::fmt::Arguments, file: &str, line: usize) -> !
 {
+	// The panic may have interrupted a message being logged.
+	unsafe { ::logging::force_unlock(); }
 	// 'args' will print to the formatted string passed to panic!
-	log!("file='{}', line={} :: {}", file, line, args);
+	error!("file='{}', line={} :: {}", file, line, args);
+	::arch::report_panic();
 	loop {}
 }
 
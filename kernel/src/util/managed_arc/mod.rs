@@ -34,10 +34,13 @@ struct ManagedWeakAddr {
 }
 
 /// Inner of an Arc, containing strong pointers and weak pointers
-/// information. Wrap the actual data.
+/// information. Wrap the actual data. The layout is fixed so that
+/// the weak pointer list can be reached without knowing `T`.
+#[repr(C)]
 struct ManagedArcInner<T> {
     lead: Mutex<usize>,
-    // TODO: Implement weak pool lock.
+    /// First weak pointer of the list. The lock is held while the
+    /// list is changed, and taken before any weak pool entry lock.
     first_weak: Mutex<Option<ManagedWeakAddr>>,
     data: T
 }
@@ -100,6 +103,11 @@ impl ManagedArcAny {
         where ManagedArc<T>: Any {
         self.type_id == TypeId::of::<T>()
     }
+
+    /// Physical address of the ManagedArcInner.
+    pub fn paddr(&self) -> PAddr {
+        self.ptr
+    }
 }
 
 impl<T: Any> From<ManagedArcAny> for ManagedArc<T> {
@@ -173,6 +181,11 @@ impl<T> ManagedArc<T> {
         unsafe { MemoryObject::<ManagedArcInner<T>>::new(self.ptr) }
     }
 
+    /// Physical address of the ManagedArcInner.
+    pub fn paddr(&self) -> PAddr {
+        self.ptr
+    }
+
     /// Get the strong pointers count.
     pub fn lead_count(&self) -> usize {
         let inner = self.inner_object();
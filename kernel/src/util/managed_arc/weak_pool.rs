@@ -58,6 +58,12 @@ macro_rules! weak_pool {
         }
 
         impl $t {
+            /// Whether the entry at `index` of this weak pool is
+            /// empty.
+            pub fn is_free(&self, index: usize) -> bool {
+                self.0[index].lock().is_none()
+            }
+
             /// Create a new strong pointer if `index` points to a
             /// non-none weak pointer in the weak pool.
             pub unsafe fn upgrade_any<F>(&self, index: usize, f: F) -> Option<ManagedArcAny> where F: FnOnce(PAddr, TypeId) -> Option<ManagedArcAny> {
@@ -85,65 +91,119 @@ macro_rules! weak_pool {
                 })
             }
 
+            /// Weak address of the entry at `index` of this weak pool.
+            fn weak_addr(&self, index: usize) -> ManagedWeakAddr {
+                ManagedWeakAddr {
+                    inner_addr: self.1,
+                    offset: index,
+                    inner_type_id: TypeId::of::<ManagedArcInner<$t>>()
+                }
+            }
+
             /// Downgrade a strong pointer to a weak pointer and store
             /// it at `index` in this weak pool.
             pub fn downgrade_at<T: Any>(&self, arc: &ManagedArc<T>, index: usize)
                 where ManagedArc<T>: Any {
+                assert!(self.downgrade_at_if_free(arc, index));
+            }
+
+            /// Like `downgrade_at`, but only if the entry at `index`
+            /// is empty. The entry is checked and filled under the
+            /// same lock. Returns whether the weak pointer is stored.
+            pub fn downgrade_at_if_free<T: Any>(&self, arc: &ManagedArc<T>, index: usize) -> bool
+                where ManagedArc<T>: Any {
+                let arc_inner_obj = arc.inner_object();
+                let arc_inner = unsafe { arc_inner_obj.as_ref() };
+
+                // Lock order is `first_weak` of the strong pointer,
+                // then the weak pool entries.
+                let mut arc_first_weak = arc_inner.first_weak.lock();
+                let mut weak_node_option = self.0[index].lock();
+                if weak_node_option.is_some() {
+                    return false;
+                }
 
-                let ptr = self.1;
+                link_weak_node(&mut weak_node_option, &mut arc_first_weak,
+                               self.weak_addr(index),
+                               arc.ptr, TypeId::of::<ManagedArc<T>>());
+                true
+            }
 
-                let weak_addr = ManagedWeakAddr {
-                    inner_addr: ptr,
-                    offset: index,
-                    inner_type_id: TypeId::of::<ManagedArcInner<$t>>()
-                };
-                let mut weak_node = ManagedWeakNode {
-                    ptr: arc.ptr,
-                    strong_type_id: TypeId::of::<ManagedArc<T>>(),
-                    prev: None,
-                    next: None
+            /// Remove the weak pointer at `index` in this weak pool,
+            /// unlinking it from the weak pointer list of its strong
+            /// pointer. Does nothing if the entry is empty.
+            pub fn remove(&self, index: usize) {
+                let ptr = match self.0[index].lock().as_ref() {
+                    Some(weak_node) => weak_node.ptr,
+                    None => return,
                 };
 
-                let mut weak_node_option = self.0[index].lock();
-                assert!(weak_node_option.is_none());
+                self.remove_ptr(index, ptr);
+            }
 
-                let arc_inner_obj = arc.inner_object();
+            /// Like `remove`, but only if the entry at `index` is a
+            /// weak pointer to the strong pointer at `ptr`. Returns
+            /// whether it was removed.
+            pub fn remove_ptr(&self, index: usize, ptr: PAddr) -> bool {
+                // The weak list of the strong pointer is locked before
+                // the entry, so the entry is checked again.
+                // `ManagedArcInner` is `repr(C)`, so `first_weak` can
+                // be accessed with any data type.
+                let arc_inner_obj: MemoryObject<ManagedArcInner<()>> =
+                    unsafe { MemoryObject::new(ptr) };
                 let arc_inner = unsafe { arc_inner_obj.as_ref() };
-
                 let mut arc_first_weak = arc_inner.first_weak.lock();
 
-                if arc_first_weak.is_none() {
-                    // ArcInner doesn't have any weak.
-                    *arc_first_weak = Some(weak_addr);
-                    *weak_node_option = Some(weak_node);
+                let weak_node = {
+                    let mut weak_node_option = self.0[index].lock();
+                    if weak_node_option.as_ref().map(|weak_node| weak_node.ptr) != Some(ptr) {
+                        return false;
+                    }
+                    weak_node_option.take().unwrap()
+                };
 
-                } else {
-                    // ArcInner has weak. Insert the new weak as the first child.
-                    let arc_second_weak_addr = arc_first_weak.take().unwrap();
-                    set_weak_node(arc_second_weak_addr, |second_weak_node| {
-                        assert!(second_weak_node.is_some());
+                match weak_node.prev {
+                    Some(prev_addr) => {
+                        set_weak_node(prev_addr, |prev_weak_node| {
+                            prev_weak_node.map(|mut prev_weak_node| {
+                                prev_weak_node.next = weak_node.next;
+                                prev_weak_node
+                            })
+                        });
+                    },
+                    None => {
+                        // The weak is the first child of the ArcInner.
+                        *arc_first_weak = weak_node.next;
+                    },
+                }
 
-                        second_weak_node.map(|mut second_weak_node| {
-                            second_weak_node.prev = Some(weak_addr);
-                            second_weak_node
+                if let Some(next_addr) = weak_node.next {
+                    set_weak_node(next_addr, |next_weak_node| {
+                        next_weak_node.map(|mut next_weak_node| {
+                            next_weak_node.prev = weak_node.prev;
+                            next_weak_node
                         })
                     });
-                    weak_node.next = Some(arc_second_weak_addr);
-
-                    *arc_first_weak = Some(weak_addr);
-                    *weak_node_option = Some(weak_node);
                 }
+                true
             }
 
             /// Downgrade a strong pointer to a weak pointer, and then
             /// store it in a free slot in this weak pool.
             pub fn downgrade_free<T: Any>(&self, arc: &ManagedArc<T>) -> Option<usize>
                 where ManagedArc<T>: Any {
-                for (i, element) in self.0.iter().enumerate() {
-                    // TODO race conditions
+                let arc_inner_obj = arc.inner_object();
+                let arc_inner = unsafe { arc_inner_obj.as_ref() };
+                let mut arc_first_weak = arc_inner.first_weak.lock();
 
-                    if { element.lock().is_none() } {
-                        self.downgrade_at(arc, i);
+                for (i, element) in self.0.iter().enumerate() {
+                    // The entry stays locked until it is filled, so
+                    // that it cannot be taken by another CPU.
+                    let mut weak_node_option = element.lock();
+                    if weak_node_option.is_none() {
+                        link_weak_node(&mut weak_node_option, &mut arc_first_weak,
+                                       self.weak_addr(i),
+                                       arc.ptr, TypeId::of::<ManagedArc<T>>());
                         return Some(i);
                     }
                 }
@@ -157,6 +217,38 @@ weak_pool!(ManagedWeakPool1);
 weak_pool!(ManagedWeakPool3);
 weak_pool!(ManagedWeakPool256);
 
+/// Store a new weak pointer to the strong pointer at `ptr` in the
+/// empty entry `weak_node_option`, whose address is `weak_addr`, and
+/// insert it as the first weak pointer of the strong pointer. The
+/// caller must hold `arc_first_weak`, the `first_weak` lock of the
+/// strong pointer, which serializes changes to its weak pointer list.
+fn link_weak_node(weak_node_option: &mut Option<ManagedWeakNode>,
+                  arc_first_weak: &mut Option<ManagedWeakAddr>,
+                  weak_addr: ManagedWeakAddr, ptr: PAddr, strong_type_id: TypeId) {
+    let mut weak_node = ManagedWeakNode {
+        ptr: ptr,
+        strong_type_id: strong_type_id,
+        prev: None,
+        next: None
+    };
+
+    if let Some(arc_second_weak_addr) = arc_first_weak.take() {
+        // ArcInner has weak. Insert the new weak as the first child.
+        set_weak_node(arc_second_weak_addr, |second_weak_node| {
+            assert!(second_weak_node.is_some());
+
+            second_weak_node.map(|mut second_weak_node| {
+                second_weak_node.prev = Some(weak_addr);
+                second_weak_node
+            })
+        });
+        weak_node.next = Some(arc_second_weak_addr);
+    }
+
+    *arc_first_weak = Some(weak_addr);
+    *weak_node_option = Some(weak_node);
+}
+
 fn set_weak_node<F>(addr: ManagedWeakAddr, f: F) where F: FnOnce(Option<ManagedWeakNode>) -> Option<ManagedWeakNode> {
     if addr.inner_type_id == TypeId::of::<ManagedArcInner<ManagedWeakPool1>>() {
         let inner_obj: MemoryObject<ManagedArcInner<ManagedWeakPool1>> =
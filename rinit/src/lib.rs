@@ -104,27 +104,25 @@ pub enum Key {
     Nonprintable
 }
 
-static mut IS_PARENT: bool = true;
+/// Thread-local storage area of the parent. Threads spawned by it
+/// get theirs from `system::thread::spawn`.
+static mut PARENT_TLS: [u8; 4096] = [0; 4096];
 
 #[lang="start"]
 #[no_mangle]
 #[allow(private_no_mangle_fns)]
 fn start(_argc: isize, _argv: *const *const u8) {
-    if unsafe { IS_PARENT } {
-        unsafe { IS_PARENT = false; }
-        parent_main();
-    } else {
-        child_main();
-    }
+    parent_main();
     loop {};
 }
 
 fn parent_main() {
-    unsafe { system::set_task_buffer_addr(0x90001000); }
+    unsafe { system::init_tls(&mut PARENT_TLS, 0x90001000); }
     system_print!("parent rinit started.");
 
     // Test allocator
     unsafe { selfalloc::setup_allocator(CAddr::from(2), CAddr::from(3), 0x1000000000); }
+    unsafe { system::thread::setup_threads(CAddr::from(0), CAddr::from(2), CAddr::from(3), 0x2000000000); }
     {
         use alloc::boxed::Box;
         let heap_test = Box::new(42);
@@ -133,9 +131,6 @@ fn parent_main() {
 
     system_print!("parent stack addr: 0x{:x}.",
                   system::task_buffer_addr() as usize);
-    print!("Child entry should be at: 0x{:x} ({})\nChild stack pointer should be at: 0x{:x} ({})\n",
-           start as *const () as usize, start as *const () as usize,
-           0x70000000 + (0x1000 * 4 - 4), 0x70000000 + (0x1000 * 4 - 4));
     print!(">>> ");
     let mut lastkey = Key::Nonprintable;
     let mut command = [0u8; 32];
@@ -166,19 +161,7 @@ fn parent_main() {
     }
 }
 
-fn start_child() {
-    system::retype_task(CAddr::from(2), CAddr::from(249));
-    system::task_set_stack_pointer(CAddr::from(249), 0x70000000 + (0x1000 * 4 - 4));
-    system::task_set_instruction_pointer(CAddr::from(249), start as *const () as u64);
-    system::task_set_cpool(CAddr::from(249), CAddr::from(0));
-    system::task_set_top_page_table(CAddr::from(249), CAddr::from(3));
-    system::task_set_buffer(CAddr::from(249), CAddr::from(250));
-    system::task_set_active(CAddr::from(249));
-}
-
 fn child_main() {
-    unsafe { system::set_task_buffer_addr(0x90003000); }
-
     system_print!("child rinit started.");
     system_print!("parent stack addr: 0x{:x}.",
                   system::task_buffer_addr() as usize);
@@ -204,9 +187,18 @@ fn execute_command(s: &str) {
     if s == "list" {
         print!("Listing task cpool ...\n");
         system::debug_cpool_list();
+    } else if s == "identify" {
+        for i in 0..256 {
+            if let Some(info) = system::cap_identify(CAddr::from(i as u8)) {
+                print!("{}: {:?} 0x{:x} ({} bytes) {:?}\n",
+                       i, info.cap_type, info.paddr, info.length, info.detail);
+            }
+        }
     } else if s == "start child" {
-        start_child();
-        print!("Child started.\n");
+        match system::thread::spawn(child_main, 4 * 4096) {
+            Some(child) => print!("Child started as task {:?}.\n", child.task()),
+            None => print!("Child failed to start.\n"),
+        }
     } else if s.len() >= 6 && &s[0..4] == "echo" {
         print!("{}\n", &s[5..s.len()]);
     } else if s.len() >= 6 && &s[0..8] == "send raw" {
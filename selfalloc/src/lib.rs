@@ -13,6 +13,7 @@ extern crate abi;
 
 use spin::{Once, Mutex};
 use abi::CAddr;
+use core::ptr;
 
 const PAGE_LENGTH: usize = 4096;
 
@@ -52,7 +53,8 @@ pub fn align_up(addr: usize, align: usize) -> usize {
 
 impl WatermarkAllocator {
     fn new(untyped_cap: CAddr, toplevel_table_cap: CAddr, page_start_addr: usize) -> Self {
-        let page_cap = system::retype_raw_page_free(untyped_cap);
+        let page_cap = system::retype_raw_page_free(untyped_cap)
+            .expect("untyped region exhausted");
         system::map_raw_page_free(page_start_addr, untyped_cap, toplevel_table_cap, page_cap.clone());
 
         WatermarkAllocator {
@@ -70,8 +72,27 @@ impl WatermarkAllocator {
 
         let mut alloc_end = alloc_start.saturating_add(size);
 
+        // Check up front that the untyped region can hold all new
+        // pages, plus the page tables `map_raw_page_free` may need
+        // for each of them, so that allocation never stops half-way.
+        let pages = alloc_end / PAGE_LENGTH;
+        if pages > 0 {
+            let needed = pages.checked_mul(PAGE_LENGTH * 4);
+            let enough = match (needed, system::untyped_info(self.untyped_cap)) {
+                (Some(needed), Some(info)) => info.remaining >= needed,
+                _ => false,
+            };
+            if !enough {
+                return ptr::null_mut();
+            }
+        }
+
         while alloc_end >= PAGE_LENGTH {
-            self.page_cap = system::retype_raw_page_free(self.untyped_cap);
+            let page_cap = match system::retype_raw_page_free(self.untyped_cap) {
+                Some(page_cap) => page_cap,
+                None => return ptr::null_mut(),
+            };
+            self.page_cap = page_cap;
             self.page_start_addr += PAGE_LENGTH;
             system::map_raw_page_free(self.page_start_addr, self.untyped_cap, self.toplevel_table_cap, self.page_cap.clone());
 
@@ -1,8 +1,11 @@
-use abi::{SystemCall, TaskBuffer, CAddr, ChannelMessage};
+use abi::{SystemCall, TaskBuffer, TASK_BUFFER_PAYLOAD_LENGTH, DecodeError, ABI_VERSION,
+          STATUS_OK, STATUS_BAD_VERSION, CAddr, ChannelMessage, CapType, UntypedInfo, CapInfo,
+          PageRights, CachePolicy, VSpaceMapping, CloneMode,
+          ChannelReceive, CHANNEL_MESSAGE_CAPS, CHANNEL_MESSAGE_PAGES};
 use core::any::Any;
 use super::task_buffer_addr;
 
-pub fn retype_raw_page_free(source: CAddr) -> CAddr {
+pub fn retype_raw_page_free(source: CAddr) -> Option<CAddr> {
     let result = system_call(SystemCall::RetypeRawPageFree {
         request: source,
         response: None
@@ -10,7 +13,33 @@ pub fn retype_raw_page_free(source: CAddr) -> CAddr {
     match result {
         SystemCall::RetypeRawPageFree {
             response, ..
-        } => { return response.unwrap(); },
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+pub fn untyped_info(target: CAddr) -> Option<UntypedInfo> {
+    let result = system_call(SystemCall::UntypedInfo {
+        request: target,
+        response: None
+    });
+    match result {
+        SystemCall::UntypedInfo {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+pub fn cap_identify(target: CAddr) -> Option<CapInfo> {
+    let result = system_call(SystemCall::CapIdentify {
+        request: target,
+        response: None
+    });
+    match result {
+        SystemCall::CapIdentify {
+            response, ..
+        } => { return response; },
         _ => panic!(),
     };
 }
@@ -23,6 +52,91 @@ pub fn map_raw_page_free(vaddr: usize, untyped: CAddr, toplevel_table: CAddr, pa
     });
 }
 
+/// Map a page. Large and huge pages are zeroed a chunk at a time,
+/// one chunk per call, and are only mapped by the call that zeroes
+/// the last chunk, so this calls again until the page is mapped.
+/// Returns `None` if the page cannot be mapped.
+pub fn page_map(vaddr: usize, untyped: CAddr, toplevel_table: CAddr, page: CAddr,
+                rights: PageRights, cache: CachePolicy) -> Option<()> {
+    loop {
+        let result = system_call(SystemCall::PageMap {
+            untyped: untyped,
+            toplevel_table: toplevel_table,
+            request: (vaddr, page, rights, cache),
+            response: None,
+        });
+        let remaining = match result {
+            SystemCall::PageMap {
+                response, ..
+            } => response?,
+            _ => panic!(),
+        };
+        if remaining == 0 {
+            return Some(());
+        }
+    }
+}
+
+pub fn page_unmap(page: CAddr) {
+    system_call(SystemCall::PageUnmap {
+        request: page,
+    });
+}
+
+pub fn page_remap(page: CAddr, rights: PageRights, cache: CachePolicy) {
+    system_call(SystemCall::PageRemap {
+        request: (page, rights, cache),
+    });
+}
+
+pub fn vspace_lookup(table: CAddr, vaddr: usize) -> Option<VSpaceMapping> {
+    let result = system_call(SystemCall::VSpaceLookup {
+        request: (table, vaddr),
+        response: None
+    });
+    match result {
+        SystemCall::VSpaceLookup {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+/// Clone the address space `source` into `target`, which is created
+/// if its slot is free. Huge pages are copied a chunk at a time, one
+/// chunk per call, so this calls again until the clone is complete.
+/// Returns `None` if the clone fails, in which case it may be
+/// incomplete.
+pub fn vspace_clone(untyped: CAddr, source: CAddr, target: CAddr, mode: CloneMode) -> Option<()> {
+    loop {
+        let result = system_call(SystemCall::VSpaceClone {
+            request: (untyped, source, target, mode),
+            response: None,
+        });
+        let remaining = match result {
+            SystemCall::VSpaceClone {
+                response, ..
+            } => response?,
+            _ => panic!(),
+        };
+        if remaining == 0 {
+            return Some(());
+        }
+    }
+}
+
+pub fn page_derive(untyped: CAddr, page: CAddr, target: CAddr) {
+    system_call(SystemCall::PageDerive {
+        request: (untyped, page, target),
+    });
+}
+
+pub fn page_revoke(page: CAddr) {
+    system_call(SystemCall::PageRevoke {
+        request: page,
+    });
+}
+
 pub fn retype_cpool(source: CAddr, target: CAddr) {
     system_call(SystemCall::RetypeCPool {
         request: (source, target),
@@ -35,6 +149,54 @@ pub fn retype_task(source: CAddr, target: CAddr) {
     });
 }
 
+pub fn retype_pdpt(source: CAddr, target: CAddr) {
+    system_call(SystemCall::RetypePDPT {
+        request: (source, target),
+    });
+}
+
+pub fn retype_pd(source: CAddr, target: CAddr) {
+    system_call(SystemCall::RetypePD {
+        request: (source, target),
+    });
+}
+
+pub fn retype_pt(source: CAddr, target: CAddr) {
+    system_call(SystemCall::RetypePT {
+        request: (source, target),
+    });
+}
+
+pub fn map_pdpt(pml4: CAddr, vaddr: usize, pdpt: CAddr) {
+    system_call(SystemCall::MapPDPT {
+        request: (pml4, vaddr, pdpt),
+    });
+}
+
+pub fn map_pd(pdpt: CAddr, vaddr: usize, pd: CAddr) {
+    system_call(SystemCall::MapPD {
+        request: (pdpt, vaddr, pd),
+    });
+}
+
+pub fn map_pt(pd: CAddr, vaddr: usize, pt: CAddr) {
+    system_call(SystemCall::MapPT {
+        request: (pd, vaddr, pt),
+    });
+}
+
+pub fn untyped_split(source: CAddr, bits: usize, count: usize, target: CAddr) {
+    system_call(SystemCall::UntypedSplit {
+        request: (source, bits, count, target),
+    });
+}
+
+pub fn retype_n(source: CAddr, cap_type: CapType, count: usize, target: CAddr) {
+    system_call(SystemCall::RetypeN {
+        request: (source, cap_type, count, target),
+    });
+}
+
 pub fn task_set_instruction_pointer(target: CAddr, ptr: u64) {
     system_call(SystemCall::TaskSetInstructionPointer {
         request: (target, ptr),
@@ -77,9 +239,46 @@ pub fn task_set_inactive(target: CAddr) {
     });
 }
 
+pub fn task_set_affinity(target: CAddr, mask: u64) {
+    system_call(SystemCall::TaskSetAffinity {
+        request: (target, mask)
+    });
+}
+
+pub fn task_set_tls_base(target: CAddr, fs_base: u64, gs_base: u64) {
+    system_call(SystemCall::TaskSetTLSBase {
+        request: (Some(target), fs_base, gs_base)
+    });
+}
+
+/// Stop the calling task with an exit code and a message. The
+/// message is truncated to 32 bytes, at a character boundary.
+pub fn task_exit(code: u64, message: &str) -> ! {
+    let mut length = ::core::cmp::min(message.len(), 32);
+    while !message.is_char_boundary(length) {
+        length -= 1;
+    }
+    let mut buffer = [0u8; 32];
+    buffer[0..length].copy_from_slice(&message.as_bytes()[0..length]);
+
+    system_call(SystemCall::TaskExit {
+        request: (code, buffer, length)
+    });
+    loop {}
+}
+
+/// Set the FS and GS base of the calling task. The task buffer
+/// address is given explicitly, as it cannot be read through FS
+/// before the base is set.
+pub fn set_current_tls_base(task_buffer: usize, fs_base: u64, gs_base: u64) {
+    system_call_at(task_buffer, SystemCall::TaskSetTLSBase {
+        request: (None, fs_base, gs_base)
+    });
+}
+
 fn channel_take_nonpayload(target: CAddr) -> ChannelMessage {
     let result = system_call(SystemCall::ChannelTake {
-        request: target,
+        request: (target, ChannelReceive::default()),
         response: None
     });
     match result {
@@ -110,7 +309,7 @@ pub fn channel_take_cap(target: CAddr) -> CAddr {
 
 pub fn channel_take<T: Any + Clone>(target: CAddr) -> T {
     let (result, payload) = system_call_take_payload(SystemCall::ChannelTake {
-        request: target,
+        request: (target, ChannelReceive::default()),
         response: None
     });
     match result {
@@ -124,6 +323,61 @@ pub fn channel_take<T: Any + Clone>(target: CAddr) -> T {
     };
 }
 
+pub fn channel_take_caps(target: CAddr, slots: [Option<CAddr>; CHANNEL_MESSAGE_CAPS]) -> [Option<CAddr>; CHANNEL_MESSAGE_CAPS] {
+    let result = system_call(SystemCall::ChannelTake {
+        request: (target, ChannelReceive { slots: slots, ..Default::default() }),
+        response: None
+    });
+    match result {
+        SystemCall::ChannelTake {
+            request: _,
+            response: Some(ChannelMessage::Compound(caps)),
+        } => {
+            return caps;
+        },
+        _ => panic!(),
+    };
+}
+
+pub fn channel_take_compound<T: Any + Clone>(target: CAddr, slots: [Option<CAddr>; CHANNEL_MESSAGE_CAPS]) -> ([Option<CAddr>; CHANNEL_MESSAGE_CAPS], T) {
+    let (result, payload) = system_call_take_payload(SystemCall::ChannelTake {
+        request: (target, ChannelReceive { slots: slots, ..Default::default() }),
+        response: None
+    });
+    match result {
+        SystemCall::ChannelTake {
+            request: _,
+            response: Some(ChannelMessage::Compound(caps)),
+        } => {
+            return (caps, payload);
+        },
+        _ => panic!(),
+    };
+}
+
+pub fn channel_take_bulk(target: CAddr, pages: [Option<CAddr>; CHANNEL_MESSAGE_PAGES]) -> usize {
+    let result = system_call(SystemCall::ChannelTake {
+        request: (target, ChannelReceive { pages: pages, ..Default::default() }),
+        response: None
+    });
+    match result {
+        SystemCall::ChannelTake {
+            request: _,
+            response: Some(ChannelMessage::Bulk(_, length)),
+        } => {
+            return length;
+        },
+        _ => panic!(),
+    };
+}
+
+#[cfg(not(feature="interrupt_system_call"))]
+pub fn channel_put_raw(target: CAddr, value: u64) {
+    let (bytes, depth) = target.to_raw();
+    unsafe { register_system_call_raw(::abi::REGISTER_CALL_CHANNEL_PUT_RAW, [bytes, depth, value, 0]); }
+}
+
+#[cfg(feature="interrupt_system_call")]
 pub fn channel_put_raw(target: CAddr, value: u64) {
     system_call(SystemCall::ChannelPut {
         request: (target, ChannelMessage::Raw(value))
@@ -142,6 +396,24 @@ pub fn channel_put<T: Any + Clone>(target: CAddr, value: T) {
     }, value);
 }
 
+pub fn channel_put_caps(target: CAddr, caps: [Option<CAddr>; CHANNEL_MESSAGE_CAPS]) {
+    system_call_put_payload(SystemCall::ChannelPut {
+        request: (target, ChannelMessage::Compound(caps))
+    }, ());
+}
+
+pub fn channel_put_compound<T: Any + Clone>(target: CAddr, caps: [Option<CAddr>; CHANNEL_MESSAGE_CAPS], value: T) {
+    system_call_put_payload(SystemCall::ChannelPut {
+        request: (target, ChannelMessage::Compound(caps))
+    }, value);
+}
+
+pub fn channel_put_bulk(target: CAddr, pages: [Option<CAddr>; CHANNEL_MESSAGE_PAGES], length: usize) {
+    system_call(SystemCall::ChannelPut {
+        request: (target, ChannelMessage::Bulk(pages, length))
+    });
+}
+
 pub fn print(buffer: [u8; 32], size: usize) {
     let _ = system_call(SystemCall::Print {
         request: (buffer, size)
@@ -165,13 +437,39 @@ pub fn debug_test_fail() {
     loop {}
 }
 
+/// Decode the reply to a system call from the task buffer. A version
+/// mismatch means the program was built against another kernel, and
+/// is reported on the first call it makes. A call the kernel rejected
+/// holds the request instead of a reply, so its status is checked
+/// first.
+fn system_call_reply(buffer: &TaskBuffer) -> SystemCall {
+    if buffer.call.status == STATUS_BAD_VERSION {
+        panic!("system call ABI version {} does not match kernel version {}",
+               ABI_VERSION, buffer.call.version);
+    } else if buffer.call.status != STATUS_OK {
+        panic!("system call failed with status {}", buffer.call.status);
+    }
+
+    match SystemCall::decode(&buffer.call) {
+        Ok(message) => message,
+        Err(DecodeError::Version(version)) =>
+            panic!("system call ABI version {} does not match kernel version {}",
+                   ABI_VERSION, version),
+        Err(error) => panic!("system call failed: {:?}", error),
+    }
+}
+
 fn system_call(message: SystemCall) -> SystemCall {
-    let addr = task_buffer_addr();
+    system_call_at(task_buffer_addr(), message)
+}
+
+/// Make a system call through the task buffer at `addr`.
+fn system_call_at(addr: usize, message: SystemCall) -> SystemCall {
     unsafe {
         let buffer = &mut *(addr as *mut TaskBuffer);
-        buffer.call = Some(message);
+        buffer.call = message.encode().expect("system call does not fit in the task buffer");
         system_call_raw();
-        buffer.call.take().unwrap()
+        system_call_reply(buffer)
     }
 }
 
@@ -181,15 +479,16 @@ fn system_call_put_payload<T: Any>(message: SystemCall, payload: T) -> SystemCal
 
     unsafe {
         let buffer = &mut *(addr as *mut TaskBuffer);
-        buffer.call = Some(message);
+        buffer.call = message.encode().expect("system call does not fit in the task buffer");
 
+        assert!(size_of::<T>() <= TASK_BUFFER_PAYLOAD_LENGTH);
         buffer.payload_length = size_of::<T>();
         let payload_addr = &mut buffer.payload_data as *mut _ as *mut T;
         let payload_data = &mut *payload_addr;
         *payload_data = payload;
 
         system_call_raw();
-        buffer.call.take().unwrap()
+        system_call_reply(buffer)
     }
 }
 
@@ -199,19 +498,31 @@ fn system_call_take_payload<T: Any + Clone>(message: SystemCall) -> (SystemCall,
 
     unsafe {
         let buffer = &mut *(addr as *mut TaskBuffer);
-        buffer.call = Some(message);
+        buffer.call = message.encode().expect("system call does not fit in the task buffer");
 
         system_call_raw();
+        let reply = system_call_reply(buffer);
 
         let payload_addr = &mut buffer.payload_data as *mut _ as *mut T;
         let payload_data = &*payload_addr;
         assert!(buffer.payload_length != 0 && buffer.payload_length == size_of::<T>());
 
-        (buffer.call.take().unwrap(), payload_data.clone())
+        (reply, payload_data.clone())
     }
 }
 
 #[inline(never)]
+#[cfg(not(feature="interrupt_system_call"))]
+unsafe fn system_call_raw() {
+    asm!("syscall"
+         :: "{rdi}"(::abi::REGISTER_CALL_BUFFER)
+         : "rax", "rbx", "rcx", "rdx",
+         "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15"
+         : "volatile", "intel");
+}
+
+#[inline(never)]
+#[cfg(feature="interrupt_system_call")]
 unsafe fn system_call_raw() {
     asm!("int 80h"
          ::
@@ -219,3 +530,17 @@ unsafe fn system_call_raw() {
          "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15"
          : "volatile", "intel");
 }
+
+/// Make a system call with its arguments in registers. Returns 0 on
+/// success, and 1 on failure.
+#[inline(never)]
+#[cfg(not(feature="interrupt_system_call"))]
+unsafe fn register_system_call_raw(number: u64, args: [u64; 4]) -> u64 {
+    let result: u64;
+    asm!("syscall"
+         : "={rax}"(result)
+         : "{rdi}"(number), "{rsi}"(args[0]), "{rdx}"(args[1]), "{r10}"(args[2]), "{r8}"(args[3])
+         : "rcx", "r11", "memory"
+         : "volatile", "intel");
+    result
+}
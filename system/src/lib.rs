@@ -17,48 +17,36 @@ macro_rules! system_print {
 
 pub mod unwind;
 mod call;
+mod tls;
+pub mod thread;
 
 #[cfg(feature="kernel_debug")]
 pub use self::call::{debug_cpool_list, debug_test_succeed, debug_test_fail};
 
-pub use self::call::{retype_cpool, retype_task,
+pub use self::call::{retype_cpool, retype_task, retype_n, untyped_split, untyped_info,
+                     cap_identify,
                      channel_put, channel_take,
                      channel_put_raw, channel_take_raw,
                      channel_put_cap, channel_take_cap,
+                     channel_put_caps, channel_take_caps,
+                     channel_put_compound, channel_take_compound,
+                     channel_put_bulk, channel_take_bulk,
                      retype_raw_page_free, map_raw_page_free,
+                     page_map, page_unmap, page_remap, page_derive, page_revoke,
+                     vspace_lookup, vspace_clone,
+                     retype_pdpt, retype_pd, retype_pt, map_pdpt, map_pd, map_pt,
                      task_set_stack_pointer, task_set_instruction_pointer,
                      task_set_cpool, task_set_top_page_table, task_set_buffer,
-                     task_set_active, task_set_inactive};
-pub use abi::{CAddr, ChannelMessage};
+                     task_set_active, task_set_inactive, task_set_affinity,
+                     task_set_tls_base, task_exit};
+pub use self::tls::{tls_area_length, init_tls_area, init_tls,
+                    task_buffer_addr, set_task_buffer_addr};
+pub use abi::{CAddr, ChannelMessage, CapType, UntypedInfo, CapInfo, CapDetail, TaskState,
+              PageRights, CachePolicy, VSpaceMapping, CloneMode,
+              ChannelReceive, CHANNEL_MESSAGE_CAPS, CHANNEL_MESSAGE_PAGES};
 
 use core::fmt;
 
-const STACK_LENGTH: usize = 4 * 4096;
-pub fn task_buffer_loc() -> usize {
-    // We create a random value on stack, lookup its address, and go
-    // to the top of the stack possible as the kernel buffer address
-    // storage.
-
-    let mut v: usize = 0xdeadbeaf;
-    let v_addr = &mut v as *mut usize as usize;
-
-    v_addr - (v_addr % STACK_LENGTH)
-}
-
-pub fn task_buffer_addr() -> usize {
-    unsafe {
-        let loc = task_buffer_loc() as *mut usize;
-        return *loc;
-    }
-}
-
-pub unsafe fn set_task_buffer_addr(addr: usize) {
-    use core::ptr::write;
-
-    let loc = task_buffer_loc() as *mut usize;
-    write(loc, addr);
-}
-
 pub struct PrintWriter {
     buffer: [u8; 32],
     size: usize
@@ -0,0 +1,208 @@
+use core::mem::size_of;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::{Once, Mutex};
+use abi::{CAddr, CapType, PageRights, CachePolicy};
+use call;
+use tls;
+
+const PAGE_LENGTH: usize = 4096;
+
+static THREADS: Once<Mutex<ThreadAllocator>> = Once::new();
+
+/// Futex-like wait queue, built on a channel used as a notification.
+/// Waiters block on the channel while the value is unchanged, and
+/// wakers put a value in the channel after changing it. As a channel
+/// holds a value until it is taken, a wakeup between the check and
+/// the wait is not lost. A wakeup releases at most one waiter, and
+/// waiters may also return spuriously, so they should check the
+/// value again.
+pub struct Futex {
+    value: AtomicUsize,
+    channel: CAddr,
+}
+
+impl Futex {
+    /// Create a futex waiting on `channel`, with an initial value of
+    /// 0. The channel must not be used for anything else.
+    pub const fn new(channel: CAddr) -> Futex {
+        Futex {
+            value: AtomicUsize::new(0),
+            channel: channel,
+        }
+    }
+
+    /// Value of the futex.
+    pub fn value(&self) -> &AtomicUsize {
+        &self.value
+    }
+
+    /// Block until woken up, if the value is still `expected`.
+    pub fn wait(&self, expected: usize) {
+        if self.value.load(Ordering::SeqCst) == expected {
+            call::channel_take_raw(self.channel);
+        }
+    }
+
+    /// Wake up a waiter.
+    pub fn wake(&self) {
+        call::channel_put_raw(self.channel, 0);
+    }
+}
+
+/// Shared state of a thread, at the start of its TLS pages.
+#[repr(C)]
+struct ThreadInfo {
+    entry: fn(),
+    /// Set to 1 when the entry function has returned.
+    exit: Futex,
+}
+
+/// Handle of a spawned thread.
+pub struct JoinHandle {
+    task: CAddr,
+    info: *const ThreadInfo,
+}
+
+impl JoinHandle {
+    /// Task capability of the thread.
+    pub fn task(&self) -> CAddr {
+        self.task
+    }
+
+    /// Wait until the entry function of the thread has returned. If
+    /// the thread panics, it exits without returning, and this waits
+    /// forever; its task can be checked with `cap_identify` instead.
+    pub fn join(self) {
+        let exit = unsafe { &(*self.info).exit };
+        while exit.value().load(Ordering::SeqCst) == 0 {
+            exit.wait(0);
+        }
+    }
+}
+
+/// Capabilities and address space region threads are created from.
+struct ThreadAllocator {
+    cpool_cap: CAddr,
+    untyped_cap: CAddr,
+    toplevel_table_cap: CAddr,
+    next_vaddr: usize,
+}
+
+/// Set up the thread library. Threads share the capability pool
+/// `cpool_cap` and the top-level page table `toplevel_table_cap`
+/// with the calling task. Their tasks, stacks and task buffers are
+/// retyped from `untyped_cap`, and mapped from `region_start`
+/// onwards.
+///
+/// # Safety
+///
+/// The region from `region_start` must not be used for anything
+/// else.
+pub unsafe fn setup_threads(cpool_cap: CAddr, untyped_cap: CAddr, toplevel_table_cap: CAddr,
+                            region_start: usize) {
+    THREADS.call_once(|| Mutex::new(ThreadAllocator {
+        cpool_cap: cpool_cap,
+        untyped_cap: untyped_cap,
+        toplevel_table_cap: toplevel_table_cap,
+        next_vaddr: region_start,
+    }));
+}
+
+/// Spawn a thread running `entry` on a stack of at least
+/// `stack_size` bytes. The thread shares the address space and the
+/// capability pool of the calling task. Returns `None` if
+/// `setup_threads` has not been called, or if capabilities could
+/// not be created. Memory of a thread is not reclaimed after it
+/// returns.
+pub fn spawn(entry: fn(), stack_size: usize) -> Option<JoinHandle> {
+    THREADS.try().and_then(|threads| threads.lock().spawn(entry, stack_size))
+}
+
+/// Entry point of spawned threads. The thread pointer, and so the
+/// task buffer and `ThreadInfo` address, is set up by `spawn`.
+extern "C" fn thread_start() -> ! {
+    let info = unsafe { &*(tls::thread_info_addr() as *const ThreadInfo) };
+    (info.entry)();
+
+    info.exit.value().store(1, Ordering::SeqCst);
+    info.exit.wake();
+    call::task_exit(0, "")
+}
+
+impl ThreadAllocator {
+    /// Find a free slot in the top level of the capability pool.
+    fn free_slot(&self) -> Option<CAddr> {
+        (1..256).map(|i| CAddr::from(i as u8)).find(|&caddr| call::cap_identify(caddr).is_none())
+    }
+
+    /// Retype a capability of `cap_type` into a free slot.
+    fn retype(&self, cap_type: CapType) -> Option<CAddr> {
+        let slot = self.free_slot()?;
+        call::retype_n(self.untyped_cap, cap_type, 1, slot);
+        match call::cap_identify(slot) {
+            Some(ref info) if info.cap_type == cap_type => Some(slot),
+            _ => None,
+        }
+    }
+
+    /// Map `count` new pages at `vaddr`.
+    fn map_pages(&self, vaddr: usize, count: usize) -> Option<()> {
+        for i in 0..count {
+            let page = call::retype_raw_page_free(self.untyped_cap)?;
+            call::map_raw_page_free(vaddr + i * PAGE_LENGTH, self.untyped_cap,
+                                    self.toplevel_table_cap, page);
+        }
+        Some(())
+    }
+
+    /// Create a thread. Its region is laid out as an unmapped guard
+    /// page, the stack, the TLS pages starting with the
+    /// `ThreadInfo`, and the task buffer.
+    fn spawn(&mut self, entry: fn(), stack_size: usize) -> Option<JoinHandle> {
+        let stack_pages = (stack_size + PAGE_LENGTH - 1) / PAGE_LENGTH;
+        let tls_pages = (size_of::<ThreadInfo>() + tls::tls_area_length() + PAGE_LENGTH - 1) / PAGE_LENGTH;
+
+        let stack_vaddr = self.next_vaddr + PAGE_LENGTH;
+        let tls_vaddr = stack_vaddr + stack_pages * PAGE_LENGTH;
+        let buffer_vaddr = tls_vaddr + tls_pages * PAGE_LENGTH;
+        self.next_vaddr = buffer_vaddr + PAGE_LENGTH;
+
+        let task = self.retype(CapType::Task)?;
+        let exit_channel = self.retype(CapType::Channel)?;
+        let buffer = self.retype(CapType::TaskBufferPage)?;
+        call::page_map(buffer_vaddr, self.untyped_cap, self.toplevel_table_cap, buffer,
+                       PageRights { read: true, write: true, execute: false },
+                       CachePolicy::WriteBack)?;
+        self.map_pages(stack_vaddr, stack_pages + tls_pages)?;
+
+        let info = tls_vaddr as *mut ThreadInfo;
+        let thread_pointer = unsafe {
+            ptr::write(info, ThreadInfo {
+                entry: entry,
+                exit: Futex::new(exit_channel),
+            });
+            let area = ::core::slice::from_raw_parts_mut(
+                (tls_vaddr + size_of::<ThreadInfo>()) as *mut u8,
+                tls_pages * PAGE_LENGTH - size_of::<ThreadInfo>());
+            let thread_pointer = tls::init_tls_area(area, buffer_vaddr);
+            tls::set_thread_info_addr(thread_pointer, info as usize);
+            thread_pointer
+        };
+
+        // The stack pointer is 8 bytes below a 16-byte boundary, as
+        // after a call.
+        call::task_set_stack_pointer(task, (tls_vaddr - 8) as u64);
+        call::task_set_instruction_pointer(task, thread_start as *const () as u64);
+        call::task_set_cpool(task, self.cpool_cap);
+        call::task_set_top_page_table(task, self.toplevel_table_cap);
+        call::task_set_buffer(task, buffer);
+        call::task_set_tls_base(task, thread_pointer as u64, 0);
+        call::task_set_active(task);
+
+        Some(JoinHandle {
+            task: task,
+            info: info,
+        })
+    }
+}
@@ -0,0 +1,185 @@
+use core::mem::size_of;
+use core::ptr;
+use call;
+
+/// ELF program header type of the thread-local storage template.
+const PT_TLS: u32 = 7;
+
+/// ELF file header, as far as it is needed to find the program
+/// headers.
+#[repr(C)]
+struct ElfHeader {
+    ident: [u8; 16],
+    elf_type: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+}
+
+/// ELF program header.
+#[repr(C)]
+struct ProgramHeader {
+    progtype: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+extern {
+    /// ELF header of the program, mapped with the first segment.
+    /// Defined by the linker.
+    static __ehdr_start: ElfHeader;
+}
+
+/// Thread control block. The FS base points to it, and the TLS block
+/// of the program lies right below it, as required by the x86_64 ELF
+/// TLS ABI.
+#[repr(C)]
+struct ThreadControlBlock {
+    /// Address of this block, read by code accessing `#[thread_local]`
+    /// data through `fs:[0]`.
+    this: usize,
+    /// Address of the task buffer of the thread, read through
+    /// `fs:[8]`.
+    task_buffer: usize,
+    /// Address of the shared state of a thread created by `spawn`,
+    /// read through `fs:[16]`.
+    thread_info: usize,
+}
+
+/// Initial image of the TLS block, from the `PT_TLS` program header.
+struct TlsTemplate {
+    vaddr: usize,
+    file_length: usize,
+    length: usize,
+    align: usize,
+}
+
+impl TlsTemplate {
+    /// Find the template of the program. Programs without
+    /// thread-local data get an empty template.
+    fn get() -> TlsTemplate {
+        let mut template = TlsTemplate { vaddr: 0, file_length: 0, length: 0, align: 1 };
+        unsafe {
+            let header = &__ehdr_start;
+            let start = header as *const ElfHeader as usize + header.phoff as usize;
+            for i in 0..(header.phnum as usize) {
+                let program_header = &*((start + i * header.phentsize as usize) as *const ProgramHeader);
+                if program_header.progtype == PT_TLS {
+                    template = TlsTemplate {
+                        vaddr: program_header.vaddr as usize,
+                        file_length: program_header.filesz as usize,
+                        length: program_header.memsz as usize,
+                        align: program_header.align as usize,
+                    };
+                }
+            }
+        }
+        if template.align < size_of::<usize>() {
+            template.align = size_of::<usize>();
+        }
+        template
+    }
+
+    /// Distance between the start of the TLS block and the thread
+    /// pointer.
+    fn offset(&self) -> usize {
+        align_up(self.length, self.align)
+    }
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) / align * align
+}
+
+/// Length of the area `init_tls_area` needs for the TLS block and the
+/// thread control block of one thread.
+pub fn tls_area_length() -> usize {
+    let template = TlsTemplate::get();
+    template.offset() + template.align - 1 + size_of::<ThreadControlBlock>()
+}
+
+/// Set up the TLS block and the thread control block of a thread in
+/// `area`, with `task_buffer` as the address of the task buffer of
+/// the thread. Returns the thread pointer, to be used as the FS base
+/// of the thread.
+///
+/// # Safety
+///
+/// `area` must be at least `tls_area_length` bytes long, and must
+/// live as long as the thread.
+pub unsafe fn init_tls_area(area: &mut [u8], task_buffer: usize) -> usize {
+    let template = TlsTemplate::get();
+    assert!(area.len() >= tls_area_length());
+
+    let thread_pointer = align_up(area.as_ptr() as usize + template.offset(), template.align);
+    let block = (thread_pointer - template.offset()) as *mut u8;
+    ptr::copy_nonoverlapping(template.vaddr as *const u8, block, template.file_length);
+    ptr::write_bytes(block.offset(template.file_length as isize), 0,
+                     template.length - template.file_length);
+
+    ptr::write(thread_pointer as *mut ThreadControlBlock, ThreadControlBlock {
+        this: thread_pointer,
+        task_buffer: task_buffer,
+        thread_info: 0,
+    });
+
+    thread_pointer
+}
+
+/// Set up thread-local storage of the calling task in `area`, and
+/// make it current. Must be called before any other system call, as
+/// the task buffer address is read through FS.
+///
+/// # Safety
+///
+/// `area` must be at least `tls_area_length` bytes long, and must not
+/// be used by another task.
+pub unsafe fn init_tls(area: &'static mut [u8], task_buffer: usize) {
+    let thread_pointer = init_tls_area(area, task_buffer);
+    call::set_current_tls_base(task_buffer, thread_pointer as u64, 0);
+}
+
+/// Address of the task buffer of the calling task.
+pub fn task_buffer_addr() -> usize {
+    let addr: usize;
+    unsafe {
+        asm!("mov $0, fs:[8]" : "=r"(addr) : : : "volatile", "intel");
+    }
+    addr
+}
+
+/// Set the thread state address in the thread control block at
+/// `thread_pointer`, set up by `init_tls_area`.
+pub unsafe fn set_thread_info_addr(thread_pointer: usize, addr: usize) {
+    (*(thread_pointer as *mut ThreadControlBlock)).thread_info = addr;
+}
+
+/// Thread state address of the calling task.
+pub fn thread_info_addr() -> usize {
+    let addr: usize;
+    unsafe {
+        asm!("mov $0, fs:[16]" : "=r"(addr) : : : "volatile", "intel");
+    }
+    addr
+}
+
+/// Change the address of the task buffer of the calling task.
+///
+/// # Safety
+///
+/// `init_tls` must have been called, and `addr` must point to the task
+/// buffer of the task.
+pub unsafe fn set_task_buffer_addr(addr: usize) {
+    asm!("mov fs:[8], $0" : : "r"(addr) : "memory" : "volatile", "intel");
+}
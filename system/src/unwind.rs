@@ -1,12 +1,45 @@
+use core::fmt;
+use call;
 
+/// Exit code of a task that panicked.
+pub const PANIC_EXIT_CODE: u64 = 101;
+
+/// Writer keeping the beginning of a message, up to the length of a
+/// `TaskExit` message.
+struct ExitMessage {
+    buffer: [u8; 32],
+    size: usize,
+}
+
+impl fmt::Write for ExitMessage {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            let length = c.len_utf8();
+            if self.size + length > self.buffer.len() {
+                break;
+            }
+            c.encode_utf8(&mut self.buffer[self.size..]);
+            self.size += length;
+        }
+        Ok(())
+    }
+}
+
+/// Print the panic message, and stop the task with
+/// `PANIC_EXIT_CODE`, so that its supervisor sees it dead instead of
+/// the task spinning.
 #[lang="panic_fmt"]
 #[no_mangle]
-pub extern "C" fn rust_begin_unwind(args: ::core::fmt::Arguments, file: &str, line: usize) -> !
+pub extern "C" fn rust_begin_unwind(args: fmt::Arguments, file: &str, line: usize) -> !
 {
     // 'args' will print to the formatted string passed to panic!
     use core::fmt::Write;
     system_print!("file='{}', line={} :: {}", file, line, args);
-    loop {}
+
+    let mut message = ExitMessage { buffer: [0; 32], size: 0 };
+    let _ = write!(&mut message, "{}", args);
+    let message = unsafe { ::core::str::from_utf8_unchecked(&message.buffer[0..message.size]) };
+    call::task_exit(PANIC_EXIT_CODE, message)
 }
 
 #[allow(non_camel_case_types)]
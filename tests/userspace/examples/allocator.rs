@@ -12,11 +12,13 @@ extern crate alloc;
 
 use system::{CAddr};
 
+static mut TLS: [u8; 4096] = [0; 4096];
+
 #[lang="start"]
 #[no_mangle]
 #[allow(private_no_mangle_fns)]
 fn start(_argc: isize, _argv: *const *const u8) {
-    unsafe { system::set_task_buffer_addr(0x90001000); }
+    unsafe { system::init_tls(&mut TLS, 0x90001000); }
     unsafe { selfalloc::setup_allocator(CAddr::from(2), CAddr::from(3), 0x1000000000); }
 
     // Test allocator
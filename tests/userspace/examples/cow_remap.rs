@@ -0,0 +1,71 @@
+#![feature(lang_items)]
+#![feature(asm)]
+#![feature(const_fn)]
+#![no_std]
+
+extern crate system;
+
+use system::{CAddr, PageRights, CachePolicy, CloneMode};
+use core::ptr;
+
+static mut TLS: [u8; 4096] = [0; 4096];
+
+const PAGE_VADDR: usize = 0x3000000000;
+const PT_RW: u64 = 1 << 1;
+const PT_COW: u64 = 1 << 9;
+
+fn check(condition: bool) {
+    if !condition {
+        system::debug_test_fail();
+    }
+}
+
+#[lang="start"]
+#[no_mangle]
+#[allow(private_no_mangle_fns)]
+fn start(_argc: isize, _argv: *const *const u8) {
+    unsafe { system::init_tls(&mut TLS, 0x90001000); }
+
+    let untyped = CAddr::from(2);
+    let toplevel_table = CAddr::from(3);
+    let clone = CAddr::from(250);
+    let writable = PageRights { read: true, write: true, execute: false };
+    let read_only = PageRights { read: true, write: false, execute: false };
+    let value = PAGE_VADDR as *mut u64;
+
+    let page = system::retype_raw_page_free(untyped);
+    check(page.is_some());
+    let page = page.unwrap();
+    check(system::page_map(PAGE_VADDR, untyped, toplevel_table, page,
+                           writable, CachePolicy::WriteBack).is_some());
+    unsafe { ptr::write_volatile(value, 1); }
+
+    // Clone, then remap the page writable before it is written: it
+    // must stay read-only and copy-on-write, as the clone shares it.
+    check(system::vspace_clone(untyped, toplevel_table, clone, CloneMode::CopyOnWrite).is_some());
+    let shared = system::vspace_lookup(clone, PAGE_VADDR);
+    check(shared.map_or(false, |mapping| mapping.flags & PT_COW != 0 && mapping.flags & PT_RW == 0));
+    let shared = shared.unwrap();
+
+    system::page_remap(page, writable, CachePolicy::WriteBack);
+    let mapping = system::vspace_lookup(toplevel_table, PAGE_VADDR);
+    check(mapping.map_or(false, |mapping| {
+        mapping.paddr == shared.paddr && mapping.flags & PT_COW != 0 && mapping.flags & PT_RW == 0
+    }));
+
+    // Write, which copies the page, then remap it read-only: the
+    // private copy must be left alone, and the clone keep the
+    // original frame.
+    unsafe { ptr::write_volatile(value, 2); }
+    let private = system::vspace_lookup(toplevel_table, PAGE_VADDR);
+    check(private.map_or(false, |mapping| mapping.paddr != shared.paddr && mapping.flags & PT_RW != 0));
+    let private = private.unwrap();
+
+    system::page_remap(page, read_only, CachePolicy::WriteBack);
+    let mapping = system::vspace_lookup(toplevel_table, PAGE_VADDR);
+    check(mapping.map_or(false, |mapping| mapping.paddr == private.paddr && mapping.flags & PT_RW != 0));
+    check(system::vspace_lookup(clone, PAGE_VADDR).map_or(false, |mapping| mapping.paddr == shared.paddr));
+    check(unsafe { ptr::read_volatile(value) } == 2);
+
+    system::debug_test_succeed();
+}